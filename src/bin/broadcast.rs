@@ -1,43 +1,791 @@
 use anyhow::anyhow;
 use anyhow::Ok;
 use anyhow::Result;
+use maelstrom_rust::clock::*;
+use maelstrom_rust::epochs::*;
+use maelstrom_rust::hashing::*;
 use maelstrom_rust::msg_protocol::*;
+use maelstrom_rust::node_id;
 use maelstrom_rust::runner::*;
 
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::ops::Bound::{Excluded, Unbounded};
+use std::time::Duration;
+
+// messages exchanged with a peer, whatever their type
+#[derive(Debug, Default, Serialize)]
+struct PeerCounters {
+    sent: u64,
+    received: u64,
+}
 
 struct BroadcastMaelstromNode {
-    id: i64,
+    // what gossip is sent with is known before it goes out, so that acks can be matched to it
+    msg_ids: MessageIdAllocator,
+    // messages of the default topic
     messages: HashSet<i64>,
+    // messages of every other topic, they are only flooded to neighbors on first receipt
+    // without the per node bookkeeping of the default topic
+    topics: HashMap<String, HashSet<i64>>,
     messages_shared_per_node: HashMap<String, HashSet<i64>>,
     node_id: Option<String>,
-    // all nodes in the network minus current node
-    node_ids: HashSet<String>,
+    // all nodes in the network minus current node, sorted so that fan-outs and catch-ups
+    // go out in the same order from one run to the next
+    node_ids: BTreeSet<String>,
+    // whether broadcasts gossiped by other nodes get a BroadcastOk, clients are always acked
+    ack_gossip: bool,
+    // gossip only along a spanning tree of the received topology instead of all its edges
+    spanning_tree: bool,
+    // the last spanning tree computed, whole, to tell where the node sits in it
+    tree: HashMap<String, HashSet<String>>,
+    clock: Box<dyn Clock>,
+    // when each message was first seen, and how long it then took for all neighbors to have it
+    first_seen: HashMap<i64, Duration>,
+    convergence_latencies: HashMap<i64, Duration>,
+    // AIMD rate control of catch-ups: at most `send_rates[peer]` per broadcast, halved when
+    // gossip to a peer stays unacked for `ack_timeout`, at most once per timeout, and grown by
+    // one on its acks, up to the max
+    max_send_rate: Option<usize>,
+    send_rates: HashMap<String, usize>,
+    ack_timeout: Duration,
+    last_rate_decrease: HashMap<String, Duration>,
+    // cap on the messages of the default topic, the oldest ones being evicted past it along
+    // with what is known of them; evicted values gossiped back are new again, stored and
+    // gossiped on as such
+    max_messages: Option<usize>,
+    // order messages were stored in, only kept with a cap
+    messages_order: VecDeque<i64>,
+    // reads are flagged stale when no neighbor has been heard from for that long
+    stale_after: Option<Duration>,
+    last_heard_from_peers: Option<Duration>,
+    // when each peer was last heard from, heartbeats keeping it fresh while there's no gossip
+    last_heard: HashMap<String, Duration>,
+    heartbeat_interval: Option<Duration>,
+    last_heartbeat: Duration,
+    topology_received: bool,
+    peer_counters: BTreeMap<String, PeerCounters>,
+    // messages every neighbor has, compacted out of `messages_shared_per_node` periodically
+    acked_by_all: HashSet<i64>,
+    compaction_interval: Option<Duration>,
+    last_compaction: Duration,
+    // every neighbor periodically gets a digest of the set, so that whatever the per peer
+    // bookkeeping got wrong is reconciled anyway
+    full_sync_interval: Option<Duration>,
+    last_full_sync: Duration,
+    // every neighbor periodically gets a checksum of the set, answering with a digest of its
+    // own only when its checksum differs, a cheaper safety net than full syncs
+    checksum_interval: Option<Duration>,
+    last_checksum: Duration,
+    // cap on what one inbound message makes the node send besides answers, the excess being
+    // deferred to the next messages processed; past `max_deferred` the oldest deferred messages
+    // are dropped, catch-ups and syncs making up for the gossip lost
+    max_fan_out: Option<usize>,
+    deferred: VecDeque<Message<BroadcastMessage>>,
+    max_deferred: usize,
+    deferred_dropped: u64,
+    // a peer silent for that long is taken as disconnected, its whole backlog being sent as
+    // soon as it is heard from again
+    reconnect_after: Option<Duration>,
+    // values of the default topic are owned by a node picked by consistent hashing over the
+    // whole cluster, clients' broadcasts being forwarded to that owner which gossips them
+    partitioned: bool,
+    ring: BTreeMap<u64, String>,
+    ring_hasher: Box<dyn RingHasher>,
+    // gossip from other nodes is acked in batches sent at most that often, with the msg_ids
+    // of the broadcasts acked since the previous batch
+    ack_batch_interval: Option<Duration>,
+    last_ack_batch: Duration,
+    pending_acks: BTreeMap<String, Vec<i64>>,
+    // values gossiped to each peer and not acked yet, with the msg_id and time they were sent
+    // with, so that acks tell which value the peer got: with a window they aren't sent to that
+    // peer again before it elapses; either way they are forgotten past `ack_timeout`
+    in_flight_window: Option<Duration>,
+    in_flight: HashMap<String, HashMap<i64, (i64, Duration)>>,
+    // where diagnostics go, stderr outside of tests
+    log: Box<dyn Write>,
+    // also log every value stored and every message sent to a peer, a line each
+    debug_log: bool,
+    // of the message being processed, tagging every line logged meanwhile
+    correlation_id: String,
 }
 
 impl BroadcastMaelstromNode {
     pub fn new(
-        id: i64,
         node_id: Option<String>,
         messages: HashSet<i64>,
         messages_shared_per_node: HashMap<String, HashSet<i64>>,
         node_ids: HashSet<String>,
     ) -> Self {
         Self {
-            id,
+            msg_ids: MessageIdAllocator::default(),
             node_id,
             messages,
+            topics: HashMap::new(),
             messages_shared_per_node,
-            node_ids,
+            node_ids: node_ids.into_iter().collect(),
+            ack_gossip: true,
+            spanning_tree: false,
+            tree: HashMap::new(),
+            clock: Box::new(SystemClock),
+            first_seen: HashMap::new(),
+            convergence_latencies: HashMap::new(),
+            max_send_rate: None,
+            send_rates: HashMap::new(),
+            ack_timeout: DEFAULT_ACK_TIMEOUT,
+            last_rate_decrease: HashMap::new(),
+            max_messages: None,
+            messages_order: VecDeque::new(),
+            stale_after: None,
+            last_heard_from_peers: None,
+            last_heard: HashMap::new(),
+            heartbeat_interval: None,
+            last_heartbeat: Duration::ZERO,
+            topology_received: false,
+            peer_counters: BTreeMap::new(),
+            acked_by_all: HashSet::new(),
+            compaction_interval: None,
+            last_compaction: Duration::ZERO,
+            full_sync_interval: None,
+            last_full_sync: Duration::ZERO,
+            checksum_interval: None,
+            last_checksum: Duration::ZERO,
+            max_fan_out: None,
+            deferred: VecDeque::new(),
+            max_deferred: DEFAULT_MAX_DEFERRED,
+            deferred_dropped: 0,
+            reconnect_after: None,
+            partitioned: false,
+            ring: BTreeMap::new(),
+            ring_hasher: Box::new(SipRingHasher::default()),
+            ack_batch_interval: None,
+            last_ack_batch: Duration::ZERO,
+            pending_acks: BTreeMap::new(),
+            in_flight_window: None,
+            in_flight: HashMap::new(),
+            log: Box::new(std::io::stderr()),
+            debug_log: false,
+            correlation_id: String::new(),
+        }
+    }
+
+    pub fn with_ack_gossip(mut self, ack_gossip: bool) -> Self {
+        self.ack_gossip = ack_gossip;
+        self
+    }
+
+    pub fn with_spanning_tree(mut self, spanning_tree: bool) -> Self {
+        self.spanning_tree = spanning_tree;
+        self
+    }
+
+    pub fn with_max_send_rate(mut self, max_send_rate: Option<usize>) -> Self {
+        self.max_send_rate = max_send_rate;
+        self
+    }
+
+    pub fn with_ack_timeout(mut self, ack_timeout: Duration) -> Self {
+        self.ack_timeout = ack_timeout;
+        self
+    }
+
+    pub fn with_max_messages(mut self, max_messages: Option<usize>) -> Self {
+        self.max_messages = max_messages;
+        self
+    }
+
+    pub fn with_stale_after(mut self, stale_after: Option<Duration>) -> Self {
+        self.stale_after = stale_after;
+        self
+    }
+
+    pub fn with_heartbeat_interval(mut self, heartbeat_interval: Option<Duration>) -> Self {
+        self.heartbeat_interval = heartbeat_interval;
+        self
+    }
+
+    pub fn with_compaction_interval(mut self, compaction_interval: Option<Duration>) -> Self {
+        self.compaction_interval = compaction_interval;
+        self
+    }
+
+    pub fn with_full_sync_interval(mut self, full_sync_interval: Option<Duration>) -> Self {
+        self.full_sync_interval = full_sync_interval;
+        self
+    }
+
+    pub fn with_checksum_interval(mut self, checksum_interval: Option<Duration>) -> Self {
+        self.checksum_interval = checksum_interval;
+        self
+    }
+
+    pub fn with_max_fan_out(mut self, max_fan_out: Option<usize>) -> Self {
+        self.max_fan_out = max_fan_out;
+        self
+    }
+
+    pub fn with_max_deferred(mut self, max_deferred: usize) -> Self {
+        self.max_deferred = max_deferred;
+        self
+    }
+
+    pub fn with_reconnect_after(mut self, reconnect_after: Option<Duration>) -> Self {
+        self.reconnect_after = reconnect_after;
+        self
+    }
+
+    pub fn with_partitioned(mut self, partitioned: bool) -> Self {
+        self.partitioned = partitioned;
+        self
+    }
+
+    pub fn with_ring_hasher(mut self, ring_hasher: Box<dyn RingHasher>) -> Self {
+        self.ring_hasher = ring_hasher;
+        self
+    }
+
+    pub fn with_ack_batch_interval(mut self, ack_batch_interval: Option<Duration>) -> Self {
+        self.ack_batch_interval = ack_batch_interval;
+        self
+    }
+
+    pub fn with_in_flight_window(mut self, in_flight_window: Option<Duration>) -> Self {
+        self.in_flight_window = in_flight_window;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Box<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_debug_log(mut self, debug_log: bool) -> Self {
+        self.debug_log = debug_log;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_log(mut self, log: Box<dyn Write>) -> Self {
+        self.log = log;
+        self
+    }
+
+    // one JSON line, tagged with the correlation id of the message being processed if any
+    fn log_line(&mut self, mut line: serde_json::Value) {
+        if !self.correlation_id.is_empty() {
+            line["correlation_id"] = serde_json::json!(self.correlation_id);
+        }
+        if let Err(e) = writeln!(self.log, "{}", line) {
+            eprintln!("Failed logging : {}", e);
+        }
+    }
+
+    // logged on init, to follow how the cluster forms
+    fn log_init_diagnostics(&mut self) {
+        let diagnostics = serde_json::json!({
+            "init": {
+                "node_id": self.node_id,
+                "peer_count": self.node_ids.len(),
+                "topology_received": self.topology_received,
+            }
+        });
+        self.log_line(diagnostics);
+    }
+
+    fn record_first_seen(&mut self, message: i64) {
+        let now = self.clock.now();
+        self.first_seen.entry(message).or_insert(now);
+    }
+
+    // as far as this node knows, compacted messages being known to every neighbor
+    fn has_seen(&self, peer: &str, message: i64) -> bool {
+        (self.acked_by_all.contains(&message) && self.node_ids.contains(peer))
+            || self
+                .messages_shared_per_node
+                .get(peer)
+                .is_some_and(|shared| shared.contains(&message))
+    }
+
+    /// Peers this node believes have seen `message`, in order.
+    pub fn who_has(&self, message: i64) -> Vec<String> {
+        let peers: BTreeSet<&String> = self
+            .node_ids
+            .iter()
+            .chain(self.messages_shared_per_node.keys())
+            .collect();
+        peers
+            .into_iter()
+            .filter(|peer| self.has_seen(peer, message))
+            .cloned()
+            .collect()
+    }
+
+    // once every neighbor acked `message` or sent it to us, how long that took from when it
+    // was first seen
+    fn record_convergence(&mut self, message: i64) {
+        if self.convergence_latencies.contains_key(&message) || self.node_ids.is_empty() {
+            return;
+        }
+        let shared_with_all = self
+            .node_ids
+            .iter()
+            .all(|node| self.has_seen(node, message));
+        if let (true, Some(first_seen)) = (shared_with_all, self.first_seen.get(&message)) {
+            let latency = self.clock.now().saturating_sub(*first_seen);
+            self.convergence_latencies.insert(message, latency);
+        }
+    }
+
+    // a node that never heard from its neighbors since init can't tell how far behind it is
+    fn is_stale(&self) -> bool {
+        match self.stale_after {
+            Some(stale_after) if !self.node_ids.is_empty() => self
+                .last_heard_from_peers
+                .is_none_or(|last_heard| self.clock.now().saturating_sub(last_heard) > stale_after),
+            _ => false,
+        }
+    }
+
+    // stores a message of the default topic, returns whether it is new
+    fn store(&mut self, message: i64) -> bool {
+        if !self.messages.insert(message) {
+            return false;
+        }
+        if self.debug_log {
+            self.log_line(serde_json::json!({ "new_value": message }));
+        }
+        if let Some(max_messages) = self.max_messages {
+            self.messages_order.push_back(message);
+            while self.messages.len() > max_messages {
+                match self.messages_order.pop_front() {
+                    Some(oldest) => self.evict(oldest),
+                    None => break,
+                };
+            }
+        }
+        true
+    }
+
+    fn evict(&mut self, message: i64) {
+        self.messages.remove(&message);
+        self.first_seen.remove(&message);
+        self.convergence_latencies.remove(&message);
+        self.acked_by_all.remove(&message);
+        for shared in self.messages_shared_per_node.values_mut() {
+            shared.remove(&message);
+        }
+    }
+
+    /// Catch-up messages sent to `peer` per broadcast, unlimited without rate control.
+    pub fn send_rate(&self, peer: &str) -> Option<usize> {
+        self.max_send_rate
+            .map(|max| *self.send_rates.get(peer).unwrap_or(&max))
+    }
+
+    fn on_ack(&mut self, peer: &str) {
+        if let Some(rate) = self.send_rate(peer) {
+            let max = self.max_send_rate.unwrap_or(rate);
+            self.send_rates
+                .insert(peer.to_string(), (rate + 1).min(max));
+        }
+    }
+
+    /// Messages of the default topic `peer` hasn't been seen with yet, in ascending order.
+    pub fn unsent_for_peer(&self, peer: &str) -> Vec<i64> {
+        let shared = self.messages_shared_per_node.get(peer);
+        let mut unsent: Vec<i64> = self
+            .messages
+            .iter()
+            .filter(|message| !self.acked_by_all.contains(message))
+            .filter(|message| !shared.is_some_and(|shared| shared.contains(message)))
+            .copied()
+            .collect();
+        unsent.sort();
+        unsent
+    }
+
+    // gossip unacked for longer than the ack timeout is taken as lost and left to be caught
+    // up, whether rate control is on or not; with it, the peers it went to have their rate
+    // halved unless it already was within the last timeout
+    fn on_ack_failures(&mut self) {
+        let now = self.clock.now();
+        let timeout = self.ack_timeout;
+        let mut lagging = vec![];
+        for (peer, sent) in &mut self.in_flight {
+            let before = sent.len();
+            sent.retain(|_, (_, sent_at)| now.saturating_sub(*sent_at) < timeout);
+            if sent.len() < before {
+                lagging.push(peer.clone());
+            }
+        }
+        for peer in lagging {
+            let decreased_recently = self
+                .last_rate_decrease
+                .get(&peer)
+                .is_some_and(|at| now.saturating_sub(*at) < timeout);
+            if decreased_recently {
+                continue;
+            }
+            if let Some(rate) = self.send_rate(&peer) {
+                self.send_rates.insert(peer.clone(), (rate / 2).max(1));
+                self.last_rate_decrease.insert(peer, now);
+            }
+        }
+    }
+
+    /// Number of messages still queued for propagation, summed over the known peers:
+    /// a message stays pending for a peer until that peer has been seen sending it.
+    pub fn outbound_pending(&self) -> usize {
+        self.messages_shared_per_node
+            .keys()
+            .filter(|node| maelstrom_rust::node_id::is_node(node))
+            .map(|node| self.unsent_for_peer(node).len())
+            .sum()
+    }
+
+    // a heartbeat to every neighbor once the interval has elapsed since the previous ones
+    fn heartbeats_if_due(&mut self) -> Vec<Message<BroadcastMessage>> {
+        let Some(interval) = self.heartbeat_interval else {
+            return vec![];
+        };
+        let now = self.clock.now();
+        if now.saturating_sub(self.last_heartbeat) < interval {
+            return vec![];
+        }
+        self.last_heartbeat = now;
+        self.node_ids
+            .iter()
+            .map(|node| Message {
+                src: self.node_id.clone(),
+                dest: Some(node.clone()),
+                body: Body {
+                    msg_id: None,
+                    in_reply_to: None,
+                    body: BroadcastMessage::Heartbeat {},
+                },
+            })
+            .collect()
+    }
+
+    // a batch of acks to every node with some pending once the interval has elapsed since
+    // the previous batches
+    fn ack_batches_if_due(&mut self) -> Vec<Message<BroadcastMessage>> {
+        let Some(interval) = self.ack_batch_interval else {
+            return vec![];
+        };
+        let now = self.clock.now();
+        if now.saturating_sub(self.last_ack_batch) < interval {
+            return vec![];
+        }
+        self.last_ack_batch = now;
+        std::mem::take(&mut self.pending_acks)
+            .into_iter()
+            .map(|(node, msg_ids)| Message {
+                src: self.node_id.clone(),
+                dest: Some(node),
+                body: Body {
+                    msg_id: None,
+                    in_reply_to: None,
+                    body: BroadcastMessage::BroadcastOkBatch { msg_ids },
+                },
+            })
+            .collect()
+    }
+
+    // a digest of the set to every neighbor once the interval has elapsed since the previous
+    // sync, each answering with its values of the buckets that differ
+    fn full_syncs_if_due(&mut self) -> Vec<Message<BroadcastMessage>> {
+        let Some(interval) = self.full_sync_interval else {
+            return vec![];
+        };
+        let now = self.clock.now();
+        if now.saturating_sub(self.last_full_sync) < interval {
+            return vec![];
+        }
+        self.last_full_sync = now;
+        self.node_ids
+            .iter()
+            .map(|node| Message {
+                src: self.node_id.clone(),
+                dest: Some(node.clone()),
+                body: Body {
+                    msg_id: None,
+                    in_reply_to: None,
+                    body: self.sync_digest(),
+                },
+            })
+            .collect()
+    }
+
+    // the checksum of the set to every neighbor once the interval has elapsed since the
+    // previous ones
+    fn checksums_if_due(&mut self) -> Vec<Message<BroadcastMessage>> {
+        let Some(interval) = self.checksum_interval else {
+            return vec![];
+        };
+        let now = self.clock.now();
+        if now.saturating_sub(self.last_checksum) < interval {
+            return vec![];
+        }
+        self.last_checksum = now;
+        let (checksum, count) = (self.checksum(), self.messages.len());
+        self.node_ids
+            .iter()
+            .map(|node| Message {
+                src: self.node_id.clone(),
+                dest: Some(node.clone()),
+                body: Body {
+                    msg_id: None,
+                    in_reply_to: None,
+                    body: BroadcastMessage::Checksum { checksum, count },
+                },
+            })
+            .collect()
+    }
+
+    // XOR of every value mixed through splitmix64's finalizer, so that it doesn't depend on
+    // the order values came in, kept within 2^53 for consumers reading numbers as doubles
+    fn checksum(&self) -> u64 {
+        let checksum = self.messages.iter().fold(0, |acc, value| acc ^ mix(*value));
+        checksum & MAX_SAFE_CHECKSUM
+    }
+
+    // checksum of the values of each bucket, computed like the checksum of the whole set
+    fn bucket_checksums(&self) -> Vec<u64> {
+        let mut buckets = vec![0; SYNC_BUCKETS];
+        for value in &self.messages {
+            buckets[sync_bucket(*value)] ^= mix(*value);
+        }
+        buckets
+            .into_iter()
+            .map(|checksum| checksum & MAX_SAFE_CHECKSUM)
+            .collect()
+    }
+
+    // the same size however many values the set holds
+    fn sync_digest(&self) -> BroadcastMessage {
+        BroadcastMessage::SyncDigest {
+            count: self.messages.len(),
+            buckets: self.bucket_checksums(),
+        }
+    }
+
+    // our values of the given buckets
+    fn bucket_values(&self, buckets: &[usize]) -> HashSet<i64> {
+        self.messages
+            .iter()
+            .filter(|value| buckets.contains(&sync_bucket(**value)))
+            .copied()
+            .collect()
+    }
+
+    // every node gets several points on the ring so that slices stay even
+    fn build_ring(&mut self, cluster: &[String]) {
+        let hasher = self.ring_hasher.as_ref();
+        self.ring = cluster
+            .iter()
+            .flat_map(|node| {
+                (0..RING_POINTS_PER_NODE)
+                    .map(move |point| (ring_hash(hasher, &(node, point)), node.clone()))
+            })
+            .collect();
+    }
+
+    /// Node owning `message`: the first one on the ring at or after the message's hash.
+    pub fn owner(&self, message: i64) -> Option<&String> {
+        let hash = ring_hash(self.ring_hasher.as_ref(), &message);
+        self.ring
+            .range(hash..)
+            .chain(self.ring.range(..hash))
+            .next()
+            .map(|(_, node)| node)
+    }
+
+    // the sender of `msg` when it is a neighbor heard from again after having been silent
+    fn reconnecting_peer(&self, msg: &Message<BroadcastMessage>) -> Option<String> {
+        let reconnect_after = self.reconnect_after?;
+        let src = msg
+            .src
+            .as_ref()
+            .filter(|src| self.node_ids.contains(*src))?;
+        let last_heard = self.last_heard.get(src)?;
+        (self.clock.now().saturating_sub(*last_heard) > reconnect_after).then(|| src.clone())
+    }
+
+    // every message of the default topic `peer` was never seen with, regardless of send rates
+    fn flush_backlog(&mut self, peer: &str) -> Vec<Message<BroadcastMessage>> {
+        self.unsent_for_peer(peer)
+            .into_iter()
+            .map(|message| Message {
+                src: self.node_id.clone(),
+                dest: Some(peer.to_string()),
+                body: Body {
+                    msg_id: None,
+                    in_reply_to: None,
+                    body: BroadcastMessage::Broadcast {
+                        message,
+                        topic: None,
+                    },
+                },
+            })
+            .collect()
+    }
+
+    // what the node sends besides its answers queues up behind what was deferred before
+    fn cap_fan_out(
+        &mut self,
+        sent: Vec<Message<BroadcastMessage>>,
+    ) -> Vec<Message<BroadcastMessage>> {
+        let Some(max_fan_out) = self.max_fan_out else {
+            return sent;
+        };
+        self.deferred.extend(sent);
+        let sent_now = max_fan_out.min(self.deferred.len());
+        let sent = self.deferred.drain(..sent_now).collect();
+        let dropped = self.deferred.len().saturating_sub(self.max_deferred);
+        self.deferred.drain(..dropped);
+        self.deferred_dropped += dropped as u64;
+        sent
+    }
+
+    // gossip of a value still in flight to its peer within the window is dropped, the rest is
+    // numbered and recorded as in flight
+    fn suppress_in_flight(
+        &mut self,
+        sent: Vec<Message<BroadcastMessage>>,
+    ) -> Vec<Message<BroadcastMessage>> {
+        let window = self.in_flight_window;
+        let now = self.clock.now();
+        let in_flight = &mut self.in_flight;
+        let msg_ids = &self.msg_ids;
+        sent.into_iter()
+            .filter_map(|mut reply| {
+                let (
+                    Some(dest),
+                    None,
+                    BroadcastMessage::Broadcast {
+                        message,
+                        topic: None,
+                    },
+                ) = (
+                    reply
+                        .dest
+                        .as_ref()
+                        .filter(|dest| maelstrom_rust::node_id::is_node(dest)),
+                    reply.body.in_reply_to,
+                    &reply.body.body,
+                )
+                else {
+                    return Some(reply);
+                };
+                let sent = in_flight.entry(dest.clone()).or_default();
+                if sent.get(message).is_some_and(|(_, sent_at)| {
+                    window.is_some_and(|window| now.saturating_sub(*sent_at) < window)
+                }) {
+                    return None;
+                }
+                let msg_id = *reply.body.msg_id.get_or_insert_with(|| msg_ids.next());
+                sent.insert(*message, (msg_id, now));
+                Some(reply)
+            })
+            .collect()
+    }
+
+    // whatever was in flight to `peer` with `msg_id` has arrived, the peer having it from now on
+    fn on_acked(&mut self, peer: &str, msg_id: i64) {
+        let Some(sent) = self.in_flight.get_mut(peer) else {
+            return;
+        };
+        let acked: Vec<i64> = sent
+            .iter()
+            .filter(|(_, (sent_msg_id, _))| *sent_msg_id == msg_id)
+            .map(|(message, _)| *message)
+            .collect();
+        for message in &acked {
+            sent.remove(message);
+        }
+        for message in acked {
+            self.messages_shared_per_node
+                .entry(peer.to_string())
+                .or_default()
+                .insert(message);
+            self.record_convergence(message);
+        }
+    }
+
+    // Runs a compaction once the interval has elapsed since the previous one. Entries of peers
+    // that aren't neighbors anymore are dropped (they'd get a full catch-up if they came back)
+    // and messages all neighbors have move to `acked_by_all`, stored once instead of per peer.
+    fn compact_if_due(&mut self) {
+        let Some(interval) = self.compaction_interval else {
+            return;
+        };
+        let now = self.clock.now();
+        if now.saturating_sub(self.last_compaction) < interval {
+            return;
+        }
+        self.last_compaction = now;
+
+        let node_ids = &self.node_ids;
+        self.messages_shared_per_node
+            .retain(|node, _| node_ids.contains(node));
+        if self.node_ids.is_empty() || self.messages_shared_per_node.len() < self.node_ids.len() {
+            return;
+        }
+        let mut shared_sets = self.messages_shared_per_node.values();
+        let mut acked: HashSet<i64> = shared_sets.next().cloned().unwrap_or_default();
+        for shared in shared_sets {
+            acked.retain(|message| shared.contains(message));
         }
+        for shared in self.messages_shared_per_node.values_mut() {
+            shared.retain(|message| !acked.contains(message));
+        }
+        self.acked_by_all.extend(acked);
+    }
+
+    // `acked_by_all` only holds for the neighbors it was computed with, so it is given back
+    // to each of them before they change
+    fn expand_compacted(&mut self) {
+        for node in &self.node_ids {
+            if let Some(shared) = self.messages_shared_per_node.get_mut(node) {
+                shared.extend(self.acked_by_all.iter());
+            }
+        }
+        self.acked_by_all.clear();
     }
 }
 
+const RING_POINTS_PER_NODE: u32 = 16;
+
+const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_millis(1000);
+
+const DEFAULT_MAX_DEFERRED: usize = 10_000;
+
+// buckets values are spread over for syncs, digests holding a checksum of each
+const SYNC_BUCKETS: usize = 32;
+const MAX_SAFE_CHECKSUM: u64 = (1 << 53) - 1;
+
+// splitmix64's finalizer, spreading values evenly whatever they are
+fn mix(value: i64) -> u64 {
+    let mut z = (value as u64).wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn sync_bucket(value: i64) -> usize {
+    (mix(value) % SYNC_BUCKETS as u64) as usize
+}
+
 impl Default for BroadcastMaelstromNode {
     fn default() -> Self {
-        Self::new(1, None, HashSet::new(), HashMap::new(), HashSet::new())
+        Self::new(None, HashSet::new(), HashMap::new(), HashSet::new())
     }
 }
 
@@ -45,67 +793,229 @@ impl Default for BroadcastMaelstromNode {
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 pub enum BroadcastMessage {
-    Init {
-        node_id: String,
-        node_ids: HashSet<String>,
-    },
-    InitOk {},
+    // without a topic, messages go to the default topic
     Broadcast {
+        #[serde(deserialize_with = "large_ints::int")]
         message: i64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        topic: Option<String>,
     },
     BroadcastOk {},
-    Read {},
+    // acks of several broadcasts gossiped by the same node, by msg_id
+    BroadcastOkBatch {
+        #[serde(deserialize_with = "large_ints::collection")]
+        msg_ids: Vec<i64>,
+    },
+    // without a cursor nor a limit the whole set is read at once, the cursor being the last
+    // value of the previous page
+    Read {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        topic: Option<String>,
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none",
+            deserialize_with = "large_ints::option"
+        )]
+        cursor: Option<i64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<usize>,
+    },
     ReadOk {
+        #[serde(deserialize_with = "large_ints::collection")]
         messages: HashSet<i64>,
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none",
+            deserialize_with = "large_ints::option"
+        )]
+        next_cursor: Option<i64>,
+        // set when the node hasn't heard from its neighbors recently, see `stale_after`
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        stale: bool,
     },
     Topology {
         topology: HashMap<String, HashSet<String>>,
     },
     TopologyOk {},
+    // reconfiguration of the neighbors without going through a whole new topology
+    AddPeer {
+        node_id: String,
+    },
+    AddPeerOk {},
+    RemovePeer {
+        node_id: String,
+    },
+    RemovePeerOk {},
+    // liveness only, never answered
+    Heartbeat {},
+    OutboundPending {},
+    OutboundPendingOk {
+        pending: usize,
+    },
+    // where the node sits in the spanning tree, all empty when not gossiping along one
+    TreeInfo {},
+    TreeInfoOk {
+        root: Option<String>,
+        parent: Option<String>,
+        children: Vec<String>,
+    },
+    // peers the node believes have seen a value, for debugging propagation
+    WhoHas {
+        #[serde(deserialize_with = "large_ints::int")]
+        message: i64,
+    },
+    WhoHasOk {
+        #[serde(deserialize_with = "large_ints::int")]
+        message: i64,
+        peers: Vec<String>,
+    },
+    // of a peer's set, answered with a digest of ours when it differs
+    Checksum {
+        #[serde(deserialize_with = "large_ints::int")]
+        checksum: u64,
+        count: usize,
+    },
+    // of a peer's set: its size and the checksum of each of the `SYNC_BUCKETS` buckets values
+    // are spread over, answered with our values of the buckets that differ
+    SyncDigest {
+        count: usize,
+        #[serde(deserialize_with = "large_ints::collection")]
+        buckets: Vec<u64>,
+    },
+    // values of the buckets listed, answered with the values of those buckets the peer is
+    // missing, without buckets listed those values themselves
+    SyncDelta {
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        buckets: Vec<usize>,
+        #[serde(deserialize_with = "large_ints::collection")]
+        messages: HashSet<i64>,
+    },
 }
 
-impl Processor<BroadcastMessage> for BroadcastMaelstromNode {
-    fn process(
+impl BroadcastMessage {
+    // the `type` the message is sent with
+    fn type_name(&self) -> &'static str {
+        match self {
+            BroadcastMessage::Broadcast { .. } => "broadcast",
+            BroadcastMessage::BroadcastOk {} => "broadcast_ok",
+            BroadcastMessage::BroadcastOkBatch { .. } => "broadcast_ok_batch",
+            BroadcastMessage::Read { .. } => "read",
+            BroadcastMessage::ReadOk { .. } => "read_ok",
+            BroadcastMessage::Topology { .. } => "topology",
+            BroadcastMessage::TopologyOk {} => "topology_ok",
+            BroadcastMessage::AddPeer { .. } => "add_peer",
+            BroadcastMessage::AddPeerOk {} => "add_peer_ok",
+            BroadcastMessage::RemovePeer { .. } => "remove_peer",
+            BroadcastMessage::RemovePeerOk {} => "remove_peer_ok",
+            BroadcastMessage::Heartbeat {} => "heartbeat",
+            BroadcastMessage::OutboundPending {} => "outbound_pending",
+            BroadcastMessage::OutboundPendingOk { .. } => "outbound_pending_ok",
+            BroadcastMessage::TreeInfo {} => "tree_info",
+            BroadcastMessage::TreeInfoOk { .. } => "tree_info_ok",
+            BroadcastMessage::WhoHas { .. } => "who_has",
+            BroadcastMessage::WhoHasOk { .. } => "who_has_ok",
+            BroadcastMessage::Checksum { .. } => "checksum",
+            BroadcastMessage::SyncDigest { .. } => "sync_digest",
+            BroadcastMessage::SyncDelta { .. } => "sync_delta",
+        }
+    }
+}
+
+impl BroadcastMaelstromNode {
+    // answers are returned, gossip, forwards and catch-ups going through `sender`
+    fn handle(
         &mut self,
         msg: Message<BroadcastMessage>,
+        sender: &mut dyn Sender<BroadcastMessage>,
     ) -> Result<Option<Vec<Message<BroadcastMessage>>>> {
+        if let Some(src) = msg.src.as_ref().filter(|src| self.node_ids.contains(*src)) {
+            let now = self.clock.now();
+            self.last_heard_from_peers = Some(now);
+            self.last_heard.insert(src.clone(), now);
+        }
         match msg.body.body {
-            BroadcastMessage::Init { node_id, node_ids } => {
-                let reply_msgs = vec![Message {
-                    src: msg.dest,
-                    dest: msg.src,
-                    body: Body {
-                        msg_id: Some(self.id),
-                        in_reply_to: msg.body.msg_id,
-                        body: BroadcastMessage::InitOk {},
-                    },
-                }];
-                let reply = Ok(Some(reply_msgs));
-
-                self.id += 1;
-                self.node_id = Some(node_id.clone());
-                self.node_ids = node_ids;
-                // we keep only other nodes by removing the current one
-                self.node_ids.retain(|n| n != &node_id);
-
-                reply
+            BroadcastMessage::Broadcast {
+                message,
+                topic: Some(ref topic),
+            } => {
+                if self
+                    .topics
+                    .entry(topic.clone())
+                    .or_default()
+                    .insert(message)
+                {
+                    for broadcast_dest in &self.node_ids {
+                        if msg.src.as_ref() != Some(broadcast_dest) {
+                            sender.send(Message {
+                                src: self.node_id.clone(),
+                                dest: Some(broadcast_dest.to_string()),
+                                body: Body {
+                                    msg_id: None,
+                                    in_reply_to: None,
+                                    body: BroadcastMessage::Broadcast {
+                                        message,
+                                        topic: Some(topic.clone()),
+                                    },
+                                },
+                            });
+                        }
+                    }
+                }
+                Ok(Some(vec![
+                    msg.reply_with(None, BroadcastMessage::BroadcastOk {})
+                ]))
             }
-            BroadcastMessage::Broadcast { message } => {
-                let broadcast_ok_reply_msg = Message {
-                    src: msg.dest,
-                    dest: msg.src.clone(),
-                    body: Body {
-                        msg_id: Some(self.id),
-                        in_reply_to: msg.body.msg_id,
-                        body: BroadcastMessage::BroadcastOk {},
-                    },
-                };
-                self.id += 1;
+            BroadcastMessage::Broadcast {
+                message,
+                topic: None,
+            } => {
+                let mut reply_msgs = vec![];
+                self.record_first_seen(message);
+
+                // clients always get their ack, gossip from other nodes only if configured to
+                let from_node = msg
+                    .src
+                    .as_ref()
+                    .is_some_and(|src| maelstrom_rust::node_id::is_node(src));
+                let batched_ack = from_node && self.ack_gossip && self.ack_batch_interval.is_some();
+                let owner = self
+                    .owner(message)
+                    .filter(|owner| !from_node && self.node_id.as_ref() != Some(*owner))
+                    .cloned();
+                if let Some(owner) = owner {
+                    // kept here as well, but gossiped by its owner only
+                    self.store(message);
+                    sender.send(Message {
+                        src: self.node_id.clone(),
+                        dest: Some(owner),
+                        body: Body {
+                            msg_id: None,
+                            in_reply_to: None,
+                            body: BroadcastMessage::Broadcast {
+                                message,
+                                topic: None,
+                            },
+                        },
+                    });
+                    return Ok(Some(vec![
+                        msg.reply_with(None, BroadcastMessage::BroadcastOk {})
+                    ]));
+                }
+                if let (true, Some(src), Some(msg_id)) =
+                    (batched_ack, msg.src.as_ref(), msg.body.msg_id)
+                {
+                    self.pending_acks
+                        .entry(src.clone())
+                        .or_default()
+                        .push(msg_id);
+                } else if !from_node || self.ack_gossip {
+                    reply_msgs.push(msg.reply_with(None, BroadcastMessage::BroadcastOk {}));
+                }
 
                 // update the list of nodes who have seen the message with the sender
                 if let Some(src) = msg.src.as_ref() {
                     // we want to avoid seed nodes, outside of topology
-                    if src.starts_with('n') {
+                    if maelstrom_rust::node_id::is_node(src) {
                         let messages_for_sender = self
                             .messages_shared_per_node
                             .entry(src.clone())
@@ -116,373 +1026,2912 @@ impl Processor<BroadcastMessage> for BroadcastMaelstromNode {
 
                 // we keep track of all messages shared per node and create a list of messages to broadcast to all neighbors if they haven't seen them yet
                 // we update the Map of seen messages per node on receiving a BroadcastOk ( confirmation)
+                self.on_ack_failures();
                 let prev_messages_to_broadcast = broadcast_all_seen_messages(self, &message);
-                self.id += prev_messages_to_broadcast.len() as i64;
 
-                // if we haven't seen this message before, we insert it and we broadcast it to all neighbors but the sender
-                if self.messages.insert(message) {
-                    let mut reply_msgs = vec![broadcast_ok_reply_msg];
+                // if we haven't seen this message before, we insert it and we broadcast it to all neighbors but the sender
+                if self.store(message) {
+                    // we take all neighbors except the node who sent the actual broadcast message
+                    if let Some(src) = msg.src.as_ref() {
+                        for broadcast_dest in &self.node_ids {
+                            if src != broadcast_dest {
+                                sender.send(Message {
+                                    src: self.node_id.clone(),
+                                    dest: Some(broadcast_dest.to_string()),
+                                    body: Body {
+                                        msg_id: None,
+                                        in_reply_to: None,
+                                        body: BroadcastMessage::Broadcast {
+                                            message,
+                                            topic: None,
+                                        },
+                                    },
+                                });
+                            }
+                        }
+                    }
+                }
+                for catch_up in prev_messages_to_broadcast {
+                    sender.send(catch_up);
+                }
+                self.record_convergence(message);
+                Ok(Some(reply_msgs))
+            }
+            BroadcastMessage::Heartbeat {} => Ok(None),
+            BroadcastMessage::BroadcastOk {} => {
+                if let Some(src) = msg.src.as_ref() {
+                    self.on_ack(src);
+                    if let Some(msg_id) = msg.body.in_reply_to {
+                        self.on_acked(src, msg_id);
+                    }
+                }
+                Ok(None)
+            }
+            BroadcastMessage::BroadcastOkBatch { msg_ids } => {
+                if let Some(src) = msg.src.as_ref() {
+                    for msg_id in msg_ids {
+                        self.on_ack(src);
+                        self.on_acked(src, msg_id);
+                    }
+                }
+                Ok(None)
+            }
+            BroadcastMessage::Read {
+                ref topic,
+                cursor,
+                limit,
+            } => {
+                let empty = HashSet::new();
+                let topic_messages = match topic {
+                    Some(topic) => self.topics.get(topic).unwrap_or(&empty),
+                    None => &self.messages,
+                };
+                let (messages, next_cursor) = match (cursor, limit) {
+                    (None, None) => (topic_messages.clone(), None),
+                    // pages are cut from the sorted set past the last value of the previous page,
+                    // so values stored between two pages don't shift the following ones
+                    (cursor, limit) => {
+                        let sorted: BTreeSet<i64> = topic_messages.iter().copied().collect();
+                        let start = cursor.map_or(Unbounded, Excluded);
+                        let mut rest = sorted.range((start, Unbounded)).copied();
+                        let page: Vec<i64> =
+                            rest.by_ref().take(limit.unwrap_or(usize::MAX)).collect();
+                        let next_cursor = rest.next().and(page.last().copied());
+                        (page.into_iter().collect(), next_cursor)
+                    }
+                };
+                Ok(Some(vec![msg.reply_with(
+                    None,
+                    BroadcastMessage::ReadOk {
+                        messages,
+                        next_cursor,
+                        stale: self.is_stale(),
+                    },
+                )]))
+            }
+
+            BroadcastMessage::Topology { ref topology } => {
+                let reply = Ok(Some(vec![
+                    msg.reply_with(None, BroadcastMessage::TopologyOk {})
+                ]));
+
+                // messages are processed one at a time so the new neighbors apply atomically:
+                // every broadcast processed before this fans out to the previous neighbors,
+                // every one processed after (gossip and catch-ups alike) to the new ones only
+                let topology = if self.spanning_tree {
+                    self.tree = spanning_tree(topology);
+                    self.tree.clone()
+                } else {
+                    topology.clone()
+                };
+                self.topology_received = true;
+                self.expand_compacted();
+                if let Some(node) = &self.node_id {
+                    if let Some(node_ids) = topology.get(node) {
+                        self.node_ids = node_ids.iter().cloned().collect();
+                    }
+                };
+                reply
+            }
+
+            BroadcastMessage::AddPeer { ref node_id } => {
+                self.expand_compacted();
+                if self.node_id.as_ref() != Some(node_id) {
+                    self.node_ids.insert(node_id.clone());
+                }
+                Ok(Some(vec![
+                    msg.reply_with(None, BroadcastMessage::AddPeerOk {})
+                ]))
+            }
+
+            BroadcastMessage::RemovePeer { ref node_id } => {
+                self.node_ids.remove(node_id);
+                Ok(Some(vec![
+                    msg.reply_with(None, BroadcastMessage::RemovePeerOk {})
+                ]))
+            }
+
+            BroadcastMessage::OutboundPending {} => Ok(Some(vec![msg.reply_with(
+                None,
+                BroadcastMessage::OutboundPendingOk {
+                    pending: self.outbound_pending(),
+                },
+            )])),
+
+            BroadcastMessage::TreeInfo {} => {
+                let (root, parent, children) = self
+                    .node_id
+                    .as_ref()
+                    .and_then(|node| tree_position(&self.tree, node))
+                    .unwrap_or_default();
+                Ok(Some(vec![msg.reply_with(
+                    None,
+                    BroadcastMessage::TreeInfoOk {
+                        root,
+                        parent,
+                        children,
+                    },
+                )]))
+            }
+
+            BroadcastMessage::WhoHas { message } => Ok(Some(vec![msg.reply_with(
+                None,
+                BroadcastMessage::WhoHasOk {
+                    message,
+                    peers: self.who_has(message),
+                },
+            )])),
+
+            BroadcastMessage::Checksum { checksum, count } => {
+                if checksum == self.checksum() && count == self.messages.len() {
+                    return Ok(None);
+                }
+                self.log_line(serde_json::json!({ "diverged_from": msg.src }));
+                Ok(Some(vec![msg.reply_with(None, self.sync_digest())]))
+            }
+
+            BroadcastMessage::SyncDigest { count, ref buckets } => {
+                let ours = self.bucket_checksums();
+                let mut differing: Vec<usize> = (0..SYNC_BUCKETS)
+                    .filter(|bucket| buckets.get(*bucket) != ours.get(*bucket))
+                    .collect();
+                // sizes differing while no checksum does can only be a collision, every bucket
+                // being sent then
+                if differing.is_empty() && count != self.messages.len() {
+                    differing = (0..SYNC_BUCKETS).collect();
+                }
+                if differing.is_empty() {
+                    return Ok(None);
+                }
+                let messages = self.bucket_values(&differing);
+                Ok(Some(vec![msg.reply_with(
+                    None,
+                    BroadcastMessage::SyncDelta {
+                        buckets: differing,
+                        messages,
+                    },
+                )]))
+            }
+
+            BroadcastMessage::SyncDelta {
+                ref buckets,
+                ref messages,
+            } => {
+                // what the peer has of those buckets and we don't is what it sent, the other
+                // way round what it didn't send
+                let missing: HashSet<i64> = self
+                    .bucket_values(buckets)
+                    .difference(messages)
+                    .copied()
+                    .collect();
+                // having sent them, the peer has them
+                if let Some(src) = msg
+                    .src
+                    .as_ref()
+                    .filter(|src| maelstrom_rust::node_id::is_node(src))
+                {
+                    self.messages_shared_per_node
+                        .entry(src.clone())
+                        .or_default()
+                        .extend(messages.iter());
+                }
+                for message in messages {
+                    self.record_first_seen(*message);
+                    self.store(*message);
+                    self.record_convergence(*message);
+                }
+                if missing.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some(vec![msg.reply_with(
+                    None,
+                    BroadcastMessage::SyncDelta {
+                        buckets: vec![],
+                        messages: missing,
+                    },
+                )]))
+            }
+
+            _ => Err(anyhow!("Received unknown message: {:?}", msg)),
+        }
+    }
+
+    // what goes out after handling a message or on a tick: the periodic work that is due
+    // joins what the node sends of its own, which is then throttled, and everything counted
+    fn outgoing(
+        &mut self,
+        replies: &[Message<BroadcastMessage>],
+        mut sent: Vec<Message<BroadcastMessage>>,
+    ) -> Vec<Message<BroadcastMessage>> {
+        sent.extend(
+            [
+                self.ack_batches_if_due(),
+                self.heartbeats_if_due(),
+                self.full_syncs_if_due(),
+                self.checksums_if_due(),
+            ]
+            .concat(),
+        );
+        self.compact_if_due();
+        self.on_ack_failures();
+        let sent = self.suppress_in_flight(sent);
+        let sent = self.cap_fan_out(sent);
+        for reply in replies.iter().chain(&sent) {
+            let Some(dest) = reply
+                .dest
+                .as_ref()
+                .filter(|dest| maelstrom_rust::node_id::is_node(dest))
+            else {
+                continue;
+            };
+            self.peer_counters.entry(dest.clone()).or_default().sent += 1;
+            if self.debug_log {
+                let sent_type = reply.body.body.type_name();
+                self.log_line(serde_json::json!({ "sent": { "dest": dest, "type": sent_type } }));
+            }
+        }
+        sent
+    }
+}
+
+impl Processor<BroadcastMessage> for BroadcastMaelstromNode {
+    fn process(
+        &mut self,
+        msg: Message<BroadcastMessage>,
+    ) -> Result<Option<Vec<Message<BroadcastMessage>>>> {
+        process_collecting(self, msg)
+    }
+
+    // what the node sends of its own is collected first, to be throttled before going
+    // through `sender`
+    fn process_with_sender(
+        &mut self,
+        msg: Message<BroadcastMessage>,
+        sender: &mut dyn Sender<BroadcastMessage>,
+    ) -> Result<Option<Vec<Message<BroadcastMessage>>>> {
+        self.correlation_id = msg.correlation_id();
+        if let Some(src) = msg
+            .src
+            .as_ref()
+            .filter(|src| maelstrom_rust::node_id::is_node(src))
+        {
+            self.peer_counters.entry(src.clone()).or_default().received += 1;
+        }
+        let reconnecting_peer = self.reconnecting_peer(&msg);
+        let mut sent = vec![];
+        let replies = self.handle(msg, &mut sent)?;
+        if let Some(peer) = reconnecting_peer {
+            sent.extend(self.flush_backlog(&peer));
+        }
+        let replies = replies.filter(|replies| !replies.is_empty());
+        for msg in self.outgoing(replies.as_deref().unwrap_or_default(), sent) {
+            sender.send(msg);
+        }
+        Ok(replies)
+    }
+
+    // one timer for all periodic work, ticking as often as the shortest interval configured,
+    // each piece of work checking whether it is due
+    fn timers(&self) -> Vec<(&'static str, Duration)> {
+        [
+            self.ack_batch_interval,
+            self.heartbeat_interval,
+            self.full_sync_interval,
+            self.checksum_interval,
+            self.compaction_interval,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .map(|interval| ("periodic", interval))
+        .into_iter()
+        .collect()
+    }
+
+    fn on_timer(&mut self, _timer: &str) -> Result<Option<Vec<Message<BroadcastMessage>>>> {
+        self.correlation_id.clear();
+        let sent = self.outgoing(&[], vec![]);
+        Ok((!sent.is_empty()).then_some(sent))
+    }
+
+    fn use_msg_ids(&mut self, msg_ids: MessageIdAllocator) {
+        self.msg_ids = msg_ids;
+    }
+
+    fn on_init(&mut self, node_id: String, node_ids: Vec<String>) -> Result<()> {
+        self.node_id = Some(node_id.clone());
+        // nothing the node logs from now on is about a message
+        self.correlation_id.clear();
+        if self.partitioned {
+            self.build_ring(&node_ids);
+        }
+        self.node_ids = node_ids.into_iter().collect();
+        // we keep only other nodes by removing the current one
+        self.node_ids.retain(|n| n != &node_id);
+        self.last_heard_from_peers = Some(self.clock.now());
+        self.log_init_diagnostics();
+        Ok(())
+    }
+
+    // forget every message, the topology, the ring and when periodic work last ran, but keep
+    // the node identity, neighbors and options; the ring is built again on the next init
+    fn reset(&mut self) {
+        self.messages.clear();
+        self.topics.clear();
+        self.messages_shared_per_node.clear();
+        self.first_seen.clear();
+        self.convergence_latencies.clear();
+        self.send_rates.clear();
+        self.messages_order.clear();
+        self.peer_counters.clear();
+        self.acked_by_all.clear();
+        self.pending_acks.clear();
+        self.last_heard.clear();
+        self.last_heard_from_peers = None;
+        self.last_heartbeat = Duration::ZERO;
+        self.deferred.clear();
+        self.deferred_dropped = 0;
+        self.in_flight.clear();
+        self.last_rate_decrease.clear();
+        self.tree.clear();
+        self.ring.clear();
+        self.topology_received = false;
+        self.last_compaction = Duration::ZERO;
+        self.last_full_sync = Duration::ZERO;
+        self.last_checksum = Duration::ZERO;
+        self.last_ack_batch = Duration::ZERO;
+        self.correlation_id.clear();
+    }
+
+    // distribution of the time taken by messages to reach all neighbors, from when first seen,
+    // and the number of messages exchanged with each peer
+    fn dump(&self) -> Option<serde_json::Value> {
+        let mut latencies: Vec<u128> = self
+            .convergence_latencies
+            .values()
+            .map(|latency| latency.as_millis())
+            .collect();
+        latencies.sort();
+        Some(serde_json::json!({
+            "convergence_ms": {
+                "count": latencies.len(),
+                "min": latencies.first(),
+                "median": latencies.get(latencies.len() / 2),
+                "max": latencies.last(),
+            },
+            "peers": self.peer_counters,
+            "deferred_dropped": self.deferred_dropped,
+        }))
+    }
+
+    fn save_state(&self) -> Option<serde_json::Value> {
+        let mut messages: Vec<&i64> = self.messages.iter().collect();
+        messages.sort();
+        Some(serde_json::json!({ "messages": messages }))
+    }
+}
+
+fn broadcast_all_seen_messages(
+    processor: &BroadcastMaelstromNode,
+    filter_message: &i64,
+) -> Vec<Message<BroadcastMessage>> {
+    // only current neighbors are caught up, a node dropped by a topology update isn't anymore
+    processor
+        .node_ids
+        .iter()
+        .filter(|node| {
+            maelstrom_rust::node_id::is_node(node)
+                && processor.messages_shared_per_node.contains_key(*node)
+        })
+        .flat_map(|node| {
+            processor
+                .unsent_for_peer(node)
+                .into_iter()
+                .filter(|m| m != filter_message)
+                .take(processor.send_rate(node).unwrap_or(usize::MAX))
+                .map(move |not_shared_msg_for_node| (node, not_shared_msg_for_node))
+        })
+        .map(|(node, not_shared_msg_for_node)| Message {
+            src: processor.node_id.clone(),
+            dest: Some(node.clone()),
+            body: Body {
+                msg_id: None,
+                in_reply_to: None,
+                body: BroadcastMessage::Broadcast {
+                    message: not_shared_msg_for_node,
+                    topic: None,
+                },
+            },
+        })
+        .collect()
+}
+
+// Spanning tree (forest if the topology is disconnected) of the topology, as an adjacency map.
+// Links are taken as bidirectional and every edge weighs the same, so any spanning tree is
+// minimal: this one is built breadth-first from the smallest node ids to be deterministic.
+fn spanning_tree(topology: &HashMap<String, HashSet<String>>) -> HashMap<String, HashSet<String>> {
+    let mut links: HashMap<&String, HashSet<&String>> = HashMap::new();
+    for (node, neighbors) in topology {
+        links.entry(node).or_default();
+        for neighbor in neighbors {
+            links.entry(node).or_default().insert(neighbor);
+            links.entry(neighbor).or_default().insert(node);
+        }
+    }
+    let mut nodes: Vec<&String> = links.keys().copied().collect();
+    nodes.sort_by(|a, b| node_id::compare(a, b));
+
+    let mut tree: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut visited: HashSet<&String> = HashSet::new();
+    for root in nodes {
+        if !visited.insert(root) {
+            continue;
+        }
+        tree.entry(root.clone()).or_default();
+        let mut to_visit = VecDeque::from([root]);
+        while let Some(node) = to_visit.pop_front() {
+            let mut neighbors: Vec<&String> = links[node].iter().copied().collect();
+            neighbors.sort_by(|a, b| node_id::compare(a, b));
+            for neighbor in neighbors {
+                if visited.insert(neighbor) {
+                    tree.entry(node.clone())
+                        .or_default()
+                        .insert(neighbor.to_string());
+                    tree.entry(neighbor.to_string())
+                        .or_default()
+                        .insert(node.clone());
+                    to_visit.push_back(neighbor);
+                }
+            }
+        }
+    }
+    tree
+}
+
+// Root, parent and children of `node` in `tree`, rooted like `spanning_tree` builds it: at the
+// smallest node id of the node's component.
+fn tree_position(
+    tree: &HashMap<String, HashSet<String>>,
+    node: &String,
+) -> Option<(Option<String>, Option<String>, Vec<String>)> {
+    tree.get(node)?;
+    let mut component: HashSet<&String> = HashSet::from([node]);
+    let mut to_visit = vec![node];
+    while let Some(current) = to_visit.pop() {
+        for neighbor in &tree[current] {
+            if component.insert(neighbor) {
+                to_visit.push(neighbor);
+            }
+        }
+    }
+    let root = component
+        .into_iter()
+        .min_by(|a, b| node_id::compare(a, b))?;
+
+    let mut parents: HashMap<&String, &String> = HashMap::new();
+    let mut to_visit = VecDeque::from([root]);
+    while let Some(current) = to_visit.pop_front() {
+        for neighbor in &tree[current] {
+            if neighbor != root && !parents.contains_key(neighbor) {
+                parents.insert(neighbor, current);
+                to_visit.push_back(neighbor);
+            }
+        }
+    }
+    let parent = parents.get(node).map(|parent| parent.to_string());
+    let mut children: Vec<String> = tree[node]
+        .iter()
+        .filter(|neighbor| Some(*neighbor) != parent.as_ref())
+        .cloned()
+        .collect();
+    children.sort_by(|a, b| node_id::compare(a, b));
+    Some((Some(root.clone()), parent, children))
+}
+
+fn main() -> anyhow::Result<()> {
+    let ack_gossip = std::env::var("BROADCAST_ACK_GOSSIP").map_or(true, |v| v != "false");
+    let spanning_tree = std::env::var("BROADCAST_SPANNING_TREE").is_ok_and(|v| v == "true");
+    let max_send_rate = std::env::var("BROADCAST_MAX_SEND_RATE")
+        .ok()
+        .and_then(|v| v.parse().ok());
+    let ack_timeout = std::env::var("BROADCAST_ACK_TIMEOUT_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map_or(DEFAULT_ACK_TIMEOUT, Duration::from_millis);
+    let max_messages = std::env::var("BROADCAST_MAX_MESSAGES")
+        .ok()
+        .and_then(|v| v.parse().ok());
+    let stale_after = std::env::var("BROADCAST_STALE_AFTER_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_millis);
+    let heartbeat_interval = std::env::var("BROADCAST_HEARTBEAT_INTERVAL_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_millis);
+    let compaction_interval = std::env::var("BROADCAST_COMPACTION_INTERVAL_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_millis);
+    let full_sync_interval = std::env::var("BROADCAST_FULL_SYNC_INTERVAL_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_millis);
+    let checksum_interval = std::env::var("BROADCAST_CHECKSUM_INTERVAL_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_millis);
+    let max_fan_out = std::env::var("BROADCAST_MAX_FAN_OUT")
+        .ok()
+        .and_then(|v| v.parse().ok());
+    let max_deferred = std::env::var("BROADCAST_MAX_DEFERRED")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_DEFERRED);
+    let reconnect_after = std::env::var("BROADCAST_RECONNECT_AFTER_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_millis);
+    let partitioned = std::env::var("BROADCAST_PARTITIONED").is_ok_and(|v| v == "true");
+    let ring_hash_seed = std::env::var("BROADCAST_RING_HASH_SEED")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    let ring_hasher = ring_hasher(
+        &std::env::var("BROADCAST_RING_HASHER").unwrap_or_else(|_| "default".into()),
+        ring_hash_seed,
+    )
+    .ok_or_else(|| anyhow!("BROADCAST_RING_HASHER must be one of default, fnv or xxhash"))?;
+    let ack_batch_interval = std::env::var("BROADCAST_ACK_BATCH_INTERVAL_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_millis);
+    let in_flight_window = std::env::var("BROADCAST_IN_FLIGHT_WINDOW_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_millis);
+    let debug_log = std::env::var("BROADCAST_DEBUG_LOG").is_ok_and(|v| v == "true");
+    let mut node = BroadcastMaelstromNode::default()
+        .with_ack_gossip(ack_gossip)
+        .with_spanning_tree(spanning_tree)
+        .with_max_send_rate(max_send_rate)
+        .with_ack_timeout(ack_timeout)
+        .with_max_messages(max_messages)
+        .with_stale_after(stale_after)
+        .with_heartbeat_interval(heartbeat_interval)
+        .with_compaction_interval(compaction_interval)
+        .with_full_sync_interval(full_sync_interval)
+        .with_checksum_interval(checksum_interval)
+        .with_max_fan_out(max_fan_out)
+        .with_max_deferred(max_deferred)
+        .with_reconnect_after(reconnect_after)
+        .with_partitioned(partitioned)
+        .with_ring_hasher(ring_hasher)
+        .with_ack_batch_interval(ack_batch_interval)
+        .with_in_flight_window(in_flight_window)
+        .with_debug_log(debug_log);
+    // stale messages from before a peer restarted are told apart by its epoch
+    if std::env::var("BROADCAST_EPOCHS").is_ok_and(|v| v == "true") {
+        run_with_timers(&mut EpochTagging::new(node))
+    } else {
+        run_with_timers(&mut node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::spanning_tree;
+    use crate::BroadcastMaelstromNode;
+    use crate::BroadcastMessage;
+    use std::collections::BTreeSet;
+    use std::collections::HashMap;
+    use std::collections::HashSet;
+
+    use maelstrom_rust::clock::*;
+    use maelstrom_rust::msg_protocol::*;
+    use maelstrom_rust::recording::*;
+    use maelstrom_rust::runner::MsgIdTracker;
+    use maelstrom_rust::testkit::*;
+
+    use maplit::hashmap;
+    use std::cell::Cell;
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use serde_json::from_str;
+    use serde_json::json;
+    use serde_json::to_string;
+    mod stubs {
+        use super::*;
+
+        #[derive(Clone, Default)]
+        pub struct FakeClock {
+            now: Rc<Cell<Duration>>,
+        }
+
+        impl FakeClock {
+            pub fn set(&self, now: Duration) {
+                self.now.set(now);
+            }
+        }
+
+        impl Clock for FakeClock {
+            fn now(&self) -> Duration {
+                self.now.get()
+            }
+        }
+
+        #[derive(Clone, Default)]
+        pub struct SharedLog {
+            pub written: Rc<RefCell<Vec<u8>>>,
+        }
+
+        impl Write for SharedLog {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.written.borrow_mut().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                std::io::Result::Ok(())
+            }
+        }
+    }
+
+    mod fixtures {
+        use super::*;
+
+        use maelstrom_rust::testkit::fixtures::{reply, request};
+        use std::{
+            collections::{HashMap, HashSet},
+            vec,
+        };
+
+        // what the runner hands the node out of the init it answers
+        pub fn init(processor: &mut BroadcastMaelstromNode) {
+            processor
+                .on_init("n1".into(), vec!["n1".into(), "n2".into()])
+                .unwrap();
+        }
+        pub fn broadcast_msg() -> Message<BroadcastMessage> {
+            Message {
+                src: Some("n2".into()),
+                dest: Some("n1".into()),
+                body: Body {
+                    msg_id: Some(1),
+                    in_reply_to: None,
+                    body: BroadcastMessage::Broadcast {
+                        message: 1,
+                        topic: None,
+                    },
+                },
+            }
+        }
+
+        pub fn broadcast_ok_msg() -> Message<BroadcastMessage> {
+            Message {
+                src: Some("n1".into()),
+                dest: Some("n2".into()),
+                body: Body {
+                    msg_id: None,
+                    in_reply_to: Some(1),
+                    body: BroadcastMessage::BroadcastOk {},
+                },
+            }
+        }
+
+        pub fn read_msg() -> Message<BroadcastMessage> {
+            request(BroadcastMessage::Read {
+                topic: None,
+                cursor: None,
+                limit: None,
+            })
+        }
+        pub fn paginated_read_msg(cursor: Option<i64>, limit: usize) -> Message<BroadcastMessage> {
+            request(BroadcastMessage::Read {
+                topic: None,
+                cursor,
+                limit: Some(limit),
+            })
+        }
+        pub fn read_ok_msg(messages: HashSet<i64>) -> Message<BroadcastMessage> {
+            reply(BroadcastMessage::ReadOk {
+                messages,
+                next_cursor: None,
+                stale: false,
+            })
+        }
+        pub fn topology_msg(
+            maybe_provided_topo: Option<HashMap<String, HashSet<String>>>,
+        ) -> Message<BroadcastMessage> {
+            let topology = maybe_provided_topo.unwrap_or(hashmap! {
+                "n1".to_string() => HashSet::from_iter(vec!["n2".into(), "n3".into()]),
+                "n2".to_string() => HashSet::from_iter(vec!["n3".into()]),
+                "n3".to_string() => HashSet::from_iter(vec!["n2".into()])
+            });
+            request(BroadcastMessage::Topology { topology })
+        }
+        pub fn topology_ok_msg() -> Message<BroadcastMessage> {
+            reply(BroadcastMessage::TopologyOk {})
+        }
+    }
+    #[test]
+    fn test_on_init() {
+        let mut processor = BroadcastMaelstromNode::default();
+        fixtures::init(&mut processor);
+        assert_eq!(processor.node_id, Some("n1".into()));
+        assert_eq!(processor.node_ids, BTreeSet::from_iter(vec!["n2".into()]));
+    }
+
+    #[test]
+    fn test_msg_processing_init_logs_diagnostics() {
+        use stubs::SharedLog;
+
+        let log = SharedLog::default();
+        let mut processor = BroadcastMaelstromNode::default().with_log(Box::new(log.clone()));
+        fixtures::init(&mut processor);
+
+        let logged: serde_json::Value = from_str(
+            String::from_utf8(log.written.borrow().clone())
+                .unwrap()
+                .trim(),
+        )
+        .unwrap();
+        assert_eq!(
+            logged,
+            json!({"init": {"node_id": "n1", "peer_count": 1, "topology_received": false}})
+        );
+    }
+
+    #[test]
+    fn test_msg_processing_broadcast_no_other_nodes() {
+        let mut processor = BroadcastMaelstromNode::default();
+        let msg = fixtures::broadcast_msg();
+
+        let reply = processor.process(msg);
+        assert_eq!(reply.unwrap(), Some(vec![fixtures::broadcast_ok_msg()]));
+        assert_eq!(processor.messages, HashSet::from_iter(vec![1]));
+        assert_eq!(
+            processor.messages_shared_per_node,
+            hashmap! {"n2".to_string() => HashSet::from_iter(vec![1])}
+        );
+    }
+    #[test]
+    fn test_msg_processing_broadcast_with_multi_broadcast_to_neighbors_ignoring_sender() {
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::from_iter(vec![]),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n2".into(), "n3".into()]),
+        );
+        let msg = fixtures::broadcast_msg();
+
+        let reply = processor.process(msg.clone());
+        assert_eq!(
+            reply.unwrap(),
+            Some(vec![
+                fixtures::broadcast_ok_msg(),
+                // gossip is numbered by the node so that acks can be matched to it, rather than
+                // reusing the client's id
+                Message {
+                    src: Some("n1".into()),
+                    dest: Some("n3".into()),
+                    body: Body {
+                        msg_id: Some(1),
+                        ..msg.body.clone()
+                    },
+                },
+            ])
+        );
+        assert_eq!(processor.messages, HashSet::from_iter(vec![1]));
+        assert_eq!(
+            processor.messages_shared_per_node,
+            hashmap! {"n2".into() => HashSet::from_iter(vec![1])}
+        );
+    }
+    #[test]
+    fn test_logs_for_one_message_share_its_correlation_id() {
+        use stubs::SharedLog;
+
+        let log = SharedLog::default();
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n2".into(), "n3".into()]),
+        )
+        .with_log(Box::new(log.clone()))
+        .with_debug_log(true);
+        let broadcast = |src: &str, msg_id: i64, message: i64| Message {
+            src: Some(src.into()),
+            dest: Some("n1".into()),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                body: BroadcastMessage::Broadcast {
+                    message,
+                    topic: None,
+                },
+            },
+        };
+        processor.process(broadcast("c1", 7, 1)).unwrap();
+        processor.process(broadcast("n2", 4, 2)).unwrap();
+
+        let logged: Vec<serde_json::Value> = String::from_utf8(log.written.borrow().clone())
+            .unwrap()
+            .lines()
+            .map(|line| from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            logged,
+            vec![
+                json!({"new_value": 1, "correlation_id": "c1:7"}),
+                json!({"sent": {"dest": "n2", "type": "broadcast"}, "correlation_id": "c1:7"}),
+                json!({"sent": {"dest": "n3", "type": "broadcast"}, "correlation_id": "c1:7"}),
+                json!({"new_value": 2, "correlation_id": "n2:4"}),
+                json!({"sent": {"dest": "n2", "type": "broadcast_ok"}, "correlation_id": "n2:4"}),
+                json!({"sent": {"dest": "n3", "type": "broadcast"}, "correlation_id": "n2:4"}),
+                // n2 never acked 1, which it gets as a catch-up
+                json!({"sent": {"dest": "n2", "type": "broadcast"}, "correlation_id": "n2:4"}),
+            ]
+        );
+
+        // values stored and messages sent are only logged for debugging
+        let quiet_log = SharedLog::default();
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n2".into(), "n3".into()]),
+        )
+        .with_log(Box::new(quiet_log.clone()));
+        processor.process(broadcast("c1", 7, 1)).unwrap();
+        assert!(quiet_log.written.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_type_names_match_the_serialized_types() {
+        let messages = [
+            BroadcastMessage::Broadcast {
+                message: 1,
+                topic: None,
+            },
+            BroadcastMessage::BroadcastOkBatch { msg_ids: vec![1] },
+            BroadcastMessage::ReadOk {
+                messages: HashSet::new(),
+                next_cursor: None,
+                stale: false,
+            },
+            BroadcastMessage::OutboundPendingOk { pending: 0 },
+            BroadcastMessage::SyncDelta {
+                buckets: vec![],
+                messages: HashSet::new(),
+            },
+        ];
+        for message in messages {
+            assert_eq!(
+                serde_json::to_value(&message).unwrap()["type"],
+                message.type_name()
+            );
+        }
+    }
+
+    #[test]
+    fn test_msg_processing_broadcast_acks_client_but_not_gossip() {
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::new(),
+            HashSet::new(),
+        )
+        .with_ack_gossip(false);
+
+        let client_broadcast = Message {
+            src: Some("c1".into()),
+            dest: Some("n1".into()),
+            body: Body {
+                msg_id: Some(1),
+                in_reply_to: None,
+                body: BroadcastMessage::Broadcast {
+                    message: 1,
+                    topic: None,
+                },
+            },
+        };
+        let reply = processor.process(client_broadcast);
+        assert_eq!(
+            reply.unwrap(),
+            Some(vec![Message {
+                src: Some("n1".into()),
+                dest: Some("c1".into()),
+                body: Body {
+                    msg_id: None,
+                    in_reply_to: Some(1),
+                    body: BroadcastMessage::BroadcastOk {},
+                },
+            }])
+        );
+
+        processor.node_ids.insert("n2".into());
+        let gossip = Message {
+            src: Some("n2".into()),
+            dest: Some("n1".into()),
+            body: Body {
+                msg_id: Some(1),
+                in_reply_to: None,
+                body: BroadcastMessage::Broadcast {
+                    message: 2,
+                    topic: None,
+                },
+            },
+        };
+        let reply = processor.process(gossip);
+        // no ack back to n2, only the catch-up of the value it hasn't seen yet
+        assert_eq!(
+            reply.unwrap(),
+            Some(vec![Message {
+                src: Some("n1".into()),
+                dest: Some("n2".into()),
+                body: Body {
+                    msg_id: Some(1),
+                    in_reply_to: None,
+                    body: BroadcastMessage::Broadcast {
+                        message: 1,
+                        topic: None,
+                    },
+                },
+            }])
+        );
+        assert_eq!(processor.messages, HashSet::from_iter(vec![1, 2]));
+    }
+
+    #[test]
+    fn test_msg_processing_topology() {
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n1".into()]),
+        );
+        let msg = fixtures::topology_msg(None);
+
+        let reply = processor.process(msg);
+        assert_eq!(reply.unwrap(), Some(vec![fixtures::topology_ok_msg()]));
+        assert_eq!(processor.node_id, Some("n1".into()));
+        assert_eq!(
+            processor.node_ids,
+            BTreeSet::from_iter(vec!["n2".into(), "n3".into()])
+        );
+    }
+
+    #[test]
+    fn test_msg_processing_topology_without_node_intialized_before() {
+        let mut processor = BroadcastMaelstromNode::default();
+        let msg = fixtures::topology_msg(None);
+
+        let reply = processor.process(msg);
+        assert_eq!(reply.unwrap(), Some(vec![fixtures::topology_ok_msg()]));
+        // we shouldn't update with values from the topology message if current node_id hasn't even been initialized
+        assert_eq!(processor.node_id, None);
+        let expected_node_ids: BTreeSet<String> = BTreeSet::new();
+        assert_eq!(processor.node_ids, expected_node_ids);
+    }
+
+    #[test]
+    fn test_msg_processing_topology_without_current_nodeid_mapped() {
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n1".into()]),
+        );
+        let msg = fixtures::topology_msg(Some(hashmap! {
+            "n2".into() => HashSet::from_iter(vec!["n3".into()]),
+            "n3".into() => HashSet::from_iter(vec!["n2".into()])
+        }));
+
+        let reply = processor.process(msg);
+        assert_eq!(reply.unwrap(), Some(vec![fixtures::topology_ok_msg()]));
+        // we shouldn't update with values from the topology message if current node_id is not mapped
+        assert_eq!(processor.node_id, Some("n1".into()));
+        assert_eq!(processor.node_ids, BTreeSet::from_iter(vec!["n1".into(),]));
+    }
+
+    #[test]
+    fn test_msg_processing_read() {
+        let stored_messages = HashSet::from_iter(vec![1, 2]);
+        let topology = HashSet::from_iter(vec!["2".to_string()]);
+
+        let mut processor = BroadcastMaelstromNode::new(
+            None,
+            stored_messages.clone(),
+            HashMap::new(),
+            topology.clone(),
+        );
+        let msg = fixtures::read_msg();
+
+        let reply = processor.process(msg);
+        assert_eq!(
+            reply.unwrap(),
+            Some(vec![fixtures::read_ok_msg(stored_messages.clone())])
+        );
+    }
+    #[test]
+    fn test_outbound_pending() {
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::from_iter(vec![1, 2]),
+            hashmap! {"n2".into() => HashSet::new()},
+            HashSet::from_iter(vec!["n2".into()]),
+        );
+        assert_eq!(processor.outbound_pending(), 2);
+
+        let msg = Message {
+            src: Some("c1".into()),
+            dest: Some("n1".into()),
+            body: Body {
+                msg_id: Some(1),
+                in_reply_to: None,
+                body: BroadcastMessage::OutboundPending {},
+            },
+        };
+        let reply = processor.process(msg);
+        assert_eq!(
+            reply.unwrap(),
+            Some(vec![Message {
+                src: Some("n1".into()),
+                dest: Some("c1".into()),
+                body: Body {
+                    msg_id: None,
+                    in_reply_to: Some(1),
+                    body: BroadcastMessage::OutboundPendingOk { pending: 2 },
+                },
+            }])
+        );
+    }
+
+    #[test]
+    fn test_msg_processing_sync_digest_exchanges_only_the_difference() {
+        // both have 0 to 199, n1 also 1000 and n2 also 2000
+        let mut node1 = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            (0..200).chain([1000]).collect(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n2".into()]),
+        );
+        let mut node2 = BroadcastMaelstromNode::new(
+            Some("n2".into()),
+            (0..200).chain([2000]).collect(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n1".into()]),
+        );
+
+        let digest = Message {
+            src: Some("n1".into()),
+            dest: Some("n2".into()),
+            body: Body {
+                msg_id: Some(1),
+                in_reply_to: None,
+                body: node1.sync_digest(),
+            },
+        };
+        // a checksum per bucket rather than the set
+        let BroadcastMessage::SyncDigest { count, buckets } = &digest.body.body else {
+            panic!("Expected a digest");
+        };
+        assert_eq!((*count, buckets.len()), (201, 32));
+
+        // n2 sends what it has of the buckets of 1000 and 2000 only, 2000 among them
+        let delta = node2.process(digest).unwrap().unwrap();
+        assert_eq!(delta.len(), 1);
+        let BroadcastMessage::SyncDelta { buckets, messages } = &delta[0].body.body else {
+            panic!("Expected a delta");
+        };
+        assert!(buckets.len() <= 2);
+        assert!(messages.contains(&2000));
+        assert!(!messages.contains(&1000));
+        assert!(messages.len() < 50);
+        assert_eq!(delta[0].body.in_reply_to, Some(1));
+
+        // n1 sends back only what n2 is missing of those buckets
+        let mut delta = delta[0].clone();
+        delta.body.msg_id = Some(2);
+        let missing = node1.process(delta).unwrap().unwrap();
+        assert_eq!(
+            missing,
+            vec![Message {
+                src: Some("n1".into()),
+                dest: Some("n2".into()),
+                body: Body {
+                    msg_id: None,
+                    in_reply_to: Some(2),
+                    body: BroadcastMessage::SyncDelta {
+                        buckets: vec![],
+                        messages: HashSet::from_iter(vec![1000]),
+                    },
+                },
+            }]
+        );
+        assert_eq!(node2.process(missing[0].clone()).unwrap(), None);
+        assert_eq!(node1.messages, node2.messages);
+        assert!(node1.messages.contains(&2000));
+    }
+
+    #[test]
+    fn test_sync_records_only_what_peers_sent() {
+        let mut node1 = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::from_iter(vec![1, 2, 3]),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n2".into()]),
+        );
+        let mut node2 = BroadcastMaelstromNode::new(
+            Some("n2".into()),
+            HashSet::from_iter(vec![4]),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n1".into()]),
+        );
+        let digest = Message {
+            src: Some("n1".into()),
+            dest: Some("n2".into()),
+            body: Body {
+                msg_id: Some(1),
+                in_reply_to: None,
+                body: node1.sync_digest(),
+            },
+        };
+        // answering a digest proves nothing about what the peer will get
+        let delta = node2.process(digest).unwrap().unwrap();
+        assert!(node2.messages_shared_per_node.is_empty());
+
+        // n1 takes n2 to have what it sent only, not what n1 sends back which may be lost
+        let missing = node1.process(delta[0].clone()).unwrap().unwrap();
+        assert_eq!(
+            node1.messages_shared_per_node,
+            hashmap! {"n2".to_string() => HashSet::from_iter(vec![4])}
+        );
+        assert_eq!(node1.unsent_for_peer("n2"), vec![1, 2, 3]);
+
+        node2.process(missing[0].clone()).unwrap();
+        assert_eq!(
+            node2.messages_shared_per_node,
+            hashmap! {"n1".to_string() => HashSet::from_iter(vec![1, 2, 3])}
+        );
+    }
+
+    fn cluster(
+        topology: HashMap<String, HashSet<String>>,
+    ) -> Cluster<BroadcastMessage, BroadcastMaelstromNode> {
+        Cluster::new(topology.into_iter().map(|(node_id, neighbors)| {
+            let node = BroadcastMaelstromNode::new(
+                Some(node_id.clone()),
+                HashSet::new(),
+                HashMap::new(),
+                neighbors,
+            );
+            (node_id, node)
+        }))
+    }
+
+    fn client_broadcast(dest: &str, message: i64) -> Message<BroadcastMessage> {
+        Message {
+            src: Some("c1".into()),
+            dest: Some(dest.into()),
+            body: Body {
+                msg_id: Some(1),
+                in_reply_to: None,
+                body: BroadcastMessage::Broadcast {
+                    message,
+                    topic: None,
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn test_cluster_converges() {
+        let mut cluster = cluster(hashmap! {
+            "n1".into() => HashSet::from_iter(vec!["n2".into(), "n3".into()]),
+            "n2".into() => HashSet::from_iter(vec!["n1".into(), "n3".into()]),
+            "n3".into() => HashSet::from_iter(vec!["n1".into(), "n2".into()]),
+        });
+        cluster.send(client_broadcast("n1", 1));
+        cluster.send(client_broadcast("n2", 2));
+
+        cluster.assert_converges_within(3);
+        assert_eq!(
+            cluster.node("n3").unwrap().messages,
+            HashSet::from_iter(vec![1, 2])
+        );
+    }
+
+    #[test]
+    fn test_cluster_node_state_after_one_tick() {
+        let mut cluster = cluster(hashmap! {
+            "n1".into() => HashSet::from_iter(vec!["n2".into()]),
+            "n2".into() => HashSet::from_iter(vec!["n1".into()]),
+        });
+        cluster.send(client_broadcast("n1", 1));
+
+        cluster.tick();
+
+        let n1 = cluster.node_state("n1").unwrap();
+        assert_eq!(n1["state"]["messages"], serde_json::json!([1]));
+        assert_eq!(
+            n1["dump"]["peers"]["n2"],
+            serde_json::json!({"sent": 1, "received": 0})
+        );
+        // the gossip is still in flight
+        let n2 = cluster.node_state("n2").unwrap();
+        assert_eq!(n2["state"]["messages"], serde_json::json!([]));
+        assert!(cluster.node_state("n3").is_none());
+    }
+
+    #[test]
+    fn test_cluster_clock_skew_reorders_first_seen() {
+        let first_seen = |n2_offset_ms| {
+            let mut cluster = Cluster::with_clock_offsets(
+                vec![("n1".to_string(), 0), ("n2".to_string(), n2_offset_ms)],
+                |node_id, clock| {
+                    let neighbor = if node_id == "n1" { "n2" } else { "n1" };
+                    BroadcastMaelstromNode::new(
+                        Some(node_id.into()),
+                        HashSet::new(),
+                        HashMap::new(),
+                        HashSet::from_iter(vec![neighbor.into()]),
+                    )
+                    .with_clock(clock)
+                },
+            );
+            cluster.set_time(Duration::from_millis(1000));
+            cluster.send(client_broadcast("n1", 1));
+            cluster.tick();
+            cluster.set_time(Duration::from_millis(1010));
+            cluster.tick();
+            let first_seen_by = |node_id| cluster.node(node_id).unwrap().first_seen[&1];
+            (first_seen_by("n1"), first_seen_by("n2"))
+        };
+
+        // n2 got the message 10ms after n1 did
+        let (n1, n2) = first_seen(0);
+        assert_eq!(n2 - n1, Duration::from_millis(10));
+        // but with its clock 50ms behind, n2 would claim to have seen it first
+        let (n1, n2) = first_seen(-50);
+        assert!(n2 < n1);
+        let (n1, n2) = first_seen(50);
+        assert_eq!(n2 - n1, Duration::from_millis(60));
+    }
+
+    #[test]
+    fn test_cluster_does_not_converge_when_disconnected() {
+        let mut cluster = cluster(hashmap! {
+            "n1".into() => HashSet::from_iter(vec!["n2".into()]),
+            "n2".into() => HashSet::from_iter(vec!["n1".into()]),
+            "n3".into() => HashSet::new(),
+        });
+        cluster.send(client_broadcast("n1", 1));
+
+        assert!(!cluster.converges_within(10));
+    }
+
+    fn full_mesh(node_ids: &[&str]) -> HashMap<String, HashSet<String>> {
+        node_ids
+            .iter()
+            .map(|node| {
+                let neighbors = node_ids
+                    .iter()
+                    .filter(|neighbor| neighbor != &node)
+                    .map(|neighbor| neighbor.to_string())
+                    .collect();
+                (node.to_string(), neighbors)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_spanning_tree() {
+        let tree = spanning_tree(&full_mesh(&["n1", "n2", "n3", "n4"]));
+
+        let edges: usize = tree
+            .values()
+            .map(|neighbors| neighbors.len())
+            .sum::<usize>()
+            / 2;
+        assert_eq!(edges, 3);
+        assert_eq!(tree.len(), 4);
+        assert_eq!(
+            tree["n1"],
+            HashSet::from_iter(vec!["n2".into(), "n3".into(), "n4".into()])
+        );
+    }
+
+    #[test]
+    fn test_tree_info() {
+        // a line, n1 - n2 - n3 - n4, is its own spanning tree
+        let topology = hashmap! {
+            "n1".to_string() => HashSet::from_iter(vec!["n2".into()]),
+            "n2".to_string() => HashSet::from_iter(vec!["n1".into(), "n3".into()]),
+            "n3".to_string() => HashSet::from_iter(vec!["n2".into(), "n4".into()]),
+            "n4".to_string() => HashSet::from_iter(vec!["n3".into()]),
+        };
+        let tree_info = |node_id: &str, spanning_tree: bool| {
+            let mut processor = BroadcastMaelstromNode::new(
+                Some(node_id.into()),
+                HashSet::new(),
+                HashMap::new(),
+                HashSet::new(),
+            )
+            .with_spanning_tree(spanning_tree);
+            processor
+                .process(fixtures::topology_msg(Some(topology.clone())))
+                .unwrap();
+            processor
+                .process(maelstrom_rust::testkit::fixtures::request(
+                    BroadcastMessage::TreeInfo {},
+                ))
+                .unwrap()
+                .unwrap()
+                .remove(0)
+                .body
+                .body
+        };
+        let info = |root: Option<&str>, parent: Option<&str>, children: Vec<&str>| {
+            BroadcastMessage::TreeInfoOk {
+                root: root.map(String::from),
+                parent: parent.map(String::from),
+                children: children.into_iter().map(String::from).collect(),
+            }
+        };
+
+        assert_eq!(tree_info("n1", true), info(Some("n1"), None, vec!["n2"]));
+        assert_eq!(
+            tree_info("n3", true),
+            info(Some("n1"), Some("n2"), vec!["n4"])
+        );
+        assert_eq!(tree_info("n4", true), info(Some("n1"), Some("n3"), vec![]));
+        assert_eq!(tree_info("n3", false), info(None, None, vec![]));
+    }
+
+    #[test]
+    fn test_who_has_reflects_per_node_seen_sets() {
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::from_iter(vec![1, 2, 3]),
+            hashmap! {
+                "n2".to_string() => HashSet::from_iter(vec![1, 2]),
+                "n3".to_string() => HashSet::from_iter(vec![2]),
+                // heard from without being a neighbor
+                "n4".to_string() => HashSet::from_iter(vec![1]),
+            },
+            HashSet::from_iter(vec!["n2".into(), "n3".into()]),
+        );
+        let mut who_has = |message| {
+            processor
+                .process(maelstrom_rust::testkit::fixtures::request(
+                    BroadcastMessage::WhoHas { message },
+                ))
+                .unwrap()
+                .unwrap()
+                .remove(0)
+                .body
+                .body
+        };
+        let who_has_ok = |message, peers: Vec<&str>| BroadcastMessage::WhoHasOk {
+            message,
+            peers: peers.into_iter().map(String::from).collect(),
+        };
+
+        assert_eq!(who_has(1), who_has_ok(1, vec!["n2", "n4"]));
+        assert_eq!(who_has(2), who_has_ok(2, vec!["n2", "n3"]));
+        assert_eq!(who_has(3), who_has_ok(3, vec![]));
+    }
+
+    #[test]
+    fn test_spanning_tree_root_is_elected_by_node_index() {
+        let topology = hashmap! {
+            "n10".to_string() => HashSet::from_iter(vec!["n2".into(), "n9".into()]),
+            "n2".to_string() => HashSet::from_iter(vec!["n10".into(), "n9".into()]),
+            "n9".to_string() => HashSet::from_iter(vec!["n10".into(), "n2".into()]),
+        };
+
+        // "n10" would come first as a string
+        assert_eq!(
+            spanning_tree(&topology)["n2"],
+            HashSet::from_iter(vec!["n9".into(), "n10".into()])
+        );
+    }
+
+    #[test]
+    fn test_cluster_broadcast_along_spanning_tree() {
+        let node_ids = ["n1", "n2", "n3", "n4", "n5"];
+        let mut cluster = Cluster::new(node_ids.iter().map(|node_id| {
+            let node = BroadcastMaelstromNode::new(
+                Some(node_id.to_string()),
+                HashSet::new(),
+                HashMap::new(),
+                HashSet::new(),
+            )
+            .with_spanning_tree(true);
+            (node_id.to_string(), node)
+        }));
+        for node_id in node_ids {
+            let mut topology = fixtures::topology_msg(Some(full_mesh(&node_ids)));
+            topology.dest = Some(node_id.into());
+            cluster.send(topology);
+        }
+        cluster.tick();
+        cluster.send(client_broadcast("n3", 1));
+
+        let mut gossip_sent = 0;
+        while cluster.in_flight().next().is_some() {
+            gossip_sent += cluster
+                .in_flight()
+                .filter(|msg| {
+                    msg.src
+                        .as_ref()
+                        .is_some_and(|src| maelstrom_rust::node_id::is_node(src))
+                        && matches!(msg.body.body, BroadcastMessage::Broadcast { .. })
+                })
+                .count();
+            cluster.tick();
+        }
+
+        assert_eq!(gossip_sent, node_ids.len() - 1);
+        for node_id in node_ids {
+            assert_eq!(
+                cluster.node(node_id).unwrap().messages,
+                HashSet::from_iter(vec![1])
+            );
+        }
+    }
+
+    #[test]
+    fn test_msg_processing_broadcast_with_reordered_msg_ids() {
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n2".into()]),
+        );
+
+        for msg_id in [3, 1, 2] {
+            let mut msg = fixtures::broadcast_msg();
+            msg.body.msg_id = Some(msg_id);
+            msg.body.body = BroadcastMessage::Broadcast {
+                message: msg_id,
+                topic: None,
+            };
+
+            let replies = processor.process(msg).unwrap().unwrap();
+            assert!(replies.contains(&Message {
+                src: Some("n1".into()),
+                dest: Some("n2".into()),
+                body: Body {
+                    msg_id: replies[0].body.msg_id,
+                    in_reply_to: Some(msg_id),
+                    body: BroadcastMessage::BroadcastOk {},
+                },
+            }));
+        }
+        assert_eq!(processor.messages, HashSet::from_iter(vec![1, 2, 3]));
+        assert_eq!(
+            processor.messages_shared_per_node,
+            hashmap! {"n2".into() => HashSet::from_iter(vec![1, 2, 3])}
+        );
+    }
+
+    #[test]
+    fn test_read_is_marked_stale_after_a_partition() {
+        use stubs::FakeClock;
+
+        let clock = FakeClock::default();
+        let mut processor = BroadcastMaelstromNode::default()
+            .with_clock(Box::new(clock.clone()))
+            .with_stale_after(Some(Duration::from_millis(100)));
+        processor
+            .on_init("n1".into(), vec!["n1".into(), "n2".into()])
+            .unwrap();
+        let is_stale = |processor: &mut BroadcastMaelstromNode| match &processor
+            .process(fixtures::read_msg())
+            .unwrap()
+            .unwrap()[0]
+            .body
+            .body
+        {
+            BroadcastMessage::ReadOk { stale, .. } => *stale,
+            other => panic!("Expected read_ok: {:?}", other),
+        };
+
+        clock.set(Duration::from_millis(50));
+        let mut gossip = client_broadcast("n1", 1);
+        gossip.src = Some("n2".into());
+        processor.process(gossip).unwrap();
+        clock.set(Duration::from_millis(120));
+        assert!(!is_stale(&mut processor));
+
+        // n2 is partitioned away, only clients keep talking to n1
+        clock.set(Duration::from_millis(200));
+        processor.process(client_broadcast("n1", 2)).unwrap();
+        assert!(is_stale(&mut processor));
+    }
+
+    #[test]
+    fn test_convergence_time() {
+        use stubs::FakeClock;
+
+        let clock = FakeClock::default();
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n2".into(), "n3".into()]),
+        )
+        .with_clock(Box::new(clock.clone()));
+
+        clock.set(Duration::from_millis(5));
+        let gossip: HashMap<String, i64> = processor
+            .process(client_broadcast("n1", 1))
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .filter(|msg| msg.body.in_reply_to.is_none())
+            .map(|msg| (msg.dest.unwrap(), msg.body.msg_id.unwrap()))
+            .collect();
+        assert_eq!(processor.first_seen[&1], Duration::from_millis(5));
+
+        // n2 acks the gossip alone, n3 in a batch
+        let ack = |src: &str, body| Message {
+            src: Some(src.into()),
+            dest: Some("n1".into()),
+            body: Body {
+                msg_id: Some(1),
+                in_reply_to: None,
+                body,
+            },
+        };
+        clock.set(Duration::from_millis(7));
+        let mut ok = ack("n2", BroadcastMessage::BroadcastOk {});
+        ok.body.in_reply_to = Some(gossip["n2"]);
+        processor.process(ok).unwrap();
+        assert_eq!(processor.dump().unwrap()["convergence_ms"]["count"], 0);
+
+        clock.set(Duration::from_millis(12));
+        let msg_ids = vec![gossip["n3"]];
+        let batch = ack("n3", BroadcastMessage::BroadcastOkBatch { msg_ids });
+        processor.process(batch).unwrap();
+        assert_eq!(processor.first_seen[&1], Duration::from_millis(5));
+        assert_eq!(
+            processor.dump().unwrap()["convergence_ms"],
+            serde_json::json!({"count": 1, "min": 7, "median": 7, "max": 7})
+        );
+    }
+
+    #[test]
+    fn test_gossip_and_catch_ups_have_distinct_msg_ids() {
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::from_iter(vec![1, 2]),
+            hashmap! {
+                "n2".to_string() => HashSet::new(),
+                "n3".to_string() => HashSet::new(),
+            },
+            HashSet::from_iter(vec!["n2".into(), "n3".into()]),
+        );
+        let mut msg_ids = MsgIdTracker::new(MessageIdAllocator::default(), true);
+        let replies = processor.process(client_broadcast("n1", 3)).unwrap();
+        assert_eq!(replies.as_ref().map(Vec::len), Some(1 + 2 + 4));
+        assert!(msg_ids.track(&replies).is_empty());
+
+        // as used to be the case, gossip reusing the msg_id of the client for every peer
+        let reused: Vec<Message<BroadcastMessage>> = ["n2", "n3"]
+            .iter()
+            .map(|dest| {
+                let mut gossip = client_broadcast("n1", 4);
+                gossip.src = Some("n1".into());
+                gossip.dest = Some(dest.to_string());
+                gossip
+            })
+            .collect();
+        assert_eq!(
+            MsgIdTracker::new(MessageIdAllocator::default(), true).track(&Some(reused)),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn test_per_peer_message_counters() {
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n2".into(), "n3".into()]),
+        );
+        processor.process(client_broadcast("n1", 1)).unwrap();
+        processor.process(client_broadcast("n1", 2)).unwrap();
+        let mut gossip = client_broadcast("n1", 3);
+        gossip.src = Some("n2".into());
+        processor.process(gossip).unwrap();
+
+        // n2 gets both client values, the ack of its own and, now known to lag, a catch-up
+        // of the client values; n3 gets all three values
+        assert_eq!(
+            processor.dump().unwrap()["peers"],
+            json!({
+                "n2": {"sent": 5, "received": 1},
+                "n3": {"sent": 3, "received": 0},
+            })
+        );
+    }
+
+    #[test]
+    fn test_msg_processing_broadcast_topics_are_kept_apart() {
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n2".into()]),
+        );
+        let broadcast = |topic: Option<&str>, message| {
+            let mut msg = client_broadcast("n1", message);
+            msg.body.body = BroadcastMessage::Broadcast {
+                message,
+                topic: topic.map(String::from),
+            };
+            msg
+        };
+        fn read(processor: &mut BroadcastMaelstromNode, topic: Option<&str>) -> HashSet<i64> {
+            let mut msg = fixtures::read_msg();
+            msg.body.body = BroadcastMessage::Read {
+                topic: topic.map(String::from),
+                cursor: None,
+                limit: None,
+            };
+            let replies = processor.process(msg).unwrap().unwrap();
+            match &replies[0].body.body {
+                BroadcastMessage::ReadOk { messages, .. } => messages.clone(),
+                other => panic!("Expected read_ok: {:?}", other),
+            }
+        }
+
+        let replies = processor.process(broadcast(Some("a"), 1)).unwrap().unwrap();
+        assert_eq!(
+            replies[1],
+            Message {
+                src: Some("n1".into()),
+                dest: Some("n2".into()),
+                body: Body {
+                    msg_id: None,
+                    in_reply_to: None,
+                    body: BroadcastMessage::Broadcast {
+                        message: 1,
+                        topic: Some("a".into()),
+                    },
+                },
+            }
+        );
+        processor.process(broadcast(Some("b"), 2)).unwrap();
+        processor.process(broadcast(None, 3)).unwrap();
+
+        assert_eq!(read(&mut processor, Some("a")), HashSet::from_iter(vec![1]));
+        assert_eq!(read(&mut processor, Some("b")), HashSet::from_iter(vec![2]));
+        assert_eq!(read(&mut processor, None), HashSet::from_iter(vec![3]));
+        assert_eq!(read(&mut processor, Some("c")), HashSet::new());
+    }
+
+    #[test]
+    fn test_msg_processing_topology_change_between_broadcasts() {
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n2".into(), "n3".into()]),
+        );
+        let gossip_dests = |replies: Vec<Message<BroadcastMessage>>| {
+            replies
+                .into_iter()
+                .filter_map(|msg| match msg.body.body {
+                    BroadcastMessage::Broadcast { message, .. } => Some((msg.dest?, message)),
+                    _ => None,
+                })
+                .collect::<HashSet<(String, i64)>>()
+        };
+        let broadcast = |message| {
+            let mut msg = fixtures::broadcast_msg();
+            msg.body.body = BroadcastMessage::Broadcast {
+                message,
+                topic: None,
+            };
+            msg
+        };
+
+        let replies = processor.process(broadcast(1)).unwrap().unwrap();
+        assert_eq!(
+            gossip_dests(replies),
+            HashSet::from_iter(vec![("n3".to_string(), 1)])
+        );
+
+        processor
+            .process(fixtures::topology_msg(Some(hashmap! {
+                "n1".into() => HashSet::from_iter(vec!["n2".into(), "n4".into()]),
+            })))
+            .unwrap();
+
+        // node3 isn't a neighbor anymore, node4 gets the new message but no catch-up
+        // as nothing is known about what it has seen yet
+        let replies = processor.process(broadcast(2)).unwrap().unwrap();
+        assert_eq!(
+            gossip_dests(replies),
+            HashSet::from_iter(vec![("n4".to_string(), 2)])
+        );
+    }
+
+    #[test]
+    fn test_send_rate_decreases_on_ack_failures() {
+        use stubs::FakeClock;
+
+        let clock = FakeClock::default();
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n2".into(), "n3".into()]),
+        )
+        .with_clock(Box::new(clock.clone()))
+        .with_max_send_rate(Some(4))
+        .with_ack_timeout(Duration::from_millis(100));
+        let ack = |src: &str, in_reply_to| Message {
+            src: Some(src.into()),
+            dest: Some("n1".into()),
+            body: Body {
+                msg_id: Some(1),
+                in_reply_to: Some(in_reply_to),
+                body: BroadcastMessage::BroadcastOk {},
+            },
+        };
+        // n2 acks every gossip, n3 none
+        let mut broadcast_at = |now, message| {
+            clock.set(Duration::from_millis(now));
+            let replies = processor
+                .process(client_broadcast("n1", message))
+                .unwrap()
+                .unwrap();
+            for reply in replies {
+                if reply.dest == Some("n2".into()) && reply.body.in_reply_to.is_none() {
+                    processor
+                        .process(ack("n2", reply.body.msg_id.unwrap()))
+                        .unwrap();
+                }
+            }
+            (processor.send_rate("n2"), processor.send_rate("n3"))
+        };
+
+        assert_eq!(broadcast_at(0, 1), (Some(4), Some(4)));
+        assert_eq!(broadcast_at(50, 2), (Some(4), Some(4)));
+        // 1 timed out, n3's rate is halved once whatever else it didn't ack
+        assert_eq!(broadcast_at(120, 3), (Some(4), Some(2)));
+        // 2 timed out too, but the rate was halved less than a timeout ago
+        assert_eq!(broadcast_at(160, 4), (Some(4), Some(2)));
+        assert_eq!(broadcast_at(230, 5), (Some(4), Some(1)));
+        assert_eq!(broadcast_at(400, 6), (Some(4), Some(1)));
+
+        // n3 finally acks, its rate grows back by one
+        let gossip_to_n3 = processor.in_flight["n3"][&6].0;
+        processor.process(ack("n3", gossip_to_n3)).unwrap();
+        assert_eq!(processor.send_rate("n3"), Some(2));
+    }
+
+    #[test]
+    fn test_send_rate_caps_catch_ups() {
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::from_iter(vec![1, 2, 3]),
+            // n2 is caught up on whatever it wasn't seen with on every broadcast
+            HashMap::from([("n2".into(), HashSet::new())]),
+            HashSet::from_iter(vec!["n2".into()]),
+        )
+        .with_max_send_rate(Some(4));
+        processor.send_rates.insert("n2".into(), 1);
+
+        let replies = processor
+            .process(client_broadcast("n1", 4))
+            .unwrap()
+            .unwrap();
+        let to_n2 = replies
+            .iter()
+            .filter(|m| m.dest == Some("n2".into()))
+            .count();
+        // the gossip of 4, and a single catch-up
+        assert_eq!(to_n2, 1 + 1);
+    }
+
+    #[test]
+    fn test_peers_are_iterated_in_sorted_order() {
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n4".into(), "n2".into(), "n5".into(), "n3".into()]),
+        );
+        let replies = processor
+            .process(maelstrom_rust::testkit::fixtures::request(
+                BroadcastMessage::Broadcast {
+                    message: 1,
+                    topic: None,
+                },
+            ))
+            .unwrap()
+            .unwrap();
+
+        let gossip_dests: Vec<String> = replies
+            .iter()
+            .filter_map(|reply| reply.dest.clone())
+            .filter(|dest| maelstrom_rust::node_id::is_node(dest))
+            .collect();
+        assert_eq!(gossip_dests, vec!["n2", "n3", "n4", "n5"]);
+    }
+
+    #[test]
+    fn test_msg_processing_add_and_remove_peer() {
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n2".into()]),
+        );
+        let gossip_dests = |processor: &mut BroadcastMaelstromNode, message| {
+            processor
+                .process(client_broadcast("n1", message))
+                .unwrap()
+                .unwrap()
+                .into_iter()
+                .filter_map(|reply| reply.dest)
+                .filter(|dest| maelstrom_rust::node_id::is_node(dest))
+                .collect::<Vec<String>>()
+        };
+
+        let reply = processor
+            .process(maelstrom_rust::testkit::fixtures::request(
+                BroadcastMessage::AddPeer {
+                    node_id: "n3".into(),
+                },
+            ))
+            .unwrap();
+        assert_eq!(
+            reply,
+            Some(vec![maelstrom_rust::testkit::fixtures::reply(
+                BroadcastMessage::AddPeerOk {}
+            )])
+        );
+        assert_eq!(gossip_dests(&mut processor, 1), vec!["n2", "n3"]);
+
+        processor
+            .process(maelstrom_rust::testkit::fixtures::request(
+                BroadcastMessage::RemovePeer {
+                    node_id: "n2".into(),
+                },
+            ))
+            .unwrap();
+        assert_eq!(gossip_dests(&mut processor, 2), vec!["n3"]);
+    }
+
+    #[test]
+    fn test_heartbeats() {
+        use stubs::FakeClock;
+
+        let clock = FakeClock::default();
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n2".into(), "n3".into()]),
+        )
+        .with_clock(Box::new(clock.clone()))
+        .with_heartbeat_interval(Some(Duration::from_millis(100)));
+        let heartbeats_at = |processor: &mut BroadcastMaelstromNode, now| {
+            clock.set(Duration::from_millis(now));
+            processor
+                .process(fixtures::read_msg())
+                .unwrap()
+                .unwrap()
+                .into_iter()
+                .filter(|msg| msg.body.body == BroadcastMessage::Heartbeat {})
+                .filter_map(|msg| msg.dest)
+                .collect::<Vec<String>>()
+        };
+
+        assert!(heartbeats_at(&mut processor, 50).is_empty());
+        assert_eq!(heartbeats_at(&mut processor, 100), vec!["n2", "n3"]);
+        assert!(heartbeats_at(&mut processor, 150).is_empty());
+        assert_eq!(heartbeats_at(&mut processor, 230), vec!["n2", "n3"]);
+
+        clock.set(Duration::from_millis(260));
+        let reply = processor
+            .process(Message {
+                src: Some("n2".into()),
+                dest: Some("n1".into()),
+                body: Body {
+                    msg_id: Some(7),
+                    in_reply_to: None,
+                    body: BroadcastMessage::Heartbeat {},
+                },
+            })
+            .unwrap();
+        assert_eq!(reply, None);
+        assert_eq!(
+            processor.last_heard,
+            hashmap! {"n2".to_string() => Duration::from_millis(260)}
+        );
+    }
+
+    #[test]
+    fn test_nodes_with_the_same_ring_hasher_agree_on_owners() {
+        use maelstrom_rust::hashing::ring_hasher;
+
+        let cluster: Vec<String> = vec!["n1".into(), "n2".into(), "n3".into()];
+        for name in ["default", "fnv", "xxhash"] {
+            let node = |node_id: &str| {
+                let mut node = BroadcastMaelstromNode::default()
+                    .with_partitioned(true)
+                    .with_ring_hasher(ring_hasher(name, 42).unwrap());
+                node.on_init(node_id.into(), cluster.clone()).unwrap();
+                node
+            };
+            let (n1, n2) = (node("n1"), node("n2"));
+
+            for message in 0..30 {
+                assert!(n1.owner(message).is_some());
+                assert_eq!(n1.owner(message), n2.owner(message), "{name}: {message}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_partitioned_broadcast_is_forwarded_to_owner() {
+        let mut processor = BroadcastMaelstromNode::default().with_partitioned(true);
+        processor
+            .on_init("n1".into(), vec!["n1".into(), "n2".into(), "n3".into()])
+            .unwrap();
+
+        let mut owners = HashSet::new();
+        for message in 0..30 {
+            let owner = processor.owner(message).unwrap().clone();
+            let replies = processor
+                .process(maelstrom_rust::testkit::fixtures::request(
+                    BroadcastMessage::Broadcast {
+                        message,
+                        topic: None,
+                    },
+                ))
+                .unwrap()
+                .unwrap();
+            let gossip_dests: Vec<String> = replies
+                .into_iter()
+                .filter(|reply| matches!(reply.body.body, BroadcastMessage::Broadcast { .. }))
+                .filter_map(|reply| reply.dest)
+                .collect();
+            if owner == "n1" {
+                assert!(!gossip_dests.contains(&owner));
+            } else {
+                assert_eq!(gossip_dests, vec![owner.clone()]);
+            }
+            owners.insert(owner);
+        }
+        // every node owns a slice
+        assert_eq!(owners.len(), 3);
+        assert_eq!(processor.messages.len(), 30);
+    }
+
+    #[test]
+    fn test_gossip_acks_are_batched() {
+        use stubs::FakeClock;
+
+        let clock = FakeClock::default();
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n2".into()]),
+        )
+        .with_clock(Box::new(clock.clone()))
+        .with_ack_batch_interval(Some(Duration::from_millis(100)));
+        let gossip = |msg_id, message| Message {
+            src: Some("n2".into()),
+            dest: Some("n1".into()),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                body: BroadcastMessage::Broadcast {
+                    message,
+                    topic: None,
+                },
+            },
+        };
+        let acks = |replies: Option<Vec<Message<BroadcastMessage>>>| {
+            replies
+                .into_iter()
+                .flatten()
+                .filter(|reply| {
+                    matches!(
+                        reply.body.body,
+                        BroadcastMessage::BroadcastOk {}
+                            | BroadcastMessage::BroadcastOkBatch { .. }
+                    )
+                })
+                .map(|reply| (reply.dest.unwrap(), reply.body.body))
+                .collect::<Vec<(String, BroadcastMessage)>>()
+        };
+
+        clock.set(Duration::from_millis(10));
+        for msg_id in 1..=3 {
+            assert!(acks(processor.process(gossip(msg_id, msg_id * 10)).unwrap()).is_empty());
+        }
+        // clients are still acked one by one
+        let client_ack = acks(
+            processor
+                .process(maelstrom_rust::testkit::fixtures::request(
+                    BroadcastMessage::Broadcast {
+                        message: 40,
+                        topic: None,
+                    },
+                ))
+                .unwrap(),
+        );
+        assert_eq!(
+            client_ack,
+            vec![("src".into(), BroadcastMessage::BroadcastOk {})]
+        );
+
+        clock.set(Duration::from_millis(100));
+        assert_eq!(
+            acks(processor.process(fixtures::read_msg()).unwrap()),
+            vec![(
+                "n2".into(),
+                BroadcastMessage::BroadcastOkBatch {
+                    msg_ids: vec![1, 2, 3]
+                }
+            )]
+        );
+        clock.set(Duration::from_millis(200));
+        assert!(acks(processor.process(fixtures::read_msg()).unwrap()).is_empty());
+
+        let mut sender = BroadcastMaelstromNode::default().with_max_send_rate(Some(8));
+        sender.send_rates.insert("n2".into(), 1);
+        let mut batch = gossip(4, 0);
+        batch.body.body = BroadcastMessage::BroadcastOkBatch {
+            msg_ids: vec![1, 2, 3],
+        };
+        assert_eq!(sender.process(batch).unwrap(), None);
+        assert_eq!(sender.send_rate("n2"), Some(4));
+    }
+
+    #[test]
+    fn test_batched_acks_are_flushed_on_timer() {
+        use stubs::FakeClock;
+
+        assert!(BroadcastMaelstromNode::default().timers().is_empty());
+
+        let clock = FakeClock::default();
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n2".into()]),
+        )
+        .with_clock(Box::new(clock.clone()))
+        .with_ack_batch_interval(Some(Duration::from_millis(100)))
+        .with_compaction_interval(Some(Duration::from_millis(500)));
+        assert_eq!(
+            processor.timers(),
+            vec![("periodic", Duration::from_millis(100))]
+        );
+
+        clock.set(Duration::from_millis(10));
+        let gossip = Message {
+            src: Some("n2".into()),
+            dest: Some("n1".into()),
+            body: Body {
+                msg_id: Some(1),
+                in_reply_to: None,
+                body: BroadcastMessage::Broadcast {
+                    message: 10,
+                    topic: None,
+                },
+            },
+        };
+        processor.process(gossip).unwrap();
+        assert_eq!(processor.on_timer("periodic").unwrap(), None);
+
+        // no message came in since, the tick sends the batch on its own
+        clock.set(Duration::from_millis(100));
+        let flushed: Vec<(Option<String>, BroadcastMessage)> = processor
+            .on_timer("periodic")
+            .unwrap()
+            .into_iter()
+            .flatten()
+            .map(|msg| (msg.dest, msg.body.body))
+            .collect();
+        assert_eq!(
+            flushed,
+            vec![(
+                Some("n2".into()),
+                BroadcastMessage::BroadcastOkBatch { msg_ids: vec![1] }
+            )]
+        );
+    }
+
+    #[test]
+    fn test_messages_from_an_older_epoch_of_a_peer_are_ignored() {
+        use maelstrom_rust::epochs::{EpochTagging, Epoched};
+        use stubs::FakeClock;
+
+        let gossip = from_str::<Message<Epoched<BroadcastMessage>>>(
+            r#"{"src":"n2","dest":"n1","body":{"type":"broadcast","msg_id":1,"message":10,"epoch":5}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            gossip.body.body,
+            Epoched {
+                body: BroadcastMessage::Broadcast {
+                    message: 10,
+                    topic: None
+                },
+                epoch: Some(5),
+            }
+        );
+        let round_trip =
+            from_str::<Message<Epoched<BroadcastMessage>>>(&to_string(&gossip).unwrap()).unwrap();
+        assert_eq!(round_trip, gossip);
+
+        let clock = FakeClock::default();
+        clock.set(Duration::from_millis(1000));
+        let mut node = EpochTagging::new(BroadcastMaelstromNode::default())
+            .with_clock(Box::new(clock.clone()));
+        node.on_init("n1".into(), vec!["n1".into(), "n2".into()])
+            .unwrap();
+        assert_eq!(node.epoch(), 1000);
+        // a new init moves the epoch on even when the clock didn't
+        node.on_init("n1".into(), vec!["n1".into(), "n2".into()])
+            .unwrap();
+        assert_eq!(node.epoch(), 1001);
+
+        let acks = node.process(gossip.clone()).unwrap().unwrap();
+        assert_eq!(acks[0].dest, Some("n2".into()));
+        assert_eq!(acks[0].body.body.epoch, Some(1001));
+
+        // sent by n2 before it restarted into epoch 5
+        let mut stale = gossip.clone();
+        stale.body.body = Epoched {
+            body: BroadcastMessage::Broadcast {
+                message: 20,
+                topic: None,
+            },
+            epoch: Some(3),
+        };
+        assert_eq!(node.process(stale).unwrap(), None);
+
+        let read = node
+            .process(maelstrom_rust::testkit::fixtures::request(Epoched {
+                body: BroadcastMessage::Read {
+                    topic: None,
+                    cursor: None,
+                    limit: None,
+                },
+                epoch: None,
+            }))
+            .unwrap()
+            .unwrap();
+        // clients get no epoch
+        assert_eq!(read[0].body.body.epoch, None);
+        let BroadcastMessage::ReadOk { ref messages, .. } = read[0].body.body.body else {
+            panic!("Expected a read_ok, got {:?}", read[0]);
+        };
+        assert_eq!(messages, &HashSet::from_iter(vec![10]));
+    }
+
+    #[test]
+    fn test_in_flight_gossip_is_not_resent_before_ack_or_timeout() {
+        use stubs::FakeClock;
+
+        let clock = FakeClock::default();
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            // n2 is caught up on whatever it wasn't seen with on every broadcast
+            HashMap::from([("n2".into(), HashSet::new())]),
+            HashSet::from_iter(vec!["n2".into()]),
+        )
+        .with_clock(Box::new(clock.clone()))
+        .with_in_flight_window(Some(Duration::from_millis(100)));
+        let broadcast = |processor: &mut BroadcastMaelstromNode, message| {
+            processor
+                .process(maelstrom_rust::testkit::fixtures::request(
+                    BroadcastMessage::Broadcast {
+                        message,
+                        topic: None,
+                    },
+                ))
+                .unwrap()
+                .into_iter()
+                .flatten()
+                .filter_map(|reply| match reply.body.body {
+                    BroadcastMessage::Broadcast { message, .. } => {
+                        Some((message, reply.body.msg_id.unwrap()))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<(i64, i64)>>()
+        };
+
+        // tick 1: 1 is gossiped to n2
+        clock.set(Duration::from_millis(10));
+        let sent = broadcast(&mut processor, 1);
+        assert_eq!(sent.iter().map(|(m, _)| *m).collect::<Vec<i64>>(), vec![1]);
+        // tick 2: n2 hasn't acked 1, only 2 is gossiped
+        clock.set(Duration::from_millis(50));
+        let sent = broadcast(&mut processor, 2);
+        assert_eq!(sent.iter().map(|(m, _)| *m).collect::<Vec<i64>>(), vec![2]);
+        let ack_for_2 = sent[0].1;
+
+        // once the window is over, 1 is in flight no more and sent again, 2 still is
+        clock.set(Duration::from_millis(120));
+        let sent = broadcast(&mut processor, 3);
+        assert_eq!(
+            sent.iter().map(|(m, _)| *m).collect::<Vec<i64>>(),
+            vec![3, 1]
+        );
+
+        // n2 acked 2, it has it and doesn't get it again
+        processor
+            .process(Message {
+                src: Some("n2".into()),
+                dest: Some("n1".into()),
+                body: Body {
+                    msg_id: Some(1),
+                    in_reply_to: Some(ack_for_2),
+                    body: BroadcastMessage::BroadcastOk {},
+                },
+            })
+            .unwrap();
+        clock.set(Duration::from_millis(130));
+        let mut sent: Vec<i64> = broadcast(&mut processor, 4)
+            .into_iter()
+            .map(|(m, _)| m)
+            .collect();
+        sent.sort();
+        assert_eq!(sent, vec![4]);
+    }
+
+    #[test]
+    fn test_unacked_gossip_is_forgotten_past_the_ack_timeout() {
+        use stubs::FakeClock;
+
+        let clock = FakeClock::default();
+        // neither a window nor rate control
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::from([("n2".into(), HashSet::new())]),
+            HashSet::from_iter(vec!["n2".into()]),
+        )
+        .with_clock(Box::new(clock.clone()));
+
+        for message in 1..=3 {
+            let broadcast = BroadcastMessage::Broadcast {
+                message,
+                topic: None,
+            };
+            processor
+                .process(maelstrom_rust::testkit::fixtures::request(broadcast))
+                .unwrap();
+        }
+        assert_eq!(processor.in_flight["n2"].len(), 3);
+
+        clock.set(crate::DEFAULT_ACK_TIMEOUT / 2);
+        processor.on_timer("tick").unwrap();
+        assert_eq!(processor.in_flight["n2"].len(), 3);
+        clock.set(crate::DEFAULT_ACK_TIMEOUT);
+        processor.on_timer("tick").unwrap();
+        assert!(processor.in_flight["n2"].is_empty());
+    }
+
+    #[test]
+    fn test_returning_peer_gets_its_backlog() {
+        use stubs::FakeClock;
+
+        let clock = FakeClock::default();
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::from_iter(vec![1, 2, 3]),
+            hashmap! {"n2".to_string() => HashSet::from_iter(vec![1])},
+            HashSet::from_iter(vec!["n2".into()]),
+        )
+        .with_clock(Box::new(clock.clone()))
+        .with_reconnect_after(Some(Duration::from_millis(1000)));
+        let heartbeat_at = |processor: &mut BroadcastMaelstromNode, now| {
+            clock.set(Duration::from_millis(now));
+            processor
+                .process(Message {
+                    src: Some("n2".into()),
+                    dest: Some("n1".into()),
+                    body: Body {
+                        msg_id: Some(now as i64),
+                        in_reply_to: None,
+                        body: BroadcastMessage::Heartbeat {},
+                    },
+                })
+                .unwrap()
+                .into_iter()
+                .flatten()
+                .map(|msg| (msg.dest.unwrap(), msg.body.body))
+                .collect::<Vec<(String, BroadcastMessage)>>()
+        };
+
+        assert!(heartbeat_at(&mut processor, 0).is_empty());
+        assert!(heartbeat_at(&mut processor, 800).is_empty());
+        let gossip = |message| BroadcastMessage::Broadcast {
+            message,
+            topic: None,
+        };
+        assert_eq!(
+            heartbeat_at(&mut processor, 2000),
+            vec![("n2".into(), gossip(2)), ("n2".into(), gossip(3))]
+        );
+        assert!(heartbeat_at(&mut processor, 2100).is_empty());
+    }
+
+    #[test]
+    fn test_fan_out_over_cap_is_deferred() {
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n2".into(), "n3".into(), "n4".into()]),
+        )
+        .with_max_fan_out(Some(2));
+        let sent = |replies: Option<Vec<Message<BroadcastMessage>>>| {
+            replies
+                .unwrap()
+                .into_iter()
+                .map(|reply| match reply.body.body {
+                    BroadcastMessage::Broadcast { .. } => reply.dest.unwrap(),
+                    other => format!("{:?}", other)
+                        .split(' ')
+                        .next()
+                        .unwrap()
+                        .to_string(),
+                })
+                .collect::<Vec<String>>()
+        };
+
+        let replies = processor
+            .process(maelstrom_rust::testkit::fixtures::request(
+                BroadcastMessage::Broadcast {
+                    message: 1,
+                    topic: None,
+                },
+            ))
+            .unwrap();
+        assert_eq!(sent(replies), vec!["BroadcastOk", "n2", "n3"]);
+
+        let replies = processor.process(fixtures::read_msg()).unwrap();
+        assert_eq!(sent(replies), vec!["ReadOk", "n4"]);
+        assert!(processor.deferred.is_empty());
+    }
+
+    #[test]
+    fn test_gossip_goes_through_the_sender_apart_from_answers() {
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n2".into(), "n3".into()]),
+        );
+        let mut sent = vec![];
+
+        let replies = processor
+            .process_with_sender(client_broadcast("n1", 2), &mut sent)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].body.body, BroadcastMessage::BroadcastOk {});
+        let sent: Vec<(String, i64)> = sent
+            .into_iter()
+            .map(|gossip| match gossip.body.body {
+                BroadcastMessage::Broadcast { message, .. } => (gossip.dest.unwrap(), message),
+                other => panic!("unexpected message sent {:?}", other),
+            })
+            .collect();
+        assert_eq!(sent, vec![("n2".into(), 2), ("n3".into(), 2)]);
+    }
+
+    #[test]
+    fn test_deferred_over_cap_drops_the_oldest() {
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n2".into(), "n3".into(), "n4".into(), "n5".into()]),
+        )
+        .with_max_fan_out(Some(1))
+        .with_max_deferred(2);
+        let broadcast = |message| {
+            maelstrom_rust::testkit::fixtures::request(BroadcastMessage::Broadcast {
+                message,
+                topic: None,
+            })
+        };
+
+        processor.process(broadcast(1)).unwrap();
+        // gossip of 1 to n3, n4 and n5 was deferred, that to n3 dropped
+        assert_eq!(processor.deferred.len(), 2);
+        assert_eq!(processor.deferred_dropped, 1);
+        processor.process(broadcast(2)).unwrap();
+        assert_eq!(processor.deferred.len(), 2);
+        assert_eq!(processor.deferred_dropped, 4);
 
-                    // we take all neighbors except the node who sent the actual broadcast message
-                    self.node_ids.iter().for_each(|broadcast_dest| {
-                        if let Some(src) = msg.src.as_ref() {
-                            if src != broadcast_dest {
-                                reply_msgs.push(Message {
-                                    src: self.node_id.clone(),
-                                    dest: Some(broadcast_dest.to_string()),
-                                    body: Body {
-                                        msg_id: msg.body.msg_id,
-                                        in_reply_to: None,
-                                        body: BroadcastMessage::Broadcast { message },
-                                    },
-                                });
-                            }
-                        }
-                    });
-                    Ok(Some([reply_msgs, prev_messages_to_broadcast].concat()))
-                } else {
-                    Ok(Some(
-                        [vec![broadcast_ok_reply_msg], prev_messages_to_broadcast].concat(),
-                    ))
+        let deferred: Vec<(String, i64)> = processor
+            .deferred
+            .iter()
+            .map(|gossip| match gossip.body.body {
+                BroadcastMessage::Broadcast { message, .. } => {
+                    (gossip.dest.clone().unwrap(), message)
                 }
-            }
-            BroadcastMessage::Read {} => {
-                let reply = Ok(Some(vec![Message {
-                    src: msg.dest,
-                    dest: msg.src,
-                    body: Body {
-                        msg_id: Some(self.id),
-                        in_reply_to: msg.body.msg_id,
-                        body: BroadcastMessage::ReadOk {
-                            messages: self.messages.clone(),
-                        },
-                    },
-                }]));
-                self.id += 1;
-                reply
-            }
+                _ => panic!("unexpected deferred message {:?}", gossip),
+            })
+            .collect();
+        assert_eq!(deferred, vec![("n4".into(), 2), ("n5".into(), 2)]);
+        assert_eq!(processor.dump().unwrap()["deferred_dropped"], 4);
+    }
 
-            BroadcastMessage::Topology { topology } => {
-                let reply = Ok(Some(vec![Message {
-                    src: msg.dest,
-                    dest: msg.src,
-                    body: Body {
-                        msg_id: Some(self.id),
-                        in_reply_to: msg.body.msg_id,
-                        body: BroadcastMessage::TopologyOk {},
-                    },
-                }]));
+    #[test]
+    fn test_full_sync_reconciles_divergent_peer() {
+        use stubs::FakeClock;
 
-                // update current list of node's neighbors based on received topology
-                if let Some(node) = &self.node_id {
-                    if let Some(node_ids) = topology.get(node) {
-                        self.node_ids = node_ids.clone();
-                    }
-                };
-                self.id += 1;
-                reply
-            }
+        let clock = FakeClock::default();
+        let mut n1 = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::from_iter(vec![1, 2, 3]),
+            // n1 wrongly believes n2 has everything, so no catch-up would ever go out
+            hashmap! {"n2".to_string() => HashSet::from_iter(vec![1, 2, 3])},
+            HashSet::from_iter(vec!["n2".into()]),
+        )
+        .with_clock(Box::new(clock.clone()))
+        .with_full_sync_interval(Some(Duration::from_millis(5000)));
+        let mut n2 = BroadcastMaelstromNode::new(
+            Some("n2".into()),
+            HashSet::from_iter(vec![1, 4]),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n1".into()]),
+        );
+        let digests_at = |n1: &mut BroadcastMaelstromNode, now| {
+            clock.set(Duration::from_millis(now));
+            n1.process(fixtures::read_msg())
+                .unwrap()
+                .unwrap()
+                .into_iter()
+                .filter(|msg| matches!(msg.body.body, BroadcastMessage::SyncDigest { .. }))
+                .collect::<Vec<Message<BroadcastMessage>>>()
+        };
 
-            _ => Err(anyhow!("Received unknown message: {:?}", msg)),
-        }
+        assert!(digests_at(&mut n1, 1000).is_empty());
+        let digests = digests_at(&mut n1, 5000);
+        assert_eq!(digests.len(), 1);
+        assert_eq!(digests[0].dest, Some("n2".into()));
+
+        let delta = n2.process(digests[0].clone()).unwrap().unwrap();
+        let missing = n1.process(delta[0].clone()).unwrap().unwrap();
+        assert_eq!(n1.messages, HashSet::from_iter(vec![1, 2, 3, 4]));
+        n2.process(missing[0].clone()).unwrap();
+        assert_eq!(n2.messages, HashSet::from_iter(vec![1, 2, 3, 4]));
+        assert!(digests_at(&mut n1, 9000).is_empty());
     }
-}
 
-fn broadcast_all_seen_messages(
-    processor: &BroadcastMaelstromNode,
-    filter_message: &i64,
-) -> Vec<Message<BroadcastMessage>> {
-    let mut counter = processor.id;
-    processor
-        .messages_shared_per_node
-        .iter()
-        .flat_map(|(node, node_shared_messages)| {
-            processor
-                .messages
-                .iter()
-                .filter(|m| {
-                    !node_shared_messages.contains(m)
-                        && *m != filter_message
-                        && node.starts_with('n')
-                })
-                .map(move |not_shared_msg_for_node| {
-                    let b_msg = Message {
-                        src: processor.node_id.clone(),
-                        dest: Some(node.clone()),
-                        body: Body {
-                            msg_id: Some(counter),
-                            in_reply_to: None,
-                            body: BroadcastMessage::Broadcast {
-                                message: *not_shared_msg_for_node,
-                            },
-                        },
-                    };
-                    counter += 1;
-                    b_msg
-                })
-        })
-        .collect()
-}
+    #[test]
+    fn test_checksum_mismatch_triggers_a_sync() {
+        use stubs::FakeClock;
 
-fn main() -> anyhow::Result<()> {
-    run(&mut BroadcastMaelstromNode::default())
-}
+        let clock = FakeClock::default();
+        let mut n1 = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::from_iter(vec![1, 2, 3]),
+            HashMap::new(),
+            HashSet::from_iter(vec!["n2".into()]),
+        )
+        .with_clock(Box::new(clock.clone()))
+        .with_checksum_interval(Some(Duration::from_millis(1000)));
+        let checksums_at = |n1: &mut BroadcastMaelstromNode, now| {
+            clock.set(Duration::from_millis(now));
+            n1.process(fixtures::read_msg())
+                .unwrap()
+                .unwrap()
+                .into_iter()
+                .filter(|msg| matches!(msg.body.body, BroadcastMessage::Checksum { .. }))
+                .collect::<Vec<Message<BroadcastMessage>>>()
+        };
+        let peer = |messages: Vec<i64>| {
+            BroadcastMaelstromNode::new(
+                Some("n2".into()),
+                HashSet::from_iter(messages),
+                HashMap::new(),
+                HashSet::from_iter(vec!["n1".into()]),
+            )
+        };
 
-#[cfg(test)]
-mod tests {
+        assert!(checksums_at(&mut n1, 500).is_empty());
+        let checksums = checksums_at(&mut n1, 1000);
+        assert_eq!(checksums.len(), 1);
+        assert_eq!(checksums[0].dest, Some("n2".into()));
 
-    use crate::BroadcastMaelstromNode;
-    use crate::BroadcastMessage;
-    use std::collections::HashMap;
-    use std::collections::HashSet;
+        // the same set in another order, nothing to reconcile
+        let mut in_sync = peer(vec![3, 1, 2]);
+        assert_eq!(in_sync.process(checksums[0].clone()).unwrap(), None);
 
-    use maelstrom_rust::msg_protocol::*;
+        let mut diverged = peer(vec![1, 2, 4]);
+        let digest = diverged.process(checksums[0].clone()).unwrap().unwrap();
+        assert_eq!(digest.len(), 1);
+        assert_eq!(digest[0].dest, Some("n1".into()));
+        assert!(matches!(
+            digest[0].body.body,
+            BroadcastMessage::SyncDigest { .. }
+        ));
+        let delta = n1.process(digest[0].clone()).unwrap().unwrap();
+        let missing = diverged.process(delta[0].clone()).unwrap().unwrap();
+        assert_eq!(diverged.messages, HashSet::from_iter(vec![1, 2, 3, 4]));
+        n1.process(missing[0].clone()).unwrap();
+        assert_eq!(n1.messages, HashSet::from_iter(vec![1, 2, 3, 4]));
+        assert!(checksums_at(&mut n1, 1500).is_empty());
+    }
 
-    use maplit::hashmap;
-    use serde_json::from_str;
-    use serde_json::to_string;
-    mod fixtures {
-        use super::*;
+    #[test]
+    fn test_scheduled_compaction_of_fully_acked_messages() {
+        use stubs::FakeClock;
 
-        use std::{
-            collections::{HashMap, HashSet},
-            vec,
-        };
+        let clock = FakeClock::default();
+        let mut processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::from_iter(vec![1, 2, 3]),
+            hashmap! {
+                "n2".to_string() => HashSet::from_iter(vec![1, 2]),
+                "n3".to_string() => HashSet::from_iter(vec![1]),
+                "n4".to_string() => HashSet::from_iter(vec![1, 2, 3]),
+            },
+            HashSet::from_iter(vec!["n2".into(), "n3".into()]),
+        )
+        .with_clock(Box::new(clock.clone()))
+        .with_compaction_interval(Some(Duration::from_millis(10)));
 
-        pub fn init_msg() -> Message<BroadcastMessage> {
-            Message {
-                src: Some("src".into()),
-                dest: Some("dest".into()),
-                body: Body {
-                    msg_id: Some(1),
-                    in_reply_to: None,
-                    body: BroadcastMessage::Init {
-                        node_id: "node1".into(),
-                        node_ids: HashSet::from_iter(vec!["node1".into(), "node2".into()]),
-                    },
-                },
-            }
-        }
-        pub fn init_ok_msg() -> Message<BroadcastMessage> {
-            Message {
-                src: Some("dest".into()),
-                dest: Some("src".into()),
-                body: Body {
-                    msg_id: Some(1),
-                    in_reply_to: Some(1),
-                    body: BroadcastMessage::InitOk {},
-                },
-            }
-        }
-        pub fn broadcast_msg() -> Message<BroadcastMessage> {
-            Message {
-                src: Some("node2".into()),
-                dest: Some("node1".into()),
-                body: Body {
-                    msg_id: Some(1),
-                    in_reply_to: None,
-                    body: BroadcastMessage::Broadcast { message: 1 },
-                },
-            }
-        }
+        clock.set(Duration::from_millis(5));
+        processor.process(fixtures::read_msg()).unwrap();
+        assert_eq!(processor.messages_shared_per_node.len(), 3);
 
-        pub fn broadcast_ok_msg() -> Message<BroadcastMessage> {
-            Message {
-                src: Some("node1".into()),
-                dest: Some("node2".into()),
-                body: Body {
-                    msg_id: Some(1),
-                    in_reply_to: Some(1),
-                    body: BroadcastMessage::BroadcastOk {},
-                },
+        clock.set(Duration::from_millis(10));
+        processor.process(fixtures::read_msg()).unwrap();
+        assert_eq!(
+            processor.messages_shared_per_node,
+            hashmap! {
+                "n2".to_string() => HashSet::from_iter(vec![2]),
+                "n3".to_string() => HashSet::new(),
             }
-        }
+        );
+        assert_eq!(processor.unsent_for_peer("n2"), vec![3]);
+        assert_eq!(processor.unsent_for_peer("n3"), vec![2, 3]);
 
-        pub fn read_msg() -> Message<BroadcastMessage> {
-            Message {
-                src: Some("src".into()),
-                dest: Some("dest".into()),
-                body: Body {
-                    msg_id: Some(1),
-                    in_reply_to: None,
-                    body: BroadcastMessage::Read {},
-                },
-            }
-        }
-        pub fn read_ok_msg(messages: HashSet<i64>) -> Message<BroadcastMessage> {
-            Message {
-                src: Some("dest".into()),
-                dest: Some("src".into()),
-                body: Body {
-                    msg_id: Some(1),
-                    in_reply_to: Some(1),
-                    body: BroadcastMessage::ReadOk { messages },
-                },
-            }
-        }
-        pub fn topology_msg(
-            maybe_provided_topo: Option<HashMap<String, HashSet<String>>>,
-        ) -> Message<BroadcastMessage> {
-            let topology = maybe_provided_topo.unwrap_or(hashmap! {
-                "node1".to_string() => HashSet::from_iter(vec!["node2".into(), "node3".into()]),
-                "node2".to_string() => HashSet::from_iter(vec!["node3".into()]),
-                "node3".to_string() => HashSet::from_iter(vec!["node2".into()])
-            });
-            Message {
-                src: Some("src".into()),
-                dest: Some("dest".into()),
-                body: Body {
-                    msg_id: Some(1),
-                    in_reply_to: None,
-                    body: BroadcastMessage::Topology { topology },
-                },
-            }
-        }
-        pub fn topology_ok_msg() -> Message<BroadcastMessage> {
-            Message {
-                src: Some("dest".into()),
-                dest: Some("src".into()),
-                body: Body {
-                    msg_id: Some(1),
-                    in_reply_to: Some(1),
-                    body: BroadcastMessage::TopologyOk {},
+        // a new neighbor hasn't acked anything yet, the old ones get their acks back
+        processor
+            .process(maelstrom_rust::testkit::fixtures::request(
+                BroadcastMessage::AddPeer {
+                    node_id: "n5".into(),
                 },
-            }
-        }
+            ))
+            .unwrap();
+        assert_eq!(processor.unsent_for_peer("n5"), vec![1, 2, 3]);
+        assert_eq!(processor.unsent_for_peer("n3"), vec![2, 3]);
     }
+
     #[test]
-    fn test_msg_processing_init() {
-        let mut processor = BroadcastMaelstromNode::default();
-        let msg = fixtures::init_msg();
-        let reply = processor.process(msg);
-        assert_eq!(reply.unwrap(), Some(vec![fixtures::init_ok_msg()]));
-        assert_eq!(processor.node_id, Some("node1".into()));
-        assert_eq!(processor.node_ids, HashSet::from_iter(vec!["node2".into()]));
+    fn test_unsent_for_peer() {
+        let processor = BroadcastMaelstromNode::new(
+            Some("n1".into()),
+            HashSet::from_iter(vec![4, 1, 3, 2]),
+            hashmap! {"n2".to_string() => HashSet::from_iter(vec![2, 5])},
+            HashSet::from_iter(vec!["n2".into(), "n3".into()]),
+        );
+
+        assert_eq!(processor.unsent_for_peer("n2"), vec![1, 3, 4]);
+        assert_eq!(processor.unsent_for_peer("n3"), vec![1, 2, 3, 4]);
     }
 
     #[test]
-    fn test_msg_processing_broadcast_no_other_nodes() {
-        let mut processor = BroadcastMaelstromNode::default();
-        let msg = fixtures::broadcast_msg();
+    fn test_bounded_store_evicts_the_oldest_message() {
+        let mut processor = BroadcastMaelstromNode::default().with_max_messages(Some(2));
+        for message in [3, 1, 2] {
+            processor
+                .process(maelstrom_rust::testkit::fixtures::request(
+                    BroadcastMessage::Broadcast {
+                        message,
+                        topic: None,
+                    },
+                ))
+                .unwrap();
+        }
 
-        let reply = processor.process(msg);
-        assert_eq!(reply.unwrap(), Some(vec![fixtures::broadcast_ok_msg()]));
-        assert_eq!(processor.messages, HashSet::from_iter(vec![1]));
-        assert_eq!(
-            processor.messages_shared_per_node,
-            hashmap! {"node2".to_string() => HashSet::from_iter(vec![1])}
-        );
+        assert_eq!(processor.messages, HashSet::from_iter(vec![1, 2]));
     }
+
     #[test]
-    fn test_msg_processing_broadcast_with_multi_broadcast_to_neighbors_ignoring_sender() {
+    fn test_bounded_store_forgets_what_it_knew_of_evicted_messages() {
         let mut processor = BroadcastMaelstromNode::new(
-            1,
-            Some("node1".into()),
-            HashSet::from_iter(vec![]),
-            HashMap::new(),
-            HashSet::from_iter(vec!["node2".into(), "node3".into()]),
-        );
-        let msg = fixtures::broadcast_msg();
+            Some("n1".into()),
+            HashSet::new(),
+            HashMap::from([("n2".into(), HashSet::new())]),
+            HashSet::from_iter(vec!["n2".into()]),
+        )
+        .with_max_messages(Some(2));
+        let gossip = |message| Message {
+            src: Some("n2".into()),
+            dest: Some("n1".into()),
+            body: Body {
+                msg_id: Some(message),
+                in_reply_to: None,
+                body: BroadcastMessage::Broadcast {
+                    message,
+                    topic: None,
+                },
+            },
+        };
+        for message in [3, 1, 2] {
+            processor.process(gossip(message)).unwrap();
+        }
 
-        let reply = processor.process(msg.clone());
+        assert_eq!(processor.messages, HashSet::from_iter(vec![1, 2]));
         assert_eq!(
-            reply.unwrap(),
-            Some(vec![
-                fixtures::broadcast_ok_msg(),
-                Message {
-                    src: Some("node1".into()),
-                    dest: Some("node3".into()),
-                    body: msg.body.clone(),
-                },
-            ])
+            processor.messages_order,
+            std::collections::VecDeque::from(vec![1, 2])
         );
-        assert_eq!(processor.messages, HashSet::from_iter(vec![1]));
+        assert!(!processor.first_seen.contains_key(&3));
         assert_eq!(
-            processor.messages_shared_per_node,
-            hashmap! {"node2".into() => HashSet::from_iter(vec![1])}
+            processor.messages_shared_per_node["n2"],
+            HashSet::from_iter(vec![1, 2])
         );
+
+        // without a cap, the order isn't kept
+        let mut unbounded = BroadcastMaelstromNode::default();
+        unbounded.process(gossip(1)).unwrap();
+        assert!(unbounded.messages_order.is_empty());
     }
+
     #[test]
-    fn test_msg_processing_topology() {
+    fn test_reset() {
+        use stubs::FakeClock;
+
+        let clock = FakeClock::default();
         let mut processor = BroadcastMaelstromNode::new(
-            1,
-            Some("node1".into()),
+            Some("n1".into()),
             HashSet::new(),
             HashMap::new(),
-            HashSet::from_iter(vec!["node1".into()]),
-        );
-        let msg = fixtures::topology_msg(None);
+            HashSet::from_iter(vec!["n2".into(), "n3".into()]),
+        )
+        .with_max_fan_out(Some(0))
+        .with_spanning_tree(true)
+        .with_partitioned(true)
+        .with_clock(Box::new(clock.clone()))
+        .with_compaction_interval(Some(Duration::from_millis(10)))
+        .with_full_sync_interval(Some(Duration::from_millis(10)))
+        .with_checksum_interval(Some(Duration::from_millis(10)))
+        .with_ack_batch_interval(Some(Duration::from_millis(10)));
+        processor
+            .on_init("n1".into(), vec!["n1".into(), "n2".into(), "n3".into()])
+            .unwrap();
+        processor.process(fixtures::topology_msg(None)).unwrap();
+        clock.set(Duration::from_millis(100));
+        processor.process(fixtures::broadcast_msg()).unwrap();
+        assert_eq!(processor.messages, HashSet::from_iter(vec![1]));
+        assert!(processor.last_heard.contains_key("n2"));
+        assert!(!processor.deferred.is_empty());
+        assert!(processor.in_flight.contains_key("n3"));
+        assert!(processor.topology_received);
+        assert!(!processor.tree.is_empty());
+        assert!(!processor.ring.is_empty());
+        assert_eq!(processor.last_full_sync, Duration::from_millis(100));
+        assert_eq!(processor.last_checksum, Duration::from_millis(100));
+        assert_eq!(processor.last_compaction, Duration::from_millis(100));
+        assert_eq!(processor.last_ack_batch, Duration::from_millis(100));
 
-        let reply = processor.process(msg);
-        assert_eq!(reply.unwrap(), Some(vec![fixtures::topology_ok_msg()]));
-        assert_eq!(processor.node_id, Some("node1".into()));
-        assert_eq!(
-            processor.node_ids,
-            HashSet::from_iter(vec!["node2".into(), "node3".into()])
-        );
+        processor.reset();
+        assert!(processor.messages.is_empty());
+        assert!(processor.messages_shared_per_node.is_empty());
+        assert!(processor.last_heard.is_empty());
+        assert_eq!(processor.last_heard_from_peers, None);
+        assert_eq!(processor.last_heartbeat, Duration::ZERO);
+        assert!(processor.deferred.is_empty());
+        assert!(processor.in_flight.is_empty());
+        assert!(!processor.topology_received);
+        assert!(processor.tree.is_empty());
+        assert!(processor.ring.is_empty());
+        assert_eq!(processor.last_full_sync, Duration::ZERO);
+        assert_eq!(processor.last_checksum, Duration::ZERO);
+        assert_eq!(processor.last_compaction, Duration::ZERO);
+        assert_eq!(processor.last_ack_batch, Duration::ZERO);
+        assert_eq!(processor.node_id, Some("n1".into()));
+        assert_eq!(processor.node_ids.len(), 2);
     }
 
     #[test]
-    fn test_msg_processing_topology_without_node_intialized_before() {
-        let mut processor = BroadcastMaelstromNode::default();
-        let msg = fixtures::topology_msg(None);
+    fn test_recording_transitions() {
+        let mut recording = Recording::new(BroadcastMaelstromNode::default());
+        let broadcasts: Vec<Message<BroadcastMessage>> = vec![2, 1]
+            .into_iter()
+            .map(|message| {
+                let mut msg = fixtures::broadcast_msg();
+                msg.body.body = BroadcastMessage::Broadcast {
+                    message,
+                    topic: None,
+                };
+                msg
+            })
+            .collect();
+        for msg in broadcasts.iter().cloned().chain(vec![fixtures::read_msg()]) {
+            recording.process(msg).unwrap();
+        }
 
-        let reply = processor.process(msg);
-        assert_eq!(reply.unwrap(), Some(vec![fixtures::topology_ok_msg()]));
-        // we shouldn't update with values from the topology message if current node_id hasn't even been initialized
-        assert_eq!(processor.node_id, None);
-        let expected_node_ids: HashSet<String> = HashSet::new();
-        assert_eq!(processor.node_ids, expected_node_ids);
+        let transitions = recording.transitions();
+        assert_eq!(transitions.len(), 3);
+        assert_eq!(
+            transitions
+                .iter()
+                .map(|t| t.state.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                Some(json!({"messages": [2]})),
+                Some(json!({"messages": [1, 2]})),
+                Some(json!({"messages": [1, 2]})),
+            ]
+        );
+        assert_eq!(transitions[0].msg, broadcasts[0]);
+        assert_eq!(transitions[1].msg, broadcasts[1]);
+        let read_ok = fixtures::read_ok_msg(HashSet::from_iter(vec![1, 2]));
+        assert_eq!(transitions[2].replies, Some(vec![read_ok]));
     }
 
     #[test]
-    fn test_msg_processing_topology_without_current_nodeid_mapped() {
+    fn test_msg_processing_paginated_read() {
+        let stored_messages = HashSet::from_iter(vec![5, 1, 4, 2, 3]);
         let mut processor = BroadcastMaelstromNode::new(
-            1,
-            Some("node1".into()),
-            HashSet::new(),
+            None,
+            stored_messages.clone(),
             HashMap::new(),
-            HashSet::from_iter(vec!["node1".into()]),
+            HashSet::new(),
         );
-        let msg = fixtures::topology_msg(Some(hashmap! {
-            "node2".into() => HashSet::from_iter(vec!["node3".into()]),
-            "node3".into() => HashSet::from_iter(vec!["node2".into()])
-        }));
 
-        let reply = processor.process(msg);
-        assert_eq!(reply.unwrap(), Some(vec![fixtures::topology_ok_msg()]));
-        // we shouldn't update with values from the topology message if current node_id is not mapped
-        assert_eq!(processor.node_id, Some("node1".into()));
+        let first_page = processor
+            .process(fixtures::paginated_read_msg(None, 3))
+            .unwrap()
+            .unwrap();
+        let BroadcastMessage::ReadOk {
+            messages: first_messages,
+            next_cursor: Some(cursor),
+            ..
+        } = first_page[0].body.body.clone()
+        else {
+            panic!("Expected a first page with a cursor: {:?}", first_page);
+        };
+        assert_eq!(first_messages, HashSet::from_iter(vec![1, 2, 3]));
+
+        let second_page = processor
+            .process(fixtures::paginated_read_msg(Some(cursor), 3))
+            .unwrap()
+            .unwrap();
+        let BroadcastMessage::ReadOk {
+            messages: second_messages,
+            next_cursor: None,
+            ..
+        } = second_page[0].body.body.clone()
+        else {
+            panic!("Expected a last page without cursor: {:?}", second_page);
+        };
+
         assert_eq!(
-            processor.node_ids,
-            HashSet::from_iter(vec!["node1".into(),])
+            first_messages
+                .union(&second_messages)
+                .copied()
+                .collect::<HashSet<i64>>(),
+            stored_messages
         );
     }
 
     #[test]
-    fn test_msg_processing_read() {
-        let stored_messages = HashSet::from_iter(vec![1, 2]);
-        let topology = HashSet::from_iter(vec!["2".to_string()]);
-
+    fn test_msg_processing_paginated_read_with_a_value_stored_between_pages() {
         let mut processor = BroadcastMaelstromNode::new(
-            1,
             None,
-            stored_messages.clone(),
+            HashSet::from_iter(vec![10, 20, 30, 40]),
             HashMap::new(),
-            topology.clone(),
+            HashSet::new(),
         );
-        let msg = fixtures::read_msg();
+        let page = |processor: &mut BroadcastMaelstromNode, cursor| {
+            let reply = processor
+                .process(fixtures::paginated_read_msg(cursor, 2))
+                .unwrap()
+                .unwrap();
+            match reply[0].body.body.clone() {
+                BroadcastMessage::ReadOk {
+                    messages,
+                    next_cursor,
+                    ..
+                } => (messages, next_cursor),
+                other => panic!("Expected a page: {:?}", other),
+            }
+        };
 
-        let reply = processor.process(msg);
+        let (first_messages, cursor) = page(&mut processor, None);
+        assert_eq!(first_messages, HashSet::from_iter(vec![10, 20]));
+        assert_eq!(cursor, Some(20));
+
+        // before the cursor, it shifts nothing after it
+        processor.store(5);
+        let (second_messages, cursor) = page(&mut processor, cursor);
+        assert_eq!(second_messages, HashSet::from_iter(vec![30, 40]));
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn test_serde_msg_read_without_pagination_fields() {
+        let msg = from_str::<Message<BroadcastMessage>>(
+            r#"{"src":"src","dest":"dest","body":{"type":"read","msg_id":1}}"#,
+        )
+        .unwrap();
+        assert_eq!(msg, fixtures::read_msg());
         assert_eq!(
-            reply.unwrap(),
-            Some(vec![fixtures::read_ok_msg(stored_messages.clone())])
+            to_string(&fixtures::read_ok_msg(HashSet::from_iter(vec![1]))).unwrap(),
+            r#"{"src":"dest","dest":"src","body":{"in_reply_to":1,"type":"read_ok","messages":[1]}}"#
+        );
+    }
+
+    #[test]
+    fn test_serde_msg_large_ints_sent_as_strings() {
+        let msg = from_str::<Message<BroadcastMessage>>(
+            r#"{"src":"n2","dest":"n1","body":{"type":"read_ok","msg_id":"9007199254740993","messages":["9007199254740993",2],"next_cursor":"9007199254740993"}}"#,
+        )
+        .unwrap();
+        assert_eq!(msg.body.msg_id, Some(9007199254740993));
+        assert_eq!(
+            msg.body.body,
+            BroadcastMessage::ReadOk {
+                messages: HashSet::from([9007199254740993, 2]),
+                next_cursor: Some(9007199254740993),
+                stale: false,
+            }
         );
+        // anything but integers still fails
+        assert!(from_str::<BroadcastMessage>(r#"{"type":"broadcast","message":"x"}"#).is_err());
     }
+
     #[test]
     fn test_msg_processing_unhandled_msg() {
         let mut processor: BroadcastMaelstromNode = BroadcastMaelstromNode::default();
-        let msg = fixtures::init_ok_msg();
+        let msg = fixtures::topology_ok_msg();
 
         let expected_err_msg = format!("Received unknown message: {:?}", &msg);
         let result = processor.process(msg);
@@ -493,7 +3942,7 @@ mod tests {
     }
 
     #[test]
-    fn test_msg_processor_id_increments_on_every_msg() {
+    fn test_msg_processor_leaves_msg_ids_to_the_runner() {
         let mut processor = BroadcastMaelstromNode::default();
 
         fn assert_reply_to_msg(
@@ -513,7 +3962,6 @@ mod tests {
             msg.body.msg_id = Some(x);
 
             let mut expected_reply = base_reply.clone();
-            expected_reply.body.msg_id = Some(x);
             expected_reply.body.in_reply_to = Some(x);
             assert_reply_to_msg(&mut processor, msg, Some(vec![expected_reply]))
         })
@@ -548,15 +3996,6 @@ mod tests {
         assert_round_trip(fixtures::topology_ok_msg());
     }
 
-    #[test]
-    fn test_serde_msg_init() {
-        assert_round_trip(fixtures::init_msg());
-    }
-    #[test]
-    fn test_serde_msg_init_ok() {
-        assert_round_trip(fixtures::init_ok_msg());
-    }
-
     fn assert_round_trip(msg: Message<BroadcastMessage>) {
         let msg_serialized = to_string(&msg).unwrap();
         let msg_round_trip = from_str::<Message<BroadcastMessage>>(&msg_serialized).unwrap();
@@ -1,23 +1,90 @@
 use anyhow::anyhow;
-use anyhow::Ok;
 use anyhow::Result;
 use maelstrom_rust::msg_protocol::*;
 use maelstrom_rust::runner::*;
 use serde::{Deserialize, Serialize};
 
+#[derive(Clone)]
 struct EchoMaelstromNode {
-    id: i64,
+    // echo payloads are base64 encoded binary data, decoded and encoded back before replying
+    base64: bool,
 }
 
 impl EchoMaelstromNode {
-    pub fn new(id: i64) -> Self {
-        Self { id }
+    pub fn new() -> Self {
+        Self { base64: false }
     }
+
+    pub fn with_batch_size(self, batch_size: usize) -> BatchingEchoNode {
+        BatchingEchoNode {
+            node: self,
+            batch_size,
+            pending: vec![],
+        }
+    }
+
+    pub fn with_base64(mut self, base64: bool) -> Self {
+        self.base64 = base64;
+        self
+    }
+}
+
+// echo_oks are held back until `batch_size` are pending, then sent all at once; the pending
+// ones being state carried across messages, it can't be processed in parallel
+struct BatchingEchoNode {
+    node: EchoMaelstromNode,
+    batch_size: usize,
+    pending: Vec<Message<EchoMessage>>,
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, byte)| {
+            group | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let sextet = (group >> (18 - 6 * i)) & 0x3f;
+                encoded.push(BASE64_ALPHABET[sextet as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn base64_decode(encoded: &str) -> Result<Vec<u8>> {
+    let encoded = encoded.trim_end_matches('=');
+    let mut bytes = Vec::with_capacity(encoded.len() * 3 / 4);
+    let mut group = 0u32;
+    for (i, c) in encoded.bytes().enumerate() {
+        let sextet = BASE64_ALPHABET
+            .iter()
+            .position(|&a| a == c)
+            .ok_or_else(|| anyhow!("Invalid base64 character {:?}", c as char))?;
+        group = group << 6 | sextet as u32;
+        if i % 4 == 3 {
+            bytes.extend_from_slice(&group.to_be_bytes()[1..]);
+            group = 0;
+        }
+    }
+    match encoded.len() % 4 {
+        0 => {}
+        2 => bytes.push((group >> 4) as u8),
+        3 => bytes.extend_from_slice(&((group >> 2) as u16).to_be_bytes()),
+        _ => return Err(anyhow!("Invalid base64 length {}", encoded.len())),
+    }
+    Ok(bytes)
 }
 
 impl Default for EchoMaelstromNode {
     fn default() -> Self {
-        Self::new(1)
+        Self::new()
     }
 }
 
@@ -25,59 +92,81 @@ impl Default for EchoMaelstromNode {
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 pub enum EchoMessage {
-    Init {
-        node_id: String,
-        node_ids: Vec<String>,
-    },
-    InitOk {},
-    EchoOk {
-        echo: String,
-    },
-    Echo {
-        echo: String,
-    },
+    EchoOk { echo: String },
+    Echo { echo: String },
 }
 
 impl Processor<EchoMessage> for EchoMaelstromNode {
-    fn process(&mut self, msg: Message<EchoMessage>) -> Result<Option<Vec<Message<EchoMessage>>>> {
+    fn process(&mut self, msg: Message<EchoMessage>) -> ProcessResult<EchoMessage> {
         match msg.body.body {
-            EchoMessage::Init {
-                node_id: _,
-                node_ids: _,
-            } => {
-                let reply = Ok(Some(vec![Message {
-                    src: msg.dest,
-                    dest: msg.src,
-                    body: Body {
-                        msg_id: Some(self.id),
-                        in_reply_to: msg.body.msg_id,
-                        body: EchoMessage::InitOk {},
-                    },
-                }]));
-                self.id += 1;
-                reply
-            }
-
-            EchoMessage::Echo { echo } => {
-                let reply = Message {
-                    src: msg.dest,
-                    dest: msg.src,
-                    body: Body {
-                        msg_id: Some(self.id),
-                        in_reply_to: msg.body.msg_id,
-                        body: EchoMessage::EchoOk { echo },
-                    },
+            EchoMessage::Echo { ref echo } => {
+                let echo = if self.base64 {
+                    base64_encode(&base64_decode(echo)?)
+                } else {
+                    echo.clone()
                 };
-                self.id += 1;
-                Ok(Some(vec![reply]))
+                reply(vec![msg.reply_with(None, EchoMessage::EchoOk { echo })])
             }
 
-            _ => Err(anyhow!("Received unknown message: {:?}", msg)),
+            _ => fail(anyhow!("Received unknown message: {:?}", msg)),
         }
     }
+
+    fn capabilities(&self) -> Vec<String> {
+        let features = [("base64", self.base64)];
+        std::iter::once("echo")
+            .chain(features.iter().filter(|(_, on)| *on).map(|(name, _)| *name))
+            .map(Into::into)
+            .collect()
+    }
 }
+
+impl StatelessProcessor<EchoMessage> for EchoMaelstromNode {}
+
+impl Processor<EchoMessage> for BatchingEchoNode {
+    fn process(&mut self, msg: Message<EchoMessage>) -> ProcessResult<EchoMessage> {
+        let Some(echo_oks) = self.node.process(msg)? else {
+            return no_reply();
+        };
+        self.pending.extend(echo_oks);
+        if self.pending.len() < self.batch_size {
+            return no_reply();
+        }
+        reply(std::mem::take(&mut self.pending))
+    }
+
+    fn capabilities(&self) -> Vec<String> {
+        let mut capabilities = self.node.capabilities();
+        if self.batch_size > 1 {
+            capabilities.push("batching".into());
+        }
+        capabilities
+    }
+
+    // an incomplete batch still gets its replies
+    fn on_shutdown(&mut self) -> ProcessResult<EchoMessage> {
+        if self.pending.is_empty() {
+            return no_reply();
+        }
+        reply(std::mem::take(&mut self.pending))
+    }
+}
+
 fn main() -> anyhow::Result<()> {
-    run(&mut EchoMaelstromNode::default())
+    let base64 = std::env::var("ECHO_BASE64").is_ok_and(|v| v == "true");
+    let batch_size = std::env::var("ECHO_BATCH_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1);
+    let mut node = EchoMaelstromNode::default().with_base64(base64);
+    let parallel = std::env::var("ECHO_PARALLEL").is_ok_and(|v| v == "true");
+    if batch_size > 1 {
+        run(&mut node.with_batch_size(batch_size))
+    } else if parallel {
+        run_parallel(&mut node)
+    } else {
+        run(&mut node)
+    }
 }
 
 #[cfg(test)]
@@ -85,74 +174,32 @@ mod tests {
 
     use crate::EchoMaelstromNode;
     use crate::EchoMessage;
+    use crate::{base64_decode, base64_encode};
 
+    use maelstrom_rust::dedup::*;
     use maelstrom_rust::msg_protocol::*;
+    use maelstrom_rust::nemesis::*;
+    use maelstrom_rust::runner::*;
+    use maelstrom_rust::testkit::fixtures::init_line;
 
     use serde_json::from_str;
     use serde_json::to_string;
     mod fixtures {
         use super::*;
 
-        pub fn init_msg() -> Message<EchoMessage> {
-            Message {
-                src: Some("src".to_string()),
-                dest: Some("dest".to_string()),
-                body: Body {
-                    msg_id: Some(1),
-                    in_reply_to: None,
-                    body: EchoMessage::Init {
-                        node_id: "mynode1".to_string(),
-                        node_ids: vec!["mynode1".to_string()],
-                    },
-                },
-            }
-        }
-        pub fn init_ok_msg() -> Message<EchoMessage> {
-            Message {
-                src: Some("dest".to_string()),
-                dest: Some("src".to_string()),
-                body: Body {
-                    msg_id: Some(1),
-                    in_reply_to: Some(1),
-                    body: EchoMessage::InitOk {},
-                },
-            }
-        }
+        use maelstrom_rust::testkit::fixtures::{reply, request};
+
         pub fn echo_msg() -> Message<EchoMessage> {
-            Message {
-                src: Some("src".to_string()),
-                dest: Some("dest".to_string()),
-                body: Body {
-                    msg_id: Some(1),
-                    in_reply_to: None,
-                    body: EchoMessage::Echo {
-                        echo: "echo".to_string(),
-                    },
-                },
-            }
+            request(EchoMessage::Echo {
+                echo: "echo".to_string(),
+            })
         }
         pub fn echo_ok_msg() -> Message<EchoMessage> {
-            Message {
-                src: Some("dest".to_string()),
-                dest: Some("src".to_string()),
-                body: Body {
-                    msg_id: Some(1),
-                    in_reply_to: Some(1),
-                    body: EchoMessage::EchoOk {
-                        echo: "echo".to_string(),
-                    },
-                },
-            }
+            reply(EchoMessage::EchoOk {
+                echo: "echo".to_string(),
+            })
         }
     }
-    #[test]
-    fn test_msg_processing_init() {
-        let mut processor = EchoMaelstromNode::default();
-        let msg = fixtures::init_msg();
-        let reply = processor.process(msg);
-        assert_eq!(reply.unwrap(), Some(vec![fixtures::init_ok_msg()]));
-    }
-
     #[test]
     fn test_msg_processing_echo() {
         let mut processor = EchoMaelstromNode::default();
@@ -176,7 +223,7 @@ mod tests {
     }
 
     #[test]
-    fn test_msg_processor_id_increments_on_every_msg() {
+    fn test_msg_processor_leaves_msg_ids_to_the_runner() {
         let mut processor = EchoMaelstromNode::default();
 
         fn assert_reply_to_msg(
@@ -196,21 +243,203 @@ mod tests {
             msg.body.msg_id = Some(x);
 
             let mut expected_reply = base_reply.clone();
-            expected_reply.body.msg_id = Some(x);
             expected_reply.body.in_reply_to = Some(x);
             assert_reply_to_msg(&mut processor, msg, Some(vec![expected_reply]))
         })
     }
     #[test]
-    fn test_serde_msg_echo() {
-        let msg = fixtures::echo_msg();
-        let msg_serialized = to_string(&msg).unwrap();
-        let msg_round_trip = from_str::<Message<EchoMessage>>(&msg_serialized).unwrap();
-        assert_eq!(msg, msg_round_trip);
+    fn test_drop_every_second_outbound_msg() {
+        let mut processor = DropEveryNth::new(EchoMaelstromNode::default(), 2, Direction::Outbound);
+
+        let replies: Vec<Option<Vec<Message<EchoMessage>>>> = (1..5)
+            .map(|_| processor.process(fixtures::echo_msg()).unwrap())
+            .collect();
+
+        let reply = || Some(vec![fixtures::echo_ok_msg()]);
+        assert_eq!(replies, vec![reply(), Some(vec![]), reply(), Some(vec![])]);
+    }
+
+    #[test]
+    fn test_msg_processing_echo_base64() {
+        let mut processor = EchoMaelstromNode::default().with_base64(true);
+        for payload in [&b""[..], b"\x00", b"\xff\x01", b"bin\x00ary\xfe"] {
+            let echo = base64_encode(payload);
+            let mut msg = fixtures::echo_msg();
+            msg.body.body = EchoMessage::Echo { echo: echo.clone() };
+
+            let reply = processor.process(msg).unwrap().unwrap();
+
+            assert_eq!(
+                reply[0].body.body,
+                EchoMessage::EchoOk { echo: echo.clone() }
+            );
+            assert_eq!(base64_decode(&echo).unwrap(), payload);
+        }
+        assert_eq!(base64_encode(b"hello"), "aGVsbG8=");
+
+        let mut msg = fixtures::echo_msg();
+        msg.body.body = EchoMessage::Echo {
+            echo: "not base64!".into(),
+        };
+        assert!(processor.process(msg).is_err());
+    }
+
+    #[test]
+    fn test_duplicate_echo_is_resent_or_suppressed() {
+        let mut resending = ReplyCache::new(EchoMaelstromNode::default(), DuplicatePolicy::Resend);
+        let first = resending.process(fixtures::echo_msg()).unwrap();
+        assert_eq!(first, Some(vec![fixtures::echo_ok_msg()]));
+        assert_eq!(resending.process(fixtures::echo_msg()).unwrap(), first);
+
+        let mut suppressing =
+            ReplyCache::new(EchoMaelstromNode::default(), DuplicatePolicy::Suppress);
+        assert_eq!(suppressing.process(fixtures::echo_msg()).unwrap(), first);
+        assert_eq!(suppressing.process(fixtures::echo_msg()).unwrap(), None);
+
+        let mut other = fixtures::echo_msg();
+        other.body.msg_id = Some(2);
+        let mut expected = fixtures::echo_ok_msg();
+        expected.body.in_reply_to = Some(2);
+        assert_eq!(suppressing.process(other).unwrap(), Some(vec![expected]));
+    }
+
+    #[test]
+    fn test_msg_processing_batched_echo() {
+        let mut processor = EchoMaelstromNode::default().with_batch_size(3);
+        let echo = |msg_id| {
+            let mut msg = fixtures::echo_msg();
+            msg.body.msg_id = Some(msg_id);
+            msg
+        };
+        let echo_ok = |msg_id| {
+            let mut msg = fixtures::echo_ok_msg();
+            msg.body.in_reply_to = Some(msg_id);
+            msg
+        };
+
+        assert_eq!(processor.process(echo(1)).unwrap(), None);
+        assert_eq!(processor.process(echo(2)).unwrap(), None);
+        assert_eq!(
+            processor.process(echo(3)).unwrap(),
+            Some(vec![echo_ok(1), echo_ok(2), echo_ok(3)])
+        );
+        assert_eq!(processor.on_shutdown().unwrap(), None);
     }
+
+    #[test]
+    fn test_run_flushes_incomplete_batch_on_shutdown() {
+        let mut processor = EchoMaelstromNode::default().with_batch_size(3);
+        let input = [fixtures::echo_msg(), fixtures::echo_msg()]
+            .iter()
+            .map(|msg| to_string(msg).unwrap())
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let mut output = vec![];
+        run_with(
+            &mut processor,
+            input.as_bytes(),
+            &mut output,
+            &RunnerOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap().lines().count(), 2);
+    }
+
     #[test]
-    fn test_serde_msg_init_ok() {
-        let msg = fixtures::init_ok_msg();
+    fn test_run_answers_capabilities_after_init() {
+        let mut processor = EchoMaelstromNode::default().with_base64(true);
+        let capabilities = r#"{"src":"c1","dest":"n1","body":{"type":"capabilities","msg_id":2}}"#;
+        let input = [init_line("n1", &["n1"]).as_str(), capabilities].join("\n");
+
+        let mut output = vec![];
+        run_with(
+            &mut processor,
+            input.as_bytes(),
+            &mut output,
+            &RunnerOptions::default(),
+        )
+        .unwrap();
+
+        let replies: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            replies[1]["body"],
+            serde_json::json!({
+                "type": "capabilities_ok",
+                "msg_id": 2,
+                "in_reply_to": 2,
+                "capabilities": ["echo", "base64"],
+            })
+        );
+    }
+
+    #[test]
+    fn test_wrapped_node_advertises_its_capabilities() {
+        let processor = ReplyCache::new(
+            EchoMaelstromNode::default().with_base64(true),
+            DuplicatePolicy::Resend,
+        );
+
+        assert_eq!(processor.capabilities(), vec!["echo", "base64"]);
+    }
+
+    #[test]
+    fn test_run_parallel_answers_in_order() {
+        let echo = |msg_id: i64| {
+            let mut msg = fixtures::echo_msg();
+            msg.body.msg_id = Some(msg_id);
+            msg.body.body = EchoMessage::Echo {
+                echo: format!("echo {}", msg_id),
+            };
+            to_string(&msg).unwrap()
+        };
+        let input = std::iter::once(init_line("n1", &["n1"]))
+            .chain((1..=100).map(echo))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let options = RunnerOptions {
+            workers: Some(4),
+            ..RunnerOptions::default()
+        };
+
+        let mut output = vec![];
+        let report = run_parallel_with(
+            &mut EchoMaelstromNode::default(),
+            input.as_bytes(),
+            &mut output,
+            &options,
+        )
+        .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let (init_ok, replies) = output.split_once('\n').unwrap();
+        let replies: Vec<Message<EchoMessage>> = replies
+            .lines()
+            .map(|line| from_str(line).unwrap())
+            .collect();
+        assert!(init_ok.contains(r#""type":"init_ok""#));
+        for (msg_id, reply) in (1..=100).zip(&replies) {
+            assert_eq!(reply.body.in_reply_to, Some(msg_id));
+            assert_eq!(
+                reply.body.body,
+                EchoMessage::EchoOk {
+                    echo: format!("echo {}", msg_id)
+                }
+            );
+        }
+        assert_eq!(replies.len(), 100);
+        assert_eq!(report.processed, 101);
+        assert_eq!(report.duplicate_msg_ids, 0);
+    }
+
+    #[test]
+    fn test_serde_msg_echo() {
+        let msg = fixtures::echo_msg();
         let msg_serialized = to_string(&msg).unwrap();
         let msg_round_trip = from_str::<Message<EchoMessage>>(&msg_serialized).unwrap();
         assert_eq!(msg, msg_round_trip);
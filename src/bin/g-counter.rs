@@ -0,0 +1,631 @@
+use anyhow::anyhow;
+use anyhow::Result;
+use maelstrom_rust::msg_protocol::kv::{read_result, KvMessage, SEQ_KV};
+use maelstrom_rust::msg_protocol::*;
+use maelstrom_rust::replying::OrError;
+use maelstrom_rust::rpc::{PendingRpc, RpcRegistry};
+use maelstrom_rust::runner::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+// how long seq-kv gets to answer, the client being answered with a timeout past it
+const KV_TIMEOUT: Duration = Duration::from_secs(1);
+// how often replies from seq-kv are looked at while no message comes in
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+// written to before every read, with a value of its own each time
+const SYNC_KEY: &str = "sync";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum CounterMessage {
+    Add {
+        #[serde(deserialize_with = "large_ints::int")]
+        delta: i64,
+    },
+    AddOk {},
+    Read {},
+    ReadOk {
+        #[serde(deserialize_with = "large_ints::int")]
+        value: i64,
+    },
+}
+
+// what clients and seq-kv send, told apart by their fields: seq-kv's messages are tried
+// first, a client's read having no key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum GCounterMessage {
+    Kv(OrError<KvMessage>),
+    Counter(CounterMessage),
+    // failures answered to clients, errors from seq-kv being read as `Kv`
+    Error(Error),
+}
+
+// where a client request stands, with the requests to seq-kv it awaits
+enum Step {
+    // read of the node's own counter, to add `delta` to it
+    ReadOwn {
+        delta: i64,
+        read: PendingRpc<GCounterMessage>,
+    },
+    // compare-and-swap of the node's own counter from the value read
+    Swap {
+        delta: i64,
+        cas: PendingRpc<GCounterMessage>,
+    },
+    // a write of a value never written before, for the reads following it not to be stale
+    Sync {
+        write: PendingRpc<GCounterMessage>,
+    },
+    // reads of every counter, summed as they come back
+    Sum {
+        reads: Vec<PendingRpc<GCounterMessage>>,
+        sum: i64,
+    },
+}
+
+enum Progress {
+    Waiting(Step),
+    Done(CounterMessage),
+}
+
+struct InProgress {
+    request: Message<()>,
+    step: Step,
+}
+
+// each node keeps its own counter in seq-kv under its node id, only ever adding to it by
+// compare-and-swap from the value it read, reads summing the counters of every node
+#[derive(Default)]
+struct GCounterMaelstromNode {
+    node_id: String,
+    node_ids: Vec<String>,
+    rpcs: RpcRegistry<GCounterMessage>,
+    in_progress: Vec<InProgress>,
+    syncs: u64,
+}
+
+// a message stripped of its body, enough to answer it later
+fn envelope<T>(msg: &Message<T>) -> Message<()> {
+    Message {
+        src: msg.src.clone(),
+        dest: msg.dest.clone(),
+        body: Body {
+            msg_id: msg.body.msg_id,
+            in_reply_to: msg.body.in_reply_to,
+            body: (),
+        },
+    }
+}
+
+fn kv_reply(reply: Result<Message<GCounterMessage>>) -> Result<OrError<KvMessage>> {
+    match reply?.body.body {
+        GCounterMessage::Kv(reply) => Ok(reply),
+        other => Err(anyhow!("Unexpected reply from {} : {:?}", SEQ_KV, other)),
+    }
+}
+
+// a counter never written reads as 0
+fn counter(value: Option<Value>) -> Result<i64> {
+    match value {
+        None => Ok(0),
+        Some(value) => value
+            .as_i64()
+            .ok_or_else(|| anyhow!("Counter isn't an integer : {}", value)),
+    }
+}
+
+// failures carrying an `Error` keep their code, any other one is reported as a crash
+fn failure(e: anyhow::Error) -> Error {
+    e.downcast::<Error>().unwrap_or_else(|e| Error {
+        code: error_codes::CRASH,
+        text: e.to_string(),
+    })
+}
+
+impl GCounterMaelstromNode {
+    fn request_kv(
+        &self,
+        sender: &mut dyn Sender<GCounterMessage>,
+        body: KvMessage,
+    ) -> PendingRpc<GCounterMessage> {
+        let body = GCounterMessage::Kv(OrError::Message(body));
+        self.rpcs
+            .rpc(sender, &self.node_id, SEQ_KV, body, KV_TIMEOUT)
+    }
+
+    fn read_own(&self, sender: &mut dyn Sender<GCounterMessage>, delta: i64) -> Step {
+        let key = json!(self.node_id);
+        let read = self.request_kv(sender, KvMessage::Read { key });
+        Step::ReadOwn { delta, read }
+    }
+
+    // a counter never written is created by the first cas
+    fn swap_own(&self, sender: &mut dyn Sender<GCounterMessage>, delta: i64, from: i64) -> Step {
+        let body = KvMessage::Cas {
+            key: json!(self.node_id),
+            from: json!(from),
+            to: json!(from + delta),
+            create_if_not_exists: from == 0,
+        };
+        let cas = self.request_kv(sender, body);
+        Step::Swap { delta, cas }
+    }
+
+    // seq-kv may serve reads from any state past the node's previous requests: writing first
+    // has them see at least everything before the write
+    fn sync(&mut self, sender: &mut dyn Sender<GCounterMessage>) -> Step {
+        self.syncs += 1;
+        let body = KvMessage::Write {
+            key: json!(SYNC_KEY),
+            value: json!(format!("{}:{}", self.node_id, self.syncs)),
+        };
+        let write = self.request_kv(sender, body);
+        Step::Sync { write }
+    }
+
+    fn read_all(&self, sender: &mut dyn Sender<GCounterMessage>) -> Step {
+        let reads = self
+            .node_ids
+            .iter()
+            .map(|node| self.request_kv(sender, KvMessage::Read { key: json!(node) }))
+            .collect();
+        Step::Sum { reads, sum: 0 }
+    }
+
+    fn advance(
+        &mut self,
+        step: Step,
+        sender: &mut dyn Sender<GCounterMessage>,
+    ) -> Result<Progress> {
+        match step {
+            Step::ReadOwn { delta, read } => {
+                let Some(reply) = read.try_get() else {
+                    return Ok(Progress::Waiting(Step::ReadOwn { delta, read }));
+                };
+                let from = counter(read_result(kv_reply(reply)?)?)?;
+                Ok(Progress::Waiting(self.swap_own(sender, delta, from)))
+            }
+            Step::Swap { delta, cas } => {
+                let Some(reply) = cas.try_get() else {
+                    return Ok(Progress::Waiting(Step::Swap { delta, cas }));
+                };
+                match kv_reply(reply)? {
+                    OrError::Message(KvMessage::CasOk {}) => {
+                        Ok(Progress::Done(CounterMessage::AddOk {}))
+                    }
+                    // the counter changed since it was read, by another add on this node or
+                    // because the read was stale: read it again
+                    OrError::Error(error) if error.code == error_codes::PRECONDITION_FAILED => {
+                        Ok(Progress::Waiting(self.read_own(sender, delta)))
+                    }
+                    OrError::Error(error) => Err(error.into()),
+                    OrError::Message(other) => {
+                        Err(anyhow!("Unexpected reply to cas : {:?}", other))
+                    }
+                }
+            }
+            Step::Sync { write } => {
+                let Some(reply) = write.try_get() else {
+                    return Ok(Progress::Waiting(Step::Sync { write }));
+                };
+                match kv_reply(reply)? {
+                    OrError::Message(KvMessage::WriteOk {}) => {
+                        Ok(Progress::Waiting(self.read_all(sender)))
+                    }
+                    OrError::Error(error) => Err(error.into()),
+                    OrError::Message(other) => {
+                        Err(anyhow!("Unexpected reply to write : {:?}", other))
+                    }
+                }
+            }
+            Step::Sum { reads, mut sum } => {
+                let mut waiting = vec![];
+                // the read fails as a whole, whatever the other counters read
+                for read in reads {
+                    match read.try_get() {
+                        Some(reply) => sum += counter(read_result(kv_reply(reply)?)?)?,
+                        None => waiting.push(read),
+                    }
+                }
+                if waiting.is_empty() {
+                    return Ok(Progress::Done(CounterMessage::ReadOk { value: sum }));
+                }
+                Ok(Progress::Waiting(Step::Sum {
+                    reads: waiting,
+                    sum,
+                }))
+            }
+        }
+    }
+
+    // moves every request in progress on with the replies seq-kv sent since, returning the
+    // answers to the requests done
+    fn poll(&mut self, sender: &mut dyn Sender<GCounterMessage>) -> Vec<Message<GCounterMessage>> {
+        let mut answers = vec![];
+        for InProgress { request, step } in std::mem::take(&mut self.in_progress) {
+            let answer = match self.advance(step, sender) {
+                Ok(Progress::Waiting(step)) => {
+                    self.in_progress.push(InProgress { request, step });
+                    continue;
+                }
+                Ok(Progress::Done(body)) => GCounterMessage::Counter(body),
+                Err(e) => GCounterMessage::Error(failure(e)),
+            };
+            answers.push(request.reply_with(None, answer));
+        }
+        answers
+    }
+}
+
+impl Processor<GCounterMessage> for GCounterMaelstromNode {
+    fn process(
+        &mut self,
+        msg: Message<GCounterMessage>,
+    ) -> Result<Option<Vec<Message<GCounterMessage>>>> {
+        process_collecting(self, msg)
+    }
+
+    // requests to seq-kv go through the sender, their replies to the registry
+    fn process_with_sender(
+        &mut self,
+        msg: Message<GCounterMessage>,
+        sender: &mut dyn Sender<GCounterMessage>,
+    ) -> Result<Option<Vec<Message<GCounterMessage>>>> {
+        let mut answers = vec![];
+        match msg.body.body {
+            GCounterMessage::Counter(CounterMessage::Add { delta: 0 }) => answers
+                .push(msg.reply_with(None, GCounterMessage::Counter(CounterMessage::AddOk {}))),
+            GCounterMessage::Counter(CounterMessage::Add { delta }) => {
+                let step = self.read_own(sender, delta);
+                let request = envelope(&msg);
+                self.in_progress.push(InProgress { request, step });
+            }
+            GCounterMessage::Counter(CounterMessage::Read {}) => {
+                let step = self.sync(sender);
+                let request = envelope(&msg);
+                self.in_progress.push(InProgress { request, step });
+            }
+            // from seq-kv, to a request that timed out meanwhile
+            GCounterMessage::Kv(_) => {}
+            _ => return Err(anyhow!("Received unknown message: {:?}", msg)),
+        }
+        answers.extend(self.poll(sender));
+        Ok((!answers.is_empty()).then_some(answers))
+    }
+
+    fn timers(&self) -> Vec<(&'static str, Duration)> {
+        vec![("poll", POLL_INTERVAL)]
+    }
+
+    fn on_timer(&mut self, _timer: &str) -> Result<Option<Vec<Message<GCounterMessage>>>> {
+        let mut sent = vec![];
+        let mut answers = self.poll(&mut sent);
+        answers.extend(sent);
+        Ok((!answers.is_empty()).then_some(answers))
+    }
+
+    fn use_rpcs(&mut self, rpcs: RpcRegistry<GCounterMessage>) {
+        self.rpcs = rpcs;
+    }
+
+    fn on_init(&mut self, node_id: String, node_ids: Vec<String>) -> Result<()> {
+        self.node_id = node_id;
+        self.node_ids = node_ids;
+        Ok(())
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    run_with_timers(&mut GCounterMaelstromNode::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::CounterMessage;
+    use crate::GCounterMaelstromNode;
+    use crate::GCounterMessage;
+    use maelstrom_rust::msg_protocol::kv::{KvMessage, SEQ_KV};
+    use maelstrom_rust::msg_protocol::*;
+    use maelstrom_rust::replying::OrError;
+    use serde_json::from_str;
+    use serde_json::json;
+    use serde_json::to_string;
+    use serde_json::Value;
+    use std::collections::HashMap;
+
+    mod fixtures {
+        use super::*;
+
+        use maelstrom_rust::testkit::fixtures::{reply, request};
+
+        pub fn add_msg(delta: i64) -> Message<GCounterMessage> {
+            request(GCounterMessage::Counter(CounterMessage::Add { delta }))
+        }
+        pub fn read_msg() -> Message<GCounterMessage> {
+            request(GCounterMessage::Counter(CounterMessage::Read {}))
+        }
+        pub fn read_ok_msg(value: i64) -> Message<GCounterMessage> {
+            reply(GCounterMessage::Counter(CounterMessage::ReadOk { value }))
+        }
+
+        pub fn kv(body: KvMessage) -> GCounterMessage {
+            GCounterMessage::Kv(OrError::Message(body))
+        }
+
+        // a node initialized as `node_id` among n1 and n2
+        pub fn node(node_id: &str) -> GCounterMaelstromNode {
+            let mut node = GCounterMaelstromNode::default();
+            node.on_init(node_id.into(), vec!["n1".into(), "n2".into()])
+                .unwrap();
+            node
+        }
+    }
+
+    mod stubs {
+        use super::*;
+
+        // stands for seq-kv, answering on the spot, keeping every request it got
+        #[derive(Default)]
+        pub struct FakeSeqKv {
+            pub values: HashMap<Value, Value>,
+            pub requests: Vec<KvMessage>,
+        }
+
+        impl FakeSeqKv {
+            pub fn answer(&mut self, msg: &Message<GCounterMessage>) -> Message<GCounterMessage> {
+                let GCounterMessage::Kv(OrError::Message(request)) = &msg.body.body else {
+                    panic!("seq-kv can't answer {:?}", msg);
+                };
+                self.requests.push(request.clone());
+                let error = |code, text: &str| {
+                    OrError::Error(Error {
+                        code,
+                        text: text.into(),
+                    })
+                };
+                let body = match request {
+                    KvMessage::Read { key } => match self.values.get(key) {
+                        Some(value) => OrError::Message(KvMessage::ReadOk {
+                            value: value.clone(),
+                        }),
+                        None => error(error_codes::KEY_DOES_NOT_EXIST, "not found"),
+                    },
+                    KvMessage::Write { key, value } => {
+                        self.values.insert(key.clone(), value.clone());
+                        OrError::Message(KvMessage::WriteOk {})
+                    }
+                    KvMessage::Cas {
+                        key,
+                        from,
+                        to,
+                        create_if_not_exists,
+                    } => match self.values.get(key) {
+                        None if !create_if_not_exists => {
+                            error(error_codes::KEY_DOES_NOT_EXIST, "not found")
+                        }
+                        Some(value) if value != from => {
+                            error(error_codes::PRECONDITION_FAILED, "expected another value")
+                        }
+                        _ => {
+                            self.values.insert(key.clone(), to.clone());
+                            OrError::Message(KvMessage::CasOk {})
+                        }
+                    },
+                    other => panic!("seq-kv can't answer {:?}", other),
+                };
+                Message {
+                    src: Some(SEQ_KV.into()),
+                    dest: msg.src.clone(),
+                    body: Body {
+                        msg_id: None,
+                        in_reply_to: msg.body.msg_id,
+                        body: GCounterMessage::Kv(body),
+                    },
+                }
+            }
+        }
+
+        // hands seq-kv what the node sent it, its answers going to the node's registry as the
+        // runner routes them, until the node has nothing more to send; returns what went to
+        // clients
+        pub fn settle(
+            node: &mut GCounterMaelstromNode,
+            kv: &mut FakeSeqKv,
+            sent: Vec<Message<GCounterMessage>>,
+        ) -> Vec<Message<GCounterMessage>> {
+            let mut sent = sent;
+            let mut to_clients = vec![];
+            while !sent.is_empty() {
+                for msg in sent {
+                    match msg.dest.as_deref() {
+                        Some(SEQ_KV) => assert!(node.rpcs.route(kv.answer(&msg)).is_none()),
+                        _ => to_clients.push(msg),
+                    }
+                }
+                sent = node.on_timer("poll").unwrap().unwrap_or_default();
+            }
+            to_clients
+        }
+
+        pub fn process(
+            node: &mut GCounterMaelstromNode,
+            kv: &mut FakeSeqKv,
+            msg: Message<GCounterMessage>,
+        ) -> Vec<Message<GCounterMessage>> {
+            let sent = node.process(msg).unwrap().unwrap_or_default();
+            settle(node, kv, sent)
+        }
+    }
+
+    #[test]
+    fn test_serde_msg_add() {
+        let msg = fixtures::add_msg(3);
+        let msg_serialized = to_string(&msg).unwrap();
+        let msg_round_trip = from_str::<Message<GCounterMessage>>(&msg_serialized).unwrap();
+        assert_eq!(msg, msg_round_trip);
+    }
+
+    #[test]
+    fn test_serde_msg_read() {
+        let msg = fixtures::read_msg();
+        let msg_serialized = to_string(&msg).unwrap();
+        assert_eq!(
+            msg_serialized,
+            r#"{"src":"src","dest":"dest","body":{"msg_id":1,"type":"read"}}"#
+        );
+        let msg_round_trip = from_str::<Message<GCounterMessage>>(&msg_serialized).unwrap();
+        assert_eq!(msg, msg_round_trip);
+    }
+
+    #[test]
+    fn test_serde_msg_read_ok() {
+        let msg = fixtures::read_ok_msg(42);
+        let msg_serialized = to_string(&msg).unwrap();
+        assert_eq!(
+            msg_serialized,
+            r#"{"src":"dest","dest":"src","body":{"in_reply_to":1,"type":"read_ok","value":42}}"#
+        );
+        // only ever received from seq-kv
+        let msg_read = from_str::<Message<GCounterMessage>>(&msg_serialized).unwrap();
+        assert_eq!(
+            msg_read.body.body,
+            fixtures::kv(KvMessage::ReadOk { value: json!(42) })
+        );
+    }
+
+    #[test]
+    fn test_serde_msg_kv_cas_and_error() {
+        let cas = r#"{"type":"cas","key":"n1","from":0,"to":3,"create_if_not_exists":true}"#;
+        let body = from_str::<GCounterMessage>(cas).unwrap();
+        assert_eq!(
+            body,
+            fixtures::kv(KvMessage::Cas {
+                key: json!("n1"),
+                from: json!(0),
+                to: json!(3),
+                create_if_not_exists: true
+            })
+        );
+        assert_eq!(to_string(&body).unwrap(), cas);
+
+        let error = r#"{"type":"error","code":22,"text":"expected another value"}"#;
+        let body = from_str::<GCounterMessage>(error).unwrap();
+        assert!(matches!(body, GCounterMessage::Kv(OrError::Error(_))));
+        assert_eq!(to_string(&body).unwrap(), error);
+    }
+
+    #[test]
+    fn test_adds_on_every_node_sum_up_on_read() {
+        let mut kv = stubs::FakeSeqKv::default();
+        let mut n1 = fixtures::node("n1");
+        let mut n2 = fixtures::node("n2");
+
+        for (node_id, delta) in [("n1", 2), ("n2", 5), ("n1", 3)] {
+            let node = if node_id == "n1" { &mut n1 } else { &mut n2 };
+            let answers = stubs::process(node, &mut kv, fixtures::add_msg(delta));
+            assert_eq!(answers.len(), 1);
+            assert_eq!(
+                answers[0].body.body,
+                GCounterMessage::Counter(CounterMessage::AddOk {})
+            );
+            assert_eq!(answers[0].body.in_reply_to, Some(1));
+        }
+        // every node keeps its own counter
+        assert_eq!(kv.values[&json!("n1")], json!(5));
+        assert_eq!(kv.values[&json!("n2")], json!(5));
+
+        let answers = stubs::process(&mut n2, &mut kv, fixtures::read_msg());
+        assert_eq!(answers.len(), 1);
+        assert_eq!(
+            answers[0].body.body,
+            GCounterMessage::Counter(CounterMessage::ReadOk { value: 10 })
+        );
+        assert_eq!(answers[0].dest, Some("src".into()));
+        assert!(n2.in_progress.is_empty());
+    }
+
+    #[test]
+    fn test_reads_are_preceded_by_a_unique_write() {
+        let mut kv = stubs::FakeSeqKv::default();
+        let mut node = fixtures::node("n1");
+
+        for _ in 0..2 {
+            stubs::process(&mut node, &mut kv, fixtures::read_msg());
+        }
+
+        let types: Vec<(&str, Option<&Value>)> = kv
+            .requests
+            .iter()
+            .map(|request| match request {
+                KvMessage::Write { value, .. } => ("write", Some(value)),
+                KvMessage::Read { .. } => ("read", None),
+                other => panic!("unexpected request {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                ("write", Some(&json!("n1:1"))),
+                ("read", None),
+                ("read", None),
+                ("write", Some(&json!("n1:2"))),
+                ("read", None),
+                ("read", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_concurrent_adds_retry_failed_cas() {
+        let mut kv = stubs::FakeSeqKv::default();
+        let mut node = fixtures::node("n1");
+
+        // both adds read the counter before either swaps it
+        let mut reads = node.process(fixtures::add_msg(2)).unwrap().unwrap();
+        reads.extend(node.process(fixtures::add_msg(3)).unwrap().unwrap());
+        for read in &reads {
+            assert!(node.rpcs.route(kv.answer(read)).is_none());
+        }
+        let cases = node.on_timer("poll").unwrap().unwrap();
+        assert_eq!(cases.len(), 2);
+
+        let answers = stubs::settle(&mut node, &mut kv, cases);
+        assert_eq!(answers.len(), 2);
+        assert!(answers
+            .iter()
+            .all(|answer| answer.body.body == GCounterMessage::Counter(CounterMessage::AddOk {})));
+        assert_eq!(kv.values[&json!("n1")], json!(5));
+    }
+
+    #[test]
+    fn test_kv_failures_are_answered_to_clients() {
+        let mut node = fixtures::node("n1");
+        let read = node
+            .process(fixtures::add_msg(2))
+            .unwrap()
+            .unwrap()
+            .remove(0);
+        let timeout = Error {
+            code: error_codes::TIMEOUT,
+            text: "timed out".into(),
+        };
+        let failed = Message {
+            src: Some(SEQ_KV.into()),
+            dest: Some("n1".into()),
+            body: Body {
+                msg_id: None,
+                in_reply_to: read.body.msg_id,
+                body: GCounterMessage::Kv(OrError::Error(timeout.clone())),
+            },
+        };
+        assert!(node.rpcs.route(failed).is_none());
+
+        let answers = node.on_timer("poll").unwrap().unwrap();
+        assert_eq!(answers[0].dest, Some("src".into()));
+        assert_eq!(answers[0].body.body, GCounterMessage::Error(timeout));
+        assert!(node.in_progress.is_empty());
+    }
+}
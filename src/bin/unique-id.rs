@@ -1,33 +1,184 @@
 use anyhow::anyhow;
 use anyhow::Result;
+use maelstrom_rust::clock::{Clock, SystemClock};
 use maelstrom_rust::msg_protocol::*;
+use maelstrom_rust::node_id;
 use maelstrom_rust::runner::*;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use uuid::Uuid;
 
 trait IdGenerator {
-    fn generate(&self) -> Uuid;
+    // called with the node identity and the whole cluster once known, on init
+    fn init(&mut self, _node_id: &str, _node_ids: &[String]) {}
+    // fails when the generator can't vouch for the id being unique, e.g. the clock went back
+    fn generate(&mut self) -> Result<String>;
 }
 struct DefaultIdGenerator;
 impl IdGenerator for DefaultIdGenerator {
-    fn generate(&self) -> Uuid {
-        Uuid::new_v4()
+    fn generate(&mut self) -> Result<String> {
+        Ok(Uuid::new_v4().to_string())
     }
 }
+
+// human readable ids made of the node id and a local counter, e.g. "n1-42"
+#[derive(Default)]
+struct PrefixedIdGenerator {
+    node_id: String,
+    counter: u64,
+}
+impl IdGenerator for PrefixedIdGenerator {
+    fn init(&mut self, node_id: &str, _node_ids: &[String]) {
+        self.node_id = node_id.to_string();
+    }
+
+    fn generate(&mut self) -> Result<String> {
+        self.counter += 1;
+        Ok(format!("{}-{}", self.node_id, self.counter))
+    }
+}
+
+// ids made of the node id, the time in milliseconds and a counter within that millisecond,
+// e.g. "n1-1700000000000-0"; fails when the clock goes backwards, ids from before then
+// possibly coming back
+struct TimestampIdGenerator {
+    clock: Box<dyn Clock>,
+    node_id: String,
+    // millisecond of the last id and ids generated in it
+    last: (u64, u64),
+}
+impl TimestampIdGenerator {
+    pub fn new(clock: Box<dyn Clock>) -> Self {
+        Self {
+            clock,
+            node_id: String::new(),
+            last: (0, 0),
+        }
+    }
+}
+impl Default for TimestampIdGenerator {
+    fn default() -> Self {
+        Self::new(Box::new(SystemClock))
+    }
+}
+impl IdGenerator for TimestampIdGenerator {
+    fn init(&mut self, node_id: &str, _node_ids: &[String]) {
+        self.node_id = node_id.to_string();
+    }
+
+    fn generate(&mut self) -> Result<String> {
+        let now = self.clock.now().as_millis() as u64;
+        let (last, count) = self.last;
+        if now < last {
+            return Err(anyhow!("Clock went back from {} to {}", last, now));
+        }
+        let seq = if now == last { count } else { 0 };
+        self.last = (now, seq + 1);
+        Ok(format!("{}-{}-{}", self.node_id, now, seq))
+    }
+}
+
+// which of the `node_ids.len()` partitions of the id space belongs to a node, every node
+// of the cluster must get a different one
+trait PartitionStrategy {
+    fn partition(&self, node_id: &str, node_ids: &[String]) -> Option<usize>;
+}
+
+// rank of the node among the node ids sorted by index, the same on every node whatever the init order
+struct SortedPartitionStrategy;
+impl PartitionStrategy for SortedPartitionStrategy {
+    fn partition(&self, node_id: &str, node_ids: &[String]) -> Option<usize> {
+        let mut sorted: Vec<&String> = node_ids.iter().collect();
+        sorted.sort_by(|a, b| node_id::compare(a, b));
+        sorted.dedup();
+        sorted.iter().position(|n| *n == node_id)
+    }
+}
+
+// numeric ids, node k of n generating k, k + n, k + 2n... so no coordination is needed;
+// a node left out of its own node ids falls back to UUIDs rather than risk a collision
+struct PartitionedIdGenerator {
+    strategy: Box<dyn PartitionStrategy>,
+    // partition index and partition count
+    partition: Option<(u64, u64)>,
+    counter: u64,
+}
+impl PartitionedIdGenerator {
+    pub fn new(strategy: Box<dyn PartitionStrategy>) -> Self {
+        Self {
+            strategy,
+            partition: None,
+            counter: 0,
+        }
+    }
+}
+impl Default for PartitionedIdGenerator {
+    fn default() -> Self {
+        Self::new(Box::new(SortedPartitionStrategy))
+    }
+}
+impl IdGenerator for PartitionedIdGenerator {
+    fn init(&mut self, node_id: &str, node_ids: &[String]) {
+        let partition_count = node_ids.iter().collect::<HashSet<_>>().len() as u64;
+        self.partition = self
+            .strategy
+            .partition(node_id, node_ids)
+            .map(|partition| (partition as u64, partition_count));
+    }
+
+    fn generate(&mut self) -> Result<String> {
+        match self.partition {
+            Some((partition, partition_count)) => {
+                let id = self.counter * partition_count + partition;
+                self.counter += 1;
+                Ok(id.to_string())
+            }
+            None => DefaultIdGenerator.generate(),
+        }
+    }
+}
+
+// ids from `primary` whenever it can generate them, e.g. timestamped ones while the clock
+// doesn't go back, node-partitioned ones otherwise, which need nothing but the init to stay unique; they are prefixed so as not to collide with the primary's
+struct PartitionFallbackIdGenerator {
+    primary: Box<dyn IdGenerator>,
+    fallback: PartitionedIdGenerator,
+}
+impl PartitionFallbackIdGenerator {
+    pub fn new(primary: Box<dyn IdGenerator>) -> Self {
+        Self {
+            primary,
+            fallback: PartitionedIdGenerator::default(),
+        }
+    }
+}
+impl IdGenerator for PartitionFallbackIdGenerator {
+    fn init(&mut self, node_id: &str, node_ids: &[String]) {
+        self.primary.init(node_id, node_ids);
+        self.fallback.init(node_id, node_ids);
+    }
+
+    fn generate(&mut self) -> Result<String> {
+        self.primary.generate().or_else(|e| {
+            eprintln!("Falling back to partitioned ids : {:#}", e);
+            Ok(format!("fallback-{}", self.fallback.generate()?))
+        })
+    }
+}
+
 struct UniqueIdGeneratorMaelstromNode {
-    id: i64,
-    uuid_generator: Box<dyn IdGenerator>,
+    id_generator: Box<dyn IdGenerator>,
 }
 
 impl UniqueIdGeneratorMaelstromNode {
-    pub fn new(id: i64, uuid_generator: Box<dyn IdGenerator>) -> Self {
-        Self { id, uuid_generator }
+    pub fn new(id_generator: Box<dyn IdGenerator>) -> Self {
+        Self { id_generator }
     }
 }
 
 impl Default for UniqueIdGeneratorMaelstromNode {
     fn default() -> Self {
-        Self::new(1, Box::new(DefaultIdGenerator))
+        Self::new(Box::new(DefaultIdGenerator))
     }
 }
 
@@ -35,15 +186,8 @@ impl Default for UniqueIdGeneratorMaelstromNode {
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 pub enum UniqueIdMessage {
-    Init {
-        node_id: String,
-        node_ids: Vec<String>,
-    },
-    InitOk {},
     Generate {},
-    GenerateOk {
-        id: Uuid,
-    },
+    GenerateOk { id: String },
 }
 
 impl Processor<UniqueIdMessage> for UniqueIdGeneratorMaelstromNode {
@@ -52,107 +196,71 @@ impl Processor<UniqueIdMessage> for UniqueIdGeneratorMaelstromNode {
         msg: Message<UniqueIdMessage>,
     ) -> Result<Option<Vec<Message<UniqueIdMessage>>>> {
         match msg.body.body {
-            UniqueIdMessage::Init {
-                node_id: _,
-                node_ids: _,
-            } => {
-                // TODO fix repetition with other nodes
-                let reply = Ok(Some(vec![Message {
-                    src: msg.dest,
-                    dest: msg.src,
-                    body: Body {
-                        msg_id: Some(self.id),
-                        in_reply_to: msg.body.msg_id,
-                        body: UniqueIdMessage::InitOk {},
-                    },
-                }]));
-                self.id += 1;
-                reply
-            }
             UniqueIdMessage::Generate {} => {
-                let reply = Ok(Some(vec![Message {
-                    src: msg.dest,
-                    dest: msg.src,
-                    body: Body {
-                        msg_id: Some(self.id),
-                        in_reply_to: msg.body.msg_id,
-                        body: UniqueIdMessage::GenerateOk {
-                            id: self.uuid_generator.generate(),
-                        },
-                    },
-                }]));
-                self.id += 1;
-                reply
+                let id = self.id_generator.generate()?;
+                reply(vec![
+                    msg.reply_with(None, UniqueIdMessage::GenerateOk { id })
+                ])
             }
             _ => Err(anyhow!("Received unknown message: {:?}", msg)),
         }
     }
+
+    fn on_init(&mut self, node_id: String, node_ids: Vec<String>) -> Result<()> {
+        self.id_generator.init(&node_id, &node_ids);
+        Ok(())
+    }
 }
 fn main() -> anyhow::Result<()> {
-    run(&mut UniqueIdGeneratorMaelstromNode::default())
+    let id_generator: Box<dyn IdGenerator> = match std::env::var("UNIQUE_ID_GENERATOR") {
+        std::result::Result::Ok(generator) if generator == "prefixed" => {
+            Box::new(PrefixedIdGenerator::default())
+        }
+        std::result::Result::Ok(generator) if generator == "partitioned" => {
+            Box::new(PartitionedIdGenerator::default())
+        }
+        std::result::Result::Ok(generator) if generator == "timestamp" => {
+            Box::new(TimestampIdGenerator::default())
+        }
+        _ => Box::new(DefaultIdGenerator),
+    };
+    let id_generator: Box<dyn IdGenerator> =
+        match std::env::var("UNIQUE_ID_PARTITION_FALLBACK").is_ok_and(|v| v == "true") {
+            true => Box::new(PartitionFallbackIdGenerator::new(id_generator)),
+            false => id_generator,
+        };
+    run(&mut UniqueIdGeneratorMaelstromNode::new(id_generator))
 }
 
 #[cfg(test)]
 mod tests {
     use crate::IdGenerator;
+    use crate::PartitionFallbackIdGenerator;
+    use crate::PartitionedIdGenerator;
+    use crate::PrefixedIdGenerator;
+    use crate::TimestampIdGenerator;
     use crate::UniqueIdGeneratorMaelstromNode;
     use crate::UniqueIdMessage;
+    use maelstrom_rust::clock::ManualClock;
     use maelstrom_rust::msg_protocol::*;
+    use maelstrom_rust::replying::*;
     use serde_json::from_str;
     use serde_json::to_string;
+    use std::time::Duration;
     use uuid::Uuid;
 
     mod fixtures {
         use super::*;
 
-        pub fn init_msg() -> Message<UniqueIdMessage> {
-            Message {
-                src: Some("src".to_string()),
-                dest: Some("dest".to_string()),
-                body: Body {
-                    msg_id: Some(1),
-                    in_reply_to: None,
-                    body: UniqueIdMessage::Init {
-                        node_id: "mynode1".to_string(),
-                        node_ids: vec!["mynode1".to_string()],
-                    },
-                },
-            }
-        }
-
-        pub fn init_ok_msg() -> Message<UniqueIdMessage> {
-            Message {
-                src: Some("dest".to_string()),
-                dest: Some("src".to_string()),
-                body: Body {
-                    msg_id: Some(1),
-                    in_reply_to: Some(1),
-                    body: UniqueIdMessage::InitOk {},
-                },
-            }
-        }
+        use maelstrom_rust::testkit::fixtures::{reply, request};
 
         pub fn generate_msg() -> Message<UniqueIdMessage> {
-            Message {
-                src: Some("src".to_string()),
-                dest: Some("dest".to_string()),
-                body: Body {
-                    msg_id: Some(1),
-                    in_reply_to: None,
-                    body: UniqueIdMessage::Generate {},
-                },
-            }
+            request(UniqueIdMessage::Generate {})
         }
         pub fn generate_ok_msg(uuid: Uuid) -> Message<UniqueIdMessage> {
-            Message {
-                src: Some("dest".to_string()),
-                dest: Some("src".to_string()),
-                body: Body {
-                    msg_id: Some(1),
-                    in_reply_to: Some(1),
-                    body: UniqueIdMessage::GenerateOk { id: uuid },
-                },
-            }
+            reply(UniqueIdMessage::GenerateOk {
+                id: uuid.to_string(),
+            })
         }
     }
 
@@ -168,20 +276,12 @@ mod tests {
             }
         }
         impl IdGenerator for FakeIdGenerator {
-            fn generate(&self) -> Uuid {
-                self.uuid
+            fn generate(&mut self) -> anyhow::Result<String> {
+                Ok(self.uuid.to_string())
             }
         }
     }
 
-    #[test]
-    fn test_msg_processing_init() {
-        let mut processor = UniqueIdGeneratorMaelstromNode::default();
-        let msg = fixtures::init_msg();
-        let reply = processor.process(msg);
-        assert_eq!(reply.unwrap(), Some(vec![fixtures::init_ok_msg()]));
-    }
-
     #[test]
     fn test_serde_msg_generate() {
         let msg = fixtures::generate_msg();
@@ -205,7 +305,7 @@ mod tests {
 
         let uuid = Uuid::new_v4();
         let id_generator = FakeIdGenerator::new(uuid);
-        let mut processor = UniqueIdGeneratorMaelstromNode::new(1, Box::new(id_generator));
+        let mut processor = UniqueIdGeneratorMaelstromNode::new(Box::new(id_generator));
         let msg = fixtures::generate_msg();
 
         let reply = processor.process(msg);
@@ -213,6 +313,109 @@ mod tests {
         assert_eq!(reply.unwrap(), Some(vec![fixtures::generate_ok_msg(uuid)]));
     }
 
+    #[test]
+    fn test_msg_processing_generate_with_prefixed_ids() {
+        let mut processor =
+            UniqueIdGeneratorMaelstromNode::new(Box::new(PrefixedIdGenerator::default()));
+        processor
+            .on_init("mynode1".into(), vec!["mynode1".into()])
+            .unwrap();
+
+        let ids: Vec<String> = (0..2)
+            .map(|_| {
+                let reply = processor
+                    .process(fixtures::generate_msg())
+                    .unwrap()
+                    .unwrap();
+                match &reply[0].body.body {
+                    UniqueIdMessage::GenerateOk { id } => id.clone(),
+                    other => panic!("Expected generate_ok: {:?}", other),
+                }
+            })
+            .collect();
+
+        assert_eq!(ids, vec!["mynode1-1", "mynode1-2"]);
+    }
+
+    #[test]
+    fn test_partitioned_ids_are_disjoint_across_nodes() {
+        let node_ids: Vec<String> = vec!["n3".into(), "n1".into(), "n2".into()];
+        let ids: Vec<Vec<String>> = node_ids
+            .iter()
+            .map(|node_id| {
+                let mut id_generator = PartitionedIdGenerator::default();
+                id_generator.init(node_id, &node_ids);
+                (0..3).map(|_| id_generator.generate().unwrap()).collect()
+            })
+            .collect();
+
+        assert_eq!(
+            ids,
+            vec![
+                vec!["2", "5", "8"],
+                vec!["0", "3", "6"],
+                vec!["1", "4", "7"]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_timestamp_ids_fail_when_the_clock_goes_back() {
+        let clock = ManualClock::default();
+        clock.set(Duration::from_millis(10));
+        let mut id_generator = TimestampIdGenerator::new(Box::new(clock.clone()));
+        id_generator.init("n1", &["n1".into()]);
+        assert_eq!(id_generator.generate().unwrap(), "n1-10-0");
+        assert_eq!(id_generator.generate().unwrap(), "n1-10-1");
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(id_generator.generate().unwrap(), "n1-11-0");
+
+        clock.set(Duration::from_millis(5));
+        let err = id_generator.generate().unwrap_err();
+        assert_eq!(err.to_string(), "Clock went back from 11 to 5");
+        let mut processor = UniqueIdGeneratorMaelstromNode::new(Box::new(id_generator));
+        assert!(processor.process(fixtures::generate_msg()).is_err());
+    }
+
+    #[test]
+    fn test_partition_fallback_when_the_clock_goes_back() {
+        let node_ids: Vec<String> = vec!["n1".into(), "n2".into()];
+        let clock = ManualClock::default();
+        clock.set(Duration::from_millis(10));
+        let mut id_generators: Vec<PartitionFallbackIdGenerator> = node_ids
+            .iter()
+            .map(|node_id| {
+                let mut id_generator = PartitionFallbackIdGenerator::new(Box::new(
+                    TimestampIdGenerator::new(Box::new(clock.clone())),
+                ));
+                id_generator.init(node_id, &node_ids);
+                id_generator
+            })
+            .collect();
+        let mut generate = |clock_ms: u64| -> Vec<String> {
+            clock.set(Duration::from_millis(clock_ms));
+            id_generators
+                .iter_mut()
+                .map(|id_generator| id_generator.generate().unwrap())
+                .collect()
+        };
+
+        let ids = [generate(10), generate(5), generate(7), generate(11)].concat();
+        assert_eq!(
+            ids,
+            vec![
+                "n1-10-0",
+                "n2-10-0",
+                "fallback-0",
+                "fallback-1",
+                "fallback-2",
+                "fallback-3",
+                "n1-11-0",
+                "n2-11-0"
+            ]
+        );
+    }
+
     #[test]
     fn test_msg_processing_unhandled_generate_ok() {
         let mut processor = UniqueIdGeneratorMaelstromNode::default();
@@ -227,13 +430,70 @@ mod tests {
     }
 
     #[test]
-    fn test_msg_processor_id_increments_on_every_msg() {
+    fn test_error_replying_answers_unhandled_msg_with_error() {
+        let mut processor = ErrorReplying::new(UniqueIdGeneratorMaelstromNode::default());
+        let mut msg = fixtures::generate_ok_msg(Uuid::new_v4());
+        msg.body.msg_id = Some(1);
+        let expected_text = format!("Received unknown message: {:?}", &msg);
+        let msg = Message {
+            src: msg.src,
+            dest: msg.dest,
+            body: Body {
+                msg_id: msg.body.msg_id,
+                in_reply_to: msg.body.in_reply_to,
+                body: OrError::Message(msg.body.body),
+            },
+        };
+
+        let reply = processor.process(msg).unwrap();
+
+        assert_eq!(
+            reply,
+            Some(vec![Message {
+                src: Some("src".into()),
+                dest: Some("dest".into()),
+                body: Body {
+                    msg_id: None,
+                    in_reply_to: Some(1),
+                    body: OrError::Error(Error {
+                        code: error_codes::CRASH,
+                        text: expected_text,
+                    }),
+                },
+            }])
+        );
+    }
+
+    #[test]
+    fn test_serde_msg_error_or_message() {
+        let generate: Message<OrError<UniqueIdMessage>> =
+            from_str(r#"{"src":"c1","dest":"n1","body":{"type":"generate","msg_id":1}}"#).unwrap();
+        assert_eq!(
+            generate.body.body,
+            OrError::Message(UniqueIdMessage::Generate {})
+        );
+
+        let error: Message<OrError<UniqueIdMessage>> = from_str(
+            r#"{"src":"c1","dest":"n1","body":{"type":"error","in_reply_to":1,"code":13,"text":"crash"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            error.body.body,
+            OrError::Error(Error {
+                code: error_codes::CRASH,
+                text: "crash".into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_msg_processor_leaves_msg_ids_to_the_runner() {
         use stubs::FakeIdGenerator;
 
         // TODO extract this type of test to shared test utils
         let uuid = Uuid::new_v4();
         let id_generator = FakeIdGenerator::new(uuid);
-        let mut processor = UniqueIdGeneratorMaelstromNode::new(1, Box::new(id_generator));
+        let mut processor = UniqueIdGeneratorMaelstromNode::new(Box::new(id_generator));
 
         fn assert_reply_to_msg(
             processor: &mut UniqueIdGeneratorMaelstromNode,
@@ -253,7 +513,6 @@ mod tests {
             msg.body.msg_id = Some(x);
 
             let mut expected_reply = base_reply.clone();
-            expected_reply.body.msg_id = Some(x);
             expected_reply.body.in_reply_to = Some(x);
             assert_reply_to_msg(&mut processor, msg, Some(vec![expected_reply]))
         })
@@ -0,0 +1,100 @@
+use anyhow::{Context, Result};
+
+use crate::msg_protocol::*;
+
+/// What a history is replayed through, e.g. a counter summing the adds seen so far.
+pub trait Model<MessageType> {
+    fn apply(&mut self, msg: &Message<MessageType>);
+}
+
+/// Replays a captured history through `model` one message at a time, checking `invariant`
+/// against the model after every step. Fails on the first step breaking it, telling which.
+pub fn check_history<MessageType, M, F>(
+    messages: &[Message<MessageType>],
+    model: &mut M,
+    mut invariant: F,
+) -> Result<()>
+where
+    M: Model<MessageType>,
+    F: FnMut(&M, &Message<MessageType>) -> Result<()>,
+{
+    for (step, msg) in messages.iter().enumerate() {
+        model.apply(msg);
+        invariant(model, msg).with_context(|| {
+            format!(
+                "Invariant violated at step {} by {}",
+                step,
+                msg.correlation_id()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[test]
+    fn test_check_history_detects_invariant_violation() {
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        #[serde(tag = "type", rename_all = "snake_case")]
+        enum CounterMessage {
+            Add { delta: i64 },
+            ReadOk { value: i64 },
+        }
+
+        // reads of a grow-only counter, each expected no lower than the one before
+        #[derive(Default)]
+        struct Reads {
+            highest: i64,
+            last: i64,
+        }
+
+        impl Model<CounterMessage> for Reads {
+            fn apply(&mut self, msg: &Message<CounterMessage>) {
+                if let CounterMessage::ReadOk { value } = msg.body.body {
+                    self.highest = self.highest.max(self.last);
+                    self.last = value;
+                }
+            }
+        }
+
+        let msg = |msg_id, body| Message {
+            src: Some("n1".to_string()),
+            dest: Some("c1".to_string()),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                body,
+            },
+        };
+        let monotonic = |reads: &Reads, _: &Message<CounterMessage>| {
+            anyhow::ensure!(
+                reads.last >= reads.highest,
+                "read {} after {}",
+                reads.last,
+                reads.highest
+            );
+            Ok(())
+        };
+        let mut history = vec![
+            msg(1, CounterMessage::Add { delta: 2 }),
+            msg(2, CounterMessage::ReadOk { value: 2 }),
+            msg(3, CounterMessage::Add { delta: 1 }),
+            msg(4, CounterMessage::ReadOk { value: 3 }),
+        ];
+
+        assert!(check_history(&history, &mut Reads::default(), monotonic).is_ok());
+
+        // a read going back in time
+        history.push(msg(5, CounterMessage::ReadOk { value: 1 }));
+        let violation = check_history(&history, &mut Reads::default(), monotonic).unwrap_err();
+        assert_eq!(
+            format!("{:#}", violation),
+            "Invariant violated at step 4 by n1:5: read 1 after 3"
+        );
+    }
+}
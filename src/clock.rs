@@ -0,0 +1,65 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Source of time for nodes, so tests can control it.
+pub trait Clock {
+    // time elapsed since the unix epoch
+    fn now(&self) -> Duration;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// Clock only moving when told to, every clone reading the same time.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    now: Rc<Cell<Duration>>,
+}
+
+impl ManualClock {
+    pub fn set(&self, now: Duration) {
+        self.now.set(now);
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now.set(self.now.get() + by);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        self.now.get()
+    }
+}
+
+/// Clock running a fixed number of milliseconds ahead of another one, behind when negative.
+pub struct SkewedClock<C> {
+    clock: C,
+    offset_ms: i64,
+}
+
+impl<C: Clock> SkewedClock<C> {
+    pub fn new(clock: C, offset_ms: i64) -> Self {
+        Self { clock, offset_ms }
+    }
+}
+
+impl<C: Clock> Clock for SkewedClock<C> {
+    // never before the epoch
+    fn now(&self) -> Duration {
+        let offset = Duration::from_millis(self.offset_ms.unsigned_abs());
+        if self.offset_ms < 0 {
+            self.clock.now().saturating_sub(offset)
+        } else {
+            self.clock.now() + offset
+        }
+    }
+}
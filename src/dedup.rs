@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::msg_protocol::*;
+
+/// What to do with a request processed before, as told by its src and msg_id.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicatePolicy {
+    // send the answer again, in case the first one was lost
+    Resend,
+    // send nothing, the client may well have the answer already
+    Suppress,
+}
+
+/// Wraps a processor so that each request is processed at most once, answers being cached
+/// by requester and msg_id. Messages without a src or a msg_id are always processed, as are
+/// those whose processing failed. The cache is never evicted, it grows with the run.
+pub struct ReplyCache<MessageType, P> {
+    processor: P,
+    policy: DuplicatePolicy,
+    answers: HashMap<(String, i64), Vec<Message<MessageType>>>,
+}
+
+impl<MessageType, P> ReplyCache<MessageType, P> {
+    pub fn new(processor: P, policy: DuplicatePolicy) -> Self {
+        Self {
+            processor,
+            policy,
+            answers: HashMap::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> P {
+        self.processor
+    }
+}
+
+impl<MessageType, P> Processor<MessageType> for ReplyCache<MessageType, P>
+where
+    MessageType: Clone,
+    P: Processor<MessageType>,
+{
+    fn process(&mut self, msg: Message<MessageType>) -> Result<Option<Vec<Message<MessageType>>>> {
+        let key = match (&msg.src, msg.body.msg_id) {
+            (Some(src), Some(msg_id)) => (src.clone(), msg_id),
+            _ => return process_collecting(&mut self.processor, msg),
+        };
+        if let Some(answers) = self.answers.get(&key) {
+            return Ok(match self.policy {
+                DuplicatePolicy::Resend => Some(answers.clone()),
+                DuplicatePolicy::Suppress => None,
+            });
+        }
+        let replies = process_collecting(&mut self.processor, msg)?;
+        // only the answers go out again, not what the request made the node send elsewhere
+        let answers = replies
+            .iter()
+            .flatten()
+            .filter(|reply| reply.dest.as_ref() == Some(&key.0))
+            .filter(|reply| reply.body.in_reply_to == Some(key.1))
+            .cloned()
+            .collect();
+        self.answers.insert(key, answers);
+        Ok(replies)
+    }
+
+    delegate_hooks!(processor => all);
+}
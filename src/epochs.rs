@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{Clock, SystemClock};
+use crate::msg_protocol::*;
+use crate::node_id::NodeId;
+use crate::rpc::RpcRegistry;
+
+/// Body of a workload message along with the epoch of the node that sent it, if any.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Epoched<MessageType> {
+    #[serde(flatten)]
+    pub body: MessageType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub epoch: Option<u64>,
+}
+
+/// Wraps a processor so that what it sends other nodes carries its epoch, and messages from
+/// an epoch of a peer older than the last one seen are dropped, as stale leftovers from
+/// before the peer restarted. The epoch moves on at every init, to the time in milliseconds
+/// or past the previous epoch, so it also grows across restarts of the process.
+pub struct EpochTagging<MessageType, P> {
+    processor: P,
+    // the processor's own, the runner only routing replies to requests of the wrapper's type
+    rpcs: RpcRegistry<MessageType>,
+    clock: Box<dyn Clock>,
+    epoch: u64,
+    // last epoch seen of every peer
+    peer_epochs: HashMap<String, u64>,
+}
+
+impl<MessageType, P> EpochTagging<MessageType, P> {
+    pub fn new(processor: P) -> Self {
+        Self {
+            processor,
+            rpcs: RpcRegistry::default(),
+            clock: Box::new(SystemClock),
+            epoch: 0,
+            peer_epochs: HashMap::new(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Box<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn into_inner(self) -> P {
+        self.processor
+    }
+
+    fn tag(
+        &self,
+        replies: Option<Vec<Message<MessageType>>>,
+    ) -> Option<Vec<Message<Epoched<MessageType>>>> {
+        replies.map(|replies| {
+            replies
+                .into_iter()
+                .map(|reply| {
+                    let to_node = is_node(&reply.dest);
+                    Message {
+                        src: reply.src,
+                        dest: reply.dest,
+                        body: Body {
+                            msg_id: reply.body.msg_id,
+                            in_reply_to: reply.body.in_reply_to,
+                            body: Epoched {
+                                body: reply.body.body,
+                                epoch: to_node.then_some(self.epoch),
+                            },
+                        },
+                    }
+                })
+                .collect()
+        })
+    }
+}
+
+fn is_node(node: &Option<String>) -> bool {
+    node.as_ref()
+        .is_some_and(|node| node.parse::<NodeId>().is_ok())
+}
+
+impl<MessageType, P> Processor<Epoched<MessageType>> for EpochTagging<MessageType, P>
+where
+    P: Processor<MessageType>,
+{
+    fn process(
+        &mut self,
+        msg: Message<Epoched<MessageType>>,
+    ) -> Result<Option<Vec<Message<Epoched<MessageType>>>>> {
+        if let (Some(src), Some(epoch)) = (msg.src.as_ref(), msg.body.body.epoch) {
+            let last_seen = self.peer_epochs.entry(src.clone()).or_default();
+            if epoch < *last_seen {
+                eprintln!(
+                    "[{}] Ignored message from epoch {} of {}, past {}",
+                    msg.correlation_id(),
+                    epoch,
+                    src,
+                    last_seen
+                );
+                return Ok(None);
+            }
+            *last_seen = epoch;
+        }
+        let msg = Message {
+            src: msg.src,
+            dest: msg.dest,
+            body: Body {
+                msg_id: msg.body.msg_id,
+                in_reply_to: msg.body.in_reply_to,
+                body: msg.body.body.body,
+            },
+        };
+        let Some(msg) = self.rpcs.route(msg) else {
+            return Ok(None);
+        };
+        let replies = process_collecting(&mut self.processor, msg)?;
+        Ok(self.tag(replies))
+    }
+
+    fn on_init(&mut self, node_id: String, node_ids: Vec<String>) -> Result<()> {
+        let now = self.clock.now().as_millis() as u64;
+        self.epoch = now.max(self.epoch + 1);
+        self.processor.on_init(node_id, node_ids)
+    }
+
+    delegate_hooks!(processor => untyped);
+
+    fn use_rpcs(&mut self, rpcs: RpcRegistry<Epoched<MessageType>>) {
+        self.rpcs = rpcs.fork();
+        self.processor.use_rpcs(self.rpcs.clone())
+    }
+
+    fn on_shutdown(&mut self) -> Result<Option<Vec<Message<Epoched<MessageType>>>>> {
+        let replies = self.processor.on_shutdown()?;
+        Ok(self.tag(replies))
+    }
+
+    fn on_timer(&mut self, timer: &str) -> Result<Option<Vec<Message<Epoched<MessageType>>>>> {
+        let replies = self.processor.on_timer(timer)?;
+        Ok(self.tag(replies))
+    }
+}
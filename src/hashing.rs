@@ -0,0 +1,202 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// Hash function consistent hashing places nodes and keys on its ring with. Every strategy is
+/// seeded, so that rings are reproducible across runs, and nodes sharing the same strategy and
+/// seed agree on where anything lands.
+pub trait RingHasher {
+    fn hasher(&self) -> Box<dyn Hasher>;
+}
+
+/// Hash of `key` on the ring of `ring_hasher`.
+pub fn ring_hash(ring_hasher: &dyn RingHasher, key: &impl Hash) -> u64 {
+    let mut hasher = ring_hasher.hasher();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Strategy named `name` (`default`, `fnv` or `xxhash`), none for any other name.
+pub fn ring_hasher(name: &str, seed: u64) -> Option<Box<dyn RingHasher>> {
+    match name {
+        "default" => Some(Box::new(SipRingHasher { seed })),
+        "fnv" => Some(Box::new(FnvRingHasher { seed })),
+        "xxhash" => Some(Box::new(XxRingHasher { seed })),
+        _ => None,
+    }
+}
+
+/// Std's default hasher, the seed being hashed ahead of the key. Its algorithm isn't
+/// guaranteed to stay the same across Rust releases.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SipRingHasher {
+    pub seed: u64,
+}
+
+impl RingHasher for SipRingHasher {
+    fn hasher(&self) -> Box<dyn Hasher> {
+        let mut hasher = DefaultHasher::new();
+        hasher.write_u64(self.seed);
+        Box::new(hasher)
+    }
+}
+
+/// 64 bits FNV-1a, the seed being hashed ahead of the key. Cheap, but keys differing in
+/// their last bytes only (e.g. consecutive integers) spread less evenly over the ring.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FnvRingHasher {
+    pub seed: u64,
+}
+
+impl RingHasher for FnvRingHasher {
+    fn hasher(&self) -> Box<dyn Hasher> {
+        let mut hasher = Fnv1a(0xcbf2_9ce4_8422_2325);
+        hasher.write_u64(self.seed);
+        Box::new(hasher)
+    }
+}
+
+struct Fnv1a(u64);
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// XXH64 with the seed as its own.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XxRingHasher {
+    pub seed: u64,
+}
+
+impl RingHasher for XxRingHasher {
+    fn hasher(&self) -> Box<dyn Hasher> {
+        Box::new(Xxh64 {
+            seed: self.seed,
+            input: Vec::new(),
+        })
+    }
+}
+
+const PRIME64_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME64_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME64_3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME64_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME64_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+// keys are small, so the input is buffered and hashed in one go when finishing
+struct Xxh64 {
+    seed: u64,
+    input: Vec<u8>,
+}
+
+fn xxh64_round(acc: u64, lane: u64) -> u64 {
+    acc.wrapping_add(lane.wrapping_mul(PRIME64_2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME64_1)
+}
+
+fn xxh64_merge(acc: u64, lane: u64) -> u64 {
+    (acc ^ xxh64_round(0, lane))
+        .wrapping_mul(PRIME64_1)
+        .wrapping_add(PRIME64_4)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+impl Hasher for Xxh64 {
+    fn write(&mut self, bytes: &[u8]) {
+        self.input.extend_from_slice(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        let seed = self.seed;
+        let mut input = self.input.as_slice();
+        let mut hash = if input.len() >= 32 {
+            let mut lanes = [
+                seed.wrapping_add(PRIME64_1).wrapping_add(PRIME64_2),
+                seed.wrapping_add(PRIME64_2),
+                seed,
+                seed.wrapping_sub(PRIME64_1),
+            ];
+            while input.len() >= 32 {
+                for (i, lane) in lanes.iter_mut().enumerate() {
+                    *lane = xxh64_round(*lane, read_u64(&input[i * 8..]));
+                }
+                input = &input[32..];
+            }
+            let hash = lanes[0]
+                .rotate_left(1)
+                .wrapping_add(lanes[1].rotate_left(7))
+                .wrapping_add(lanes[2].rotate_left(12))
+                .wrapping_add(lanes[3].rotate_left(18));
+            lanes
+                .iter()
+                .fold(hash, |hash, lane| xxh64_merge(hash, *lane))
+        } else {
+            seed.wrapping_add(PRIME64_5)
+        };
+        hash = hash.wrapping_add(self.input.len() as u64);
+
+        while input.len() >= 8 {
+            hash ^= xxh64_round(0, read_u64(input));
+            hash = hash
+                .rotate_left(27)
+                .wrapping_mul(PRIME64_1)
+                .wrapping_add(PRIME64_4);
+            input = &input[8..];
+        }
+        if input.len() >= 4 {
+            hash ^= u64::from(read_u32(input)).wrapping_mul(PRIME64_1);
+            hash = hash
+                .rotate_left(23)
+                .wrapping_mul(PRIME64_2)
+                .wrapping_add(PRIME64_3);
+            input = &input[4..];
+        }
+        for byte in input {
+            hash ^= u64::from(*byte).wrapping_mul(PRIME64_5);
+            hash = hash.rotate_left(11).wrapping_mul(PRIME64_1);
+        }
+
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(PRIME64_2);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(PRIME64_3);
+        hash ^ (hash >> 32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xxhash_ring_hasher_matches_reference_digests() {
+        let xxh64 = |input: &[u8]| {
+            let mut hasher = XxRingHasher { seed: 0 }.hasher();
+            hasher.write(input);
+            hasher.finish()
+        };
+        assert_eq!(xxh64(b""), 0xEF46_DB37_51D8_E999);
+        assert_eq!(xxh64(b"a"), 0xD24E_C4F1_A98C_6E5B);
+        assert_eq!(xxh64(b"abc"), 0x44BC_2CF5_AD77_0999);
+        // long enough for the four lanes
+        assert_eq!(
+            xxh64(b"Nobody inspects the spammish repetition"),
+            0xFBCE_A83C_8A37_8BF1
+        );
+    }
+}
@@ -0,0 +1,413 @@
+use serde::ser::{self, Serialize};
+use serde_json::{Error, Map, Value};
+
+/// `value` as JSON, whatever fails to serialize in it becoming null rather than failing the
+/// whole: a field, a sequence item or a map entry, e.g. a map keyed by something JSON can't
+/// have as keys. Non-finite floats are null too, as serde_json always writes them.
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Value {
+    value.serialize(Lenient).unwrap_or(Value::Null)
+}
+
+// serializes like `serde_json::value::Serializer`, except that every item of a sequence, map
+// or struct is serialized on its own, the ones failing becoming null
+struct Lenient;
+
+fn tagged(variant: &str, value: Value) -> Value {
+    let mut map = Map::new();
+    map.insert(variant.into(), value);
+    Value::Object(map)
+}
+
+// keys as serde_json writes them, strings, numbers and booleans being the only ones JSON can
+// have
+fn key<T: Serialize + ?Sized>(key: &T) -> Result<String, Error> {
+    match key.serialize(Lenient)? {
+        Value::String(key) => Ok(key),
+        key @ (Value::Number(_) | Value::Bool(_)) => Ok(key.to_string()),
+        key => Err(ser::Error::custom(format!(
+            "Key must be a string : {}",
+            key
+        ))),
+    }
+}
+
+impl ser::Serializer for Lenient {
+    type Ok = Value;
+    type Error = Error;
+    type SerializeSeq = Seq;
+    type SerializeTuple = Seq;
+    type SerializeTupleStruct = Seq;
+    type SerializeTupleVariant = Seq;
+    type SerializeMap = Object;
+    type SerializeStruct = Object;
+    type SerializeStructVariant = Object;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+        Ok(Value::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+        Ok(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+        Ok(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+        Ok(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+        Ok(v.into())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Value, Error> {
+        serde_json::value::Serializer.serialize_i128(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+        Ok(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+        Ok(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+        Ok(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+        Ok(v.into())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Value, Error> {
+        serde_json::value::Serializer.serialize_u128(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value, Error> {
+        Ok(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value, Error> {
+        Ok(v.into())
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value, Error> {
+        Ok(Value::String(v.into()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value, Error> {
+        Ok(v.into())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> {
+        Ok(v.into())
+    }
+
+    fn serialize_none(self) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Value, Error> {
+        Ok(variant.into())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        Ok(tagged(variant, to_value(value)))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Seq, Error> {
+        Ok(Seq {
+            variant: None,
+            items: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Seq, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Seq, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Seq, Error> {
+        Ok(Seq {
+            variant: Some(variant),
+            items: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Object, Error> {
+        Ok(Object::default())
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Object, Error> {
+        Ok(Object::default())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Object, Error> {
+        Ok(Object {
+            variant: Some(variant),
+            ..Object::default()
+        })
+    }
+}
+
+struct Seq {
+    variant: Option<&'static str>,
+    items: Vec<Value>,
+}
+
+impl Seq {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.items.push(to_value(value));
+        Ok(())
+    }
+
+    fn done(self) -> Result<Value, Error> {
+        let items = Value::Array(self.items);
+        Ok(match self.variant {
+            Some(variant) => tagged(variant, items),
+            None => items,
+        })
+    }
+}
+
+impl ser::SerializeSeq for Seq {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.done()
+    }
+}
+
+impl ser::SerializeTuple for Seq {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.done()
+    }
+}
+
+impl ser::SerializeTupleStruct for Seq {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.done()
+    }
+}
+
+impl ser::SerializeTupleVariant for Seq {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.done()
+    }
+}
+
+// a map key failing fails the whole map, the entry having nowhere to go
+#[derive(Default)]
+struct Object {
+    variant: Option<&'static str>,
+    fields: Map<String, Value>,
+    next_key: Option<String>,
+}
+
+impl Object {
+    fn insert<T: Serialize + ?Sized>(&mut self, key: String, value: &T) -> Result<(), Error> {
+        self.fields.insert(key, to_value(value));
+        Ok(())
+    }
+
+    fn done(self) -> Result<Value, Error> {
+        let fields = Value::Object(self.fields);
+        Ok(match self.variant {
+            Some(variant) => tagged(variant, fields),
+            None => fields,
+        })
+    }
+}
+
+impl ser::SerializeMap for Object {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.next_key = Some(self::key(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .next_key
+            .take()
+            .ok_or_else(|| ser::Error::custom("Value serialized before its key"))?;
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.done()
+    }
+}
+
+impl ser::SerializeStruct for Object {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.insert(key.into(), value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.done()
+    }
+}
+
+impl ser::SerializeStructVariant for Object {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.insert(key.into(), value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.done()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde::Serialize;
+    use serde_json::json;
+
+    use super::to_value;
+
+    #[test]
+    fn test_to_value_nulls_only_what_fails() {
+        #[derive(Serialize)]
+        struct Roots {
+            value: f64,
+            by_pair: HashMap<(i64, i64), f64>,
+            by_name: HashMap<String, f64>,
+            nested: Vec<HashMap<Vec<i64>, f64>>,
+        }
+        let roots = Roots {
+            value: f64::NAN,
+            by_pair: HashMap::from([((1, 1), 1.0)]),
+            by_name: HashMap::from([("one".into(), 1.0)]),
+            nested: vec![HashMap::from([(vec![4], 2.0)]), HashMap::new()],
+        };
+        assert!(serde_json::to_value(&roots).is_err());
+        assert_eq!(
+            to_value(&roots),
+            json!({"value": null, "by_pair": null, "by_name": {"one": 1.0}, "nested": [null, {}]})
+        );
+    }
+
+    #[test]
+    fn test_to_value_matches_serde_json_otherwise() {
+        #[derive(Serialize)]
+        #[serde(tag = "type", rename_all = "snake_case")]
+        enum Msg {
+            Add {
+                delta: i64,
+                keys: HashMap<i64, bool>,
+            },
+        }
+        #[derive(Serialize)]
+        enum Plain {
+            Unit,
+            Pair(i64, i64),
+            Named { x: i64 },
+        }
+        let add = Msg::Add {
+            delta: 3,
+            keys: HashMap::from([(7, true)]),
+        };
+        let values = (
+            add,
+            Plain::Unit,
+            Plain::Pair(1, 2),
+            Plain::Named { x: 1 },
+            ('c', vec![1u8]),
+        );
+        assert_eq!(to_value(&values), serde_json::to_value(&values).unwrap());
+    }
+}
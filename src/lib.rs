@@ -1,4 +1,6 @@
-use std::io::StdoutLock;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
 use std::io::Write;
 
 use anyhow::Context;
@@ -6,10 +8,136 @@ use anyhow::Context;
 use anyhow::Ok;
 use anyhow::Result;
 
+// Implements, inside a wrapper's `impl Processor`, the hooks it leaves to the processor it
+// wraps in `$field`, by name or by group: `untyped` for those not involving the message type,
+// `init` for `on_init`, and `typed` for the others, only for wrappers passing messages through
+// unchanged; `all` being all of them. New hooks go in a group so no wrapper misses them.
+macro_rules! delegate_hooks {
+    ($field:ident => $($hook:ident),+) => {
+        $(delegate_hooks!(@hook $field $hook);)+
+    };
+    (@hook $field:ident all) => {
+        delegate_hooks!($field => untyped, init, typed);
+    };
+    (@hook $field:ident untyped) => {
+        delegate_hooks!(
+            $field => save_state, use_msg_ids, on_ready, dump, cluster_view, capabilities,
+                on_restart, timers
+        );
+    };
+    (@hook $field:ident init) => {
+        fn on_init(&mut self, node_id: String, node_ids: Vec<String>) -> anyhow::Result<()> {
+            self.$field.on_init(node_id, node_ids)
+        }
+    };
+    (@hook $field:ident typed) => {
+        delegate_hooks!($field => use_rpcs, on_shutdown, on_timer);
+    };
+    (@hook $field:ident save_state) => {
+        fn save_state(&self) -> Option<serde_json::Value> {
+            self.$field.save_state()
+        }
+    };
+    (@hook $field:ident use_msg_ids) => {
+        fn use_msg_ids(&mut self, msg_ids: $crate::msg_protocol::MessageIdAllocator) {
+            self.$field.use_msg_ids(msg_ids)
+        }
+    };
+    (@hook $field:ident use_rpcs) => {
+        fn use_rpcs(&mut self, rpcs: $crate::rpc::RpcRegistry<MessageType>) {
+            self.$field.use_rpcs(rpcs)
+        }
+    };
+    (@hook $field:ident on_ready) => {
+        fn on_ready(&mut self) {
+            self.$field.on_ready()
+        }
+    };
+    (@hook $field:ident dump) => {
+        fn dump(&self) -> Option<serde_json::Value> {
+            self.$field.dump()
+        }
+    };
+    (@hook $field:ident cluster_view) => {
+        fn cluster_view(
+            &self,
+            init_view: $crate::msg_protocol::ClusterView,
+        ) -> $crate::msg_protocol::ClusterView {
+            self.$field.cluster_view(init_view)
+        }
+    };
+    (@hook $field:ident capabilities) => {
+        fn capabilities(&self) -> Vec<String> {
+            self.$field.capabilities()
+        }
+    };
+    (@hook $field:ident on_restart) => {
+        fn on_restart(&mut self) {
+            self.$field.on_restart()
+        }
+    };
+    (@hook $field:ident on_shutdown) => {
+        fn on_shutdown(&mut self) -> $crate::msg_protocol::ProcessResult<MessageType> {
+            self.$field.on_shutdown()
+        }
+    };
+    (@hook $field:ident timers) => {
+        fn timers(&self) -> Vec<(&'static str, std::time::Duration)> {
+            self.$field.timers()
+        }
+    };
+    (@hook $field:ident on_timer) => {
+        fn on_timer(&mut self, timer: &str) -> $crate::msg_protocol::ProcessResult<MessageType> {
+            self.$field.on_timer(timer)
+        }
+    };
+    // a name that is neither a hook nor a group
+    (@hook $field:ident $hook:ident) => {
+        compile_error!(concat!("no processor hook named ", stringify!($hook)));
+    };
+}
+
+pub mod checker;
+pub mod clock;
+pub mod dedup;
+pub mod epochs;
+pub mod hashing;
+pub mod lenient;
+pub mod metrics;
+pub mod nemesis;
+pub mod node_id;
+pub mod recording;
+pub mod replying;
+pub mod rpc;
+pub mod testkit;
+
 pub mod msg_protocol {
     use anyhow::Result;
     use serde::{Deserialize, Serialize};
+    use std::sync::atomic::{AtomicI64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
 
+    /// Envelope of every Maelstrom message, `T` being the body type of a workload.
+    ///
+    /// ```
+    /// use maelstrom_rust::msg_protocol::kv::KvMessage;
+    /// use maelstrom_rust::msg_protocol::{Body, Message};
+    ///
+    /// let read = Message {
+    ///     src: Some("n1".to_string()),
+    ///     dest: Some("seq-kv".to_string()),
+    ///     body: Body {
+    ///         msg_id: Some(1),
+    ///         in_reply_to: None,
+    ///         body: KvMessage::Read { key: "counter".into() },
+    ///     },
+    /// };
+    /// assert_eq!(
+    ///     serde_json::to_string(&read).unwrap(),
+    ///     r#"{"src":"n1","dest":"seq-kv","body":{"msg_id":1,"type":"read","key":"counter"}}"#
+    /// );
+    /// ```
     #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
     pub struct Message<T> {
         pub src: Option<String>,
@@ -17,66 +145,3839 @@ pub mod msg_protocol {
         pub body: Body<T>,
     }
 
+    impl<T> Message<T> {
+        // tags every log line about this message, e.g. `c1:3`, `-` standing for what is missing
+        pub fn correlation_id(&self) -> String {
+            let msg_id = self.body.msg_id.map(|msg_id| msg_id.to_string());
+            format!(
+                "{}:{}",
+                self.src.as_deref().unwrap_or("-"),
+                msg_id.as_deref().unwrap_or("-")
+            )
+        }
+
+        // `body` sent back to where this message came from, as the reply to it: the sender
+        // still picks the msg_id it sends it with
+        pub fn reply_with<U>(&self, msg_id: Option<i64>, body: U) -> Message<U> {
+            Message {
+                src: self.dest.clone(),
+                dest: self.src.clone(),
+                body: Body {
+                    msg_id,
+                    in_reply_to: self.body.msg_id,
+                    body,
+                },
+            }
+        }
+
+        // error sent back to where this message came from
+        pub fn error_reply(&self, code: i64, text: impl Into<String>) -> Message<Error> {
+            self.reply_with(
+                None,
+                Error {
+                    code,
+                    text: text.into(),
+                },
+            )
+        }
+    }
+
     #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
     pub struct Body<T> {
+        // only meant to correlate replies: ids from a given peer can arrive in any order
+        // (network reordering) so nothing should expect them to be monotonic
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none",
+            deserialize_with = "large_ints::option"
+        )]
         pub msg_id: Option<i64>,
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none",
+            deserialize_with = "large_ints::option"
+        )]
         pub in_reply_to: Option<i64>,
 
         #[serde(flatten)]
         pub body: T,
     }
 
+    /// Body of Maelstrom's `error` message.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(tag = "type", rename = "error")]
+    pub struct Error {
+        pub code: i64,
+        pub text: String,
+    }
+
+    impl std::fmt::Display for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "error {}: {}", self.code, self.text)
+        }
+    }
+
+    impl std::error::Error for Error {}
+
+    /// Hands out the msg_ids a node sends messages with, from 1 upwards. Clones share the
+    /// count, so no two of them ever hand out the same id.
+    #[derive(Debug, Clone, Default)]
+    pub struct MessageIdAllocator {
+        last: Arc<AtomicI64>,
+    }
+
+    impl MessageIdAllocator {
+        pub fn next(&self) -> i64 {
+            self.last.fetch_add(1, Ordering::Relaxed) + 1
+        }
+
+        /// Highest msg_id handed out so far, 0 before the first.
+        pub fn last(&self) -> i64 {
+            self.last.load(Ordering::Relaxed)
+        }
+    }
+
+    /// Body of the reply to a `cluster_view` request, answered by the runner from the init.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(tag = "type", rename = "cluster_view_ok")]
+    pub struct ClusterView {
+        pub node_id: String,
+        // every node of the cluster, this one included
+        pub node_ids: Vec<String>,
+        pub cluster_size: usize,
+    }
+
+    /// Messages understood by Maelstrom's key/value services, their failures are `Error`s.
+    pub mod kv {
+        use serde::{Deserialize, Serialize};
+        use serde_json::Value;
+
+        use super::error_codes;
+        use crate::replying::OrError;
+
+        pub const SEQ_KV: &str = "seq-kv";
+        pub const LIN_KV: &str = "lin-kv";
+        pub const LWW_KV: &str = "lww-kv";
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        #[serde(tag = "type")]
+        #[serde(rename_all = "snake_case")]
+        pub enum KvMessage {
+            Read {
+                key: Value,
+            },
+            ReadOk {
+                value: Value,
+            },
+            Write {
+                key: Value,
+                value: Value,
+            },
+            WriteOk {},
+            Cas {
+                key: Value,
+                from: Value,
+                to: Value,
+                #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+                create_if_not_exists: bool,
+            },
+            CasOk {},
+        }
+
+        /// Value a read got back from the service, `None` when the key doesn't exist: every
+        /// other error, a timeout included, is a failure of the read itself.
+        pub fn read_result(reply: OrError<KvMessage>) -> anyhow::Result<Option<Value>> {
+            match reply {
+                OrError::Message(KvMessage::ReadOk { value }) => Ok(Some(value)),
+                OrError::Message(other) => {
+                    Err(anyhow::anyhow!("Unexpected reply to read : {:?}", other))
+                }
+                OrError::Error(error) if error.code == error_codes::KEY_DOES_NOT_EXIST => Ok(None),
+                OrError::Error(error) => Err(error.into()),
+            }
+        }
+    }
+
+    /// Maelstrom's standard error codes. Those marked indefinite leave it unknown whether
+    /// the request took effect, the others tell it didn't.
+    pub mod error_codes {
+        // indefinite
+        pub const TIMEOUT: i64 = 0;
+        pub const NODE_NOT_FOUND: i64 = 1;
+        pub const NOT_SUPPORTED: i64 = 10;
+        pub const TEMPORARILY_UNAVAILABLE: i64 = 11;
+        pub const MALFORMED_REQUEST: i64 = 12;
+        // indefinite
+        pub const CRASH: i64 = 13;
+        pub const ABORT: i64 = 14;
+        pub const KEY_DOES_NOT_EXIST: i64 = 20;
+        pub const KEY_ALREADY_EXISTS: i64 = 21;
+        pub const PRECONDITION_FAILED: i64 = 22;
+        pub const TXN_CONFLICT: i64 = 30;
+    }
+
+    /// Integer fields read from either numbers or strings of integers, the way integers beyond
+    /// 2^53 are sent when `large_ints_as_strings` is set, e.g.
+    /// `#[serde(deserialize_with = "large_ints::int")]`. Only fields declared so are read from
+    /// strings, any other string staying one.
+    pub mod large_ints {
+        use std::str::FromStr;
+
+        use serde::{Deserialize, Deserializer};
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum IntOrString<T> {
+            Int(T),
+            String(String),
+        }
+
+        impl<T: FromStr> IntOrString<T> {
+            fn parse<E: serde::de::Error>(self) -> Result<T, E> {
+                match self {
+                    IntOrString::Int(n) => Ok(n),
+                    IntOrString::String(s) => s
+                        .parse()
+                        .map_err(|_| E::custom(format!("Expected an integer : {:?}", s))),
+                }
+            }
+        }
+
+        pub fn int<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+        where
+            D: Deserializer<'de>,
+            T: Deserialize<'de> + FromStr,
+        {
+            IntOrString::deserialize(deserializer)?.parse()
+        }
+
+        pub fn option<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+        where
+            D: Deserializer<'de>,
+            T: Deserialize<'de> + FromStr,
+        {
+            Option::<IntOrString<T>>::deserialize(deserializer)?
+                .map(IntOrString::parse)
+                .transpose()
+        }
+
+        pub fn collection<'de, D, T, C>(deserializer: D) -> Result<C, D::Error>
+        where
+            D: Deserializer<'de>,
+            T: Deserialize<'de> + FromStr,
+            C: FromIterator<T>,
+        {
+            Vec::<IntOrString<T>>::deserialize(deserializer)?
+                .into_iter()
+                .map(IntOrString::parse)
+                .collect()
+        }
+    }
+
+    pub type ProcessResult<T> = Result<Option<Vec<Message<T>>>>;
+
+    pub fn reply<T>(msgs: Vec<Message<T>>) -> ProcessResult<T> {
+        Ok(Some(msgs))
+    }
+
+    pub fn no_reply<T>() -> ProcessResult<T> {
+        Ok(None)
+    }
+
+    pub fn fail<T>(err: impl Into<anyhow::Error>) -> ProcessResult<T> {
+        Err(err.into())
+    }
+
+    /// Failure asking the runner to restart the node, as if it crashed and came back: the node
+    /// forgets its state in `on_restart` and gets nothing but a new init until one comes in.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct Restart;
+
+    impl std::fmt::Display for Restart {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "restart requested")
+        }
+    }
+
+    impl std::error::Error for Restart {}
+
+    pub fn restart<T>() -> ProcessResult<T> {
+        Err(Restart.into())
+    }
+
+    /// Where a node sends messages of its own while processing one, as opposed to the
+    /// replies it returns.
+    pub trait Sender<MessageType> {
+        fn send(&mut self, msg: Message<MessageType>);
+    }
+
+    impl<MessageType> Sender<MessageType> for Vec<Message<MessageType>> {
+        fn send(&mut self, msg: Message<MessageType>) {
+            self.push(msg);
+        }
+    }
+
+    /// Processes `msg` through `process_with_sender`, what the node sent coming after its
+    /// replies, as the runner sends them. Nothing sent goes out when processing fails.
+    pub fn process_collecting<MessageType, P>(
+        processor: &mut P,
+        msg: Message<MessageType>,
+    ) -> ProcessResult<MessageType>
+    where
+        P: Processor<MessageType> + ?Sized,
+    {
+        let mut sent = vec![];
+        let replies = processor.process_with_sender(msg, &mut sent)?;
+        if sent.is_empty() {
+            return Ok(replies);
+        }
+        let mut replies = replies.unwrap_or_default();
+        replies.extend(sent);
+        Ok(Some(replies))
+    }
+
     pub trait Processor<MessageType> {
-        fn process(
+        fn process(&mut self, msg: Message<MessageType>) -> ProcessResult<MessageType>;
+
+        // processing with a sender for messages the node initiates besides its replies, which
+        // the runner calls; nodes sending such messages implement it, their `process` being
+        // `process_collecting(self, msg)`
+        fn process_with_sender(
             &mut self,
             msg: Message<MessageType>,
-        ) -> Result<Option<Vec<Message<MessageType>>>>;
+            _sender: &mut dyn Sender<MessageType>,
+        ) -> ProcessResult<MessageType> {
+            self.process(msg)
+        }
+
+        // snapshot of the node state, nodes without any state worth inspecting keep the default
+        fn save_state(&self) -> Option<serde_json::Value> {
+            None
+        }
+
+        // called by the runner on every init, which it answers with an init_ok once this
+        // succeeds, so nodes needing their identity or the cluster keep them from here
+        fn on_init(&mut self, _node_id: String, _node_ids: Vec<String>) -> Result<()> {
+            Ok(())
+        }
+
+        // the allocator the runner numbers what the node sends without a msg_id from, handed
+        // over before the first message so that nodes needing an id early can take it there
+        fn use_msg_ids(&mut self, _msg_ids: MessageIdAllocator) {}
+
+        // the registry requests the node awaits replies to are sent through, replies to them
+        // going to the registry rather than to `process`, handed over like the msg_ids
+        fn use_rpcs(&mut self, _rpcs: crate::rpc::RpcRegistry<MessageType>) {}
+
+        // called once the first init has been processed, by default logs that the node is ready
+        fn on_ready(&mut self) {
+            eprintln!("Node ready");
+        }
+
+        // diagnostics about the run (metrics, timings...), as opposed to the node state,
+        // the runner logs them once the input is exhausted
+        fn dump(&self) -> Option<serde_json::Value> {
+            None
+        }
+
+        // answer to `cluster_view`, by default what the node was given on init
+        fn cluster_view(&self, init_view: ClusterView) -> ClusterView {
+            init_view
+        }
+
+        // message types and features advertised in answer to `capabilities`, nodes keeping
+        // the default don't advertise any
+        fn capabilities(&self) -> Vec<String> {
+            vec![]
+        }
+
+        // called when processing a message asked for a restart, for the node to forget its
+        // state; only runners processing messages one at a time restart nodes
+        fn on_restart(&mut self) {}
+
+        // called once the input is exhausted, for anything still held back to go out
+        fn on_shutdown(&mut self) -> ProcessResult<MessageType> {
+            Ok(None)
+        }
+
+        // periodic callbacks as names and intervals, `run_with_timers` calling `on_timer`
+        // with a name every interval once the node is ready, whether messages come in or not
+        fn timers(&self) -> Vec<(&'static str, Duration)> {
+            vec![]
+        }
+
+        fn on_timer(&mut self, _timer: &str) -> ProcessResult<MessageType> {
+            Ok(None)
+        }
+
+        // brings the node back to a fresh state so it can be reused, e.g. across test cases
+        fn reset(&mut self)
+        where
+            Self: Default,
+        {
+            *self = Self::default();
+        }
+    }
+
+    /// Marks processors whose answers depend on nothing but the message they answer, so
+    /// messages can be processed in any order, concurrently. The runner can then have copies
+    /// of the node process them in parallel, whatever a copy changes in itself being lost.
+    pub trait StatelessProcessor<MessageType>: Processor<MessageType> + Clone + Send {}
+
+    /// Marks processors whose state is partitioned by a key messages carry, e.g. the key of a
+    /// kv operation. The runner can then spread keys over copies of the node, messages for
+    /// different keys being processed concurrently while those for the same key keep their
+    /// order, each copy only ever seeing the keys it was given.
+    pub trait ShardedProcessor<MessageType>: Processor<MessageType> + Clone + Send {
+        // key `msg` is about, messages without one all going to the same copy
+        fn shard_key(&self, msg: &Message<MessageType>) -> Option<String>;
     }
 }
 pub mod runner {
     use super::*;
+    use clock::{Clock, SystemClock};
     use msg_protocol::*;
+    use node_id::NodeId;
+    use rpc::RpcRegistry;
+
+    use serde_json::Value;
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::sync::{mpsc, Mutex};
+    use std::time::{Duration, Instant};
+
+    /// Order in which the messages a node sends while processing a message go out.
+    #[derive(Debug, Clone, Copy, Default, PartialEq)]
+    pub enum ReplyOrder {
+        // as returned by the node
+        #[default]
+        Fifo,
+        // acks (`*_ok`) before anything else, e.g. gossip, so that clients are answered first
+        AcksFirst,
+    }
+
+    #[derive(Debug, Clone, Default)]
+    pub struct RunnerOptions {
+        // replies to a message are held back until at least this long after it was read
+        pub min_reply_latency: Option<Duration>,
+        // workload versions accepted in init, any version is accepted when not set
+        pub supported_versions: Option<HashSet<String>>,
+        // reject messages with body fields the node's message type doesn't know about,
+        // instead of ignoring them
+        pub strict: bool,
+        // capacity of the buffer input is read through, 8KB when not set
+        pub read_buffer_size: Option<usize>,
+        // longer input lines are skipped with an error logged, rather than read whole
+        pub max_line_length: Option<usize>,
+        // count and log every msg_id the node sends more than once during the run, keeping
+        // them all; otherwise replies are only checked against the highest msg_id sent
+        pub check_msg_ids: bool,
+        // what fails to serialize in a reply is logged and sent as null, instead of stopping
+        // the node
+        pub lenient_serialization: bool,
+        pub reply_order: ReplyOrder,
+        // integers beyond 2^53 are sent as strings; integer fields declared with `large_ints`
+        // read them back either way
+        pub large_ints_as_strings: bool,
+        // drop messages from sources that are neither nodes of the cluster, clients nor services
+        pub validate_sources: bool,
+        // NDJSON file messages that couldn't be read or processed are appended to
+        pub dead_letter_file: Option<std::path::PathBuf>,
+        // shared secret node-to-node messages must carry as `token`, and are sent with
+        pub auth_token: Option<String>,
+        // threads processing messages when run in parallel, or shards when run sharded, as
+        // many as the machine has cores when not set
+        pub workers: Option<usize>,
+        // no message is read past init before its answer is written and flushed, even when
+        // run in parallel
+        pub init_first: bool,
+        // time spent reading, processing and writing messages is reported on shutdown, when
+        // messages aren't processed in parallel
+        pub profile_phases: bool,
+        // file the run report is written to on shutdown, in the Prometheus text format
+        pub metrics_file: Option<std::path::PathBuf>,
+    }
+
+    impl RunnerOptions {
+        /// Options set through the environment of the node process:
+        /// `MIN_REPLY_LATENCY_MS` sets `min_reply_latency`,
+        /// `SUPPORTED_VERSIONS` sets `supported_versions` as a comma-separated list,
+        /// `STRICT_MESSAGES=true` sets `strict`,
+        /// `READ_BUFFER_SIZE` sets `read_buffer_size` in bytes,
+        /// `MAX_LINE_LENGTH` sets `max_line_length` in bytes,
+        /// `CHECK_MSG_IDS=true` sets `check_msg_ids`,
+        /// `LENIENT_SERIALIZATION=true` sets `lenient_serialization`,
+        /// `WORKERS` sets `workers`,
+        /// `REPLY_ORDER=acks_first` sets `reply_order` to `AcksFirst`,
+        /// `AUTH_TOKEN` sets `auth_token`,
+        /// `LARGE_INTS_AS_STRINGS=true` sets `large_ints_as_strings`,
+        /// `DEAD_LETTER_FILE` sets `dead_letter_file`,
+        /// `VALIDATE_SOURCES=true` sets `validate_sources`,
+        /// `INIT_FIRST=true` sets `init_first`,
+        /// `PROFILE_PHASES=true` sets `profile_phases`,
+        /// `METRICS_FILE` sets `metrics_file`.
+        pub fn from_env() -> Self {
+            Self {
+                min_reply_latency: std::env::var("MIN_REPLY_LATENCY_MS")
+                    .ok()
+                    .and_then(|ms| ms.parse().ok())
+                    .map(Duration::from_millis),
+                supported_versions: std::env::var("SUPPORTED_VERSIONS")
+                    .ok()
+                    .map(|versions| versions.split(',').map(|v| v.trim().to_string()).collect()),
+                strict: std::env::var("STRICT_MESSAGES").is_ok_and(|strict| strict == "true"),
+                read_buffer_size: std::env::var("READ_BUFFER_SIZE")
+                    .ok()
+                    .and_then(|size| size.parse().ok()),
+                max_line_length: std::env::var("MAX_LINE_LENGTH")
+                    .ok()
+                    .and_then(|length| length.parse().ok()),
+                check_msg_ids: std::env::var("CHECK_MSG_IDS").is_ok_and(|check| check == "true"),
+                lenient_serialization: std::env::var("LENIENT_SERIALIZATION")
+                    .is_ok_and(|lenient| lenient == "true"),
+                workers: std::env::var("WORKERS")
+                    .ok()
+                    .and_then(|workers| workers.parse().ok()),
+                auth_token: std::env::var("AUTH_TOKEN").ok(),
+                dead_letter_file: std::env::var("DEAD_LETTER_FILE").ok().map(Into::into),
+                validate_sources: std::env::var("VALIDATE_SOURCES")
+                    .is_ok_and(|validate| validate == "true"),
+                init_first: std::env::var("INIT_FIRST").is_ok_and(|first| first == "true"),
+                profile_phases: std::env::var("PROFILE_PHASES")
+                    .is_ok_and(|profile| profile == "true"),
+                metrics_file: std::env::var("METRICS_FILE").ok().map(Into::into),
+                large_ints_as_strings: std::env::var("LARGE_INTS_AS_STRINGS")
+                    .is_ok_and(|strings| strings == "true"),
+                reply_order: match std::env::var("REPLY_ORDER").as_deref() {
+                    std::result::Result::Ok("acks_first") => ReplyOrder::AcksFirst,
+                    _ => ReplyOrder::Fifo,
+                },
+            }
+        }
+    }
+
+    /// Summary of a run, logged once the input is exhausted.
+    #[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+    pub struct RunReport {
+        // input messages, whatever became of them
+        pub received: usize,
+        // messages the node processed successfully
+        pub processed: usize,
+        // messages the runner refused to hand over to the node
+        pub rejected: usize,
+        // messages that couldn't be parsed, or whose processing failed
+        pub failed: usize,
+        pub duplicate_msg_ids: usize,
+        // size of the final node state once serialized, if the node has one
+        pub state_bytes: Option<usize>,
+        // where the time went, when profiling
+        pub phases: Option<PhaseTimes>,
+    }
+
+    /// Time spent in each phase of a run, in microseconds.
+    #[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+    pub struct PhaseTimes {
+        // reading and deserializing messages, along with the checks before the node gets them
+        pub read_us: u64,
+        // the node processing them
+        pub process_us: u64,
+        // serializing and writing what is sent
+        pub write_us: u64,
+    }
+
+    enum Phase {
+        Read,
+        Process,
+        Write,
+    }
+
+    // adds up the time spent in each phase when profiling, only runs them otherwise
+    struct PhaseTimer<'a> {
+        clock: &'a dyn Clock,
+        times: Option<PhaseTimes>,
+    }
+
+    impl PhaseTimer<'_> {
+        fn time<T>(&mut self, phase: Phase, f: impl FnOnce() -> T) -> T {
+            let Some(times) = self.times.as_mut() else {
+                return f();
+            };
+            let start = self.clock.now();
+            let result = f();
+            let elapsed = self.clock.now().saturating_sub(start).as_micros() as u64;
+            match phase {
+                Phase::Read => times.read_us += elapsed,
+                Phase::Process => times.process_us += elapsed,
+                Phase::Write => times.write_us += elapsed,
+            }
+            result
+        }
+    }
+
+    /// Which msg_ids a node could have sent, to drop replies to messages never sent. Only
+    /// when checking is every msg_id sent kept, to catch a node reusing one: replies could
+    /// then be matched to the wrong request. Otherwise any msg_id the allocator handed out
+    /// is taken as sent.
+    #[derive(Debug)]
+    pub struct MsgIdTracker {
+        allocator: MessageIdAllocator,
+        sent: Option<HashSet<i64>>,
+    }
+
+    impl MsgIdTracker {
+        pub fn new(allocator: MessageIdAllocator, check: bool) -> Self {
+            Self {
+                allocator,
+                sent: check.then(HashSet::new),
+            }
+        }
+
+        /// Records the msg_ids of the given messages when checking, returning those already
+        /// sent before.
+        pub fn track<MessageType>(&mut self, msgs: &Option<Vec<Message<MessageType>>>) -> Vec<i64> {
+            let Some(sent) = self.sent.as_mut() else {
+                return vec![];
+            };
+            msgs.iter()
+                .flatten()
+                .filter_map(|msg| msg.body.msg_id)
+                .filter(|msg_id| !sent.insert(*msg_id))
+                .collect()
+        }
+
+        pub fn was_sent(&self, msg_id: i64) -> bool {
+            match &self.sent {
+                Some(sent) => sent.contains(&msg_id),
+                None => (1..=self.allocator.last()).contains(&msg_id),
+            }
+        }
+    }
+
+    // an init can carry the workload version in its extra fields, a missing one is accepted
+    fn unsupported_version(msg: &Message<Value>, options: &RunnerOptions) -> Option<Error> {
+        let supported_versions = options.supported_versions.as_ref()?;
+        if msg.body.body.get("type")? != "init" {
+            return None;
+        }
+        let version = match msg.body.body.get("version")? {
+            Value::String(version) => version.clone(),
+            version => version.to_string(),
+        };
+        if supported_versions.contains(&version) {
+            None
+        } else {
+            Some(Error {
+                code: error_codes::NOT_SUPPORTED,
+                text: format!("Unsupported workload version {}", version),
+            })
+        }
+    }
+
+    // without a type the node can't even tell which message it got
+    fn missing_type(msg: &Message<Value>) -> Option<Error> {
+        match msg.body.body.get("type") {
+            Some(_) => None,
+            None => Some(Error {
+                code: error_codes::MALFORMED_REQUEST,
+                text: "Missing message type".into(),
+            }),
+        }
+    }
+
+    // fields of the raw body left out of the typed message, nulls are taken as absent
+    fn unknown_fields<MessageType>(raw_body: &Value, msg: &Message<MessageType>) -> Vec<String>
+    where
+        MessageType: serde::Serialize,
+    {
+        match (raw_body, serde_json::to_value(&msg.body)) {
+            (Value::Object(raw_fields), Result::Ok(Value::Object(known_fields))) => raw_fields
+                .iter()
+                .filter(|(field, value)| !value.is_null() && !known_fields.contains_key(*field))
+                .map(|(field, _)| field.clone())
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    // one message per line, parsed as the line is read rather than once buffered whole, lines
+    // longer than `max_line_length` bytes being skipped
+    struct BoundedLines<R> {
+        input: R,
+        max_line_length: usize,
+    }
+
+    // the line `input` is at as a reader, ending with its newline and failing past `remaining`
+    // bytes
+    struct LineReader<'a, R> {
+        input: &'a mut R,
+        remaining: usize,
+        ended: bool,
+        too_long: bool,
+    }
+
+    impl<R: BufRead> Read for LineReader<'_, R> {
+        fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+            if self.ended || out.is_empty() {
+                return std::io::Result::Ok(0);
+            }
+            let buf = self.input.fill_buf()?;
+            let available = buf.len().min(out.len());
+            let (len, content) = match buf[..available].iter().position(|b| *b == b'\n') {
+                Some(end) => {
+                    self.ended = true;
+                    (end + 1, end)
+                }
+                None if available == 0 => {
+                    self.ended = true;
+                    (0, 0)
+                }
+                None => (available, available),
+            };
+            if content > self.remaining {
+                self.too_long = true;
+                return Err(std::io::Error::other("line too long"));
+            }
+            out[..len].copy_from_slice(&buf[..len]);
+            self.input.consume(len);
+            self.remaining -= content;
+            std::io::Result::Ok(len)
+        }
+    }
+
+    impl<R: BufRead> BoundedLines<R> {
+        fn skip_rest_of_line(&mut self) -> std::io::Result<()> {
+            loop {
+                let buf = self.input.fill_buf()?;
+                if buf.is_empty() {
+                    return std::io::Result::Ok(());
+                }
+                match buf.iter().position(|b| *b == b'\n') {
+                    Some(end) => {
+                        self.input.consume(end + 1);
+                        return std::io::Result::Ok(());
+                    }
+                    None => {
+                        let len = buf.len();
+                        self.input.consume(len);
+                    }
+                }
+            }
+        }
+
+        // past blank lines, telling whether there's anything left to read
+        fn skip_whitespace(&mut self) -> std::io::Result<bool> {
+            loop {
+                let buf = self.input.fill_buf()?;
+                if buf.is_empty() {
+                    return std::io::Result::Ok(false);
+                }
+                let blank = buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+                if blank < buf.len() {
+                    self.input.consume(blank);
+                    return std::io::Result::Ok(true);
+                }
+                self.input.consume(blank);
+            }
+        }
+    }
+
+    impl<R: BufRead> Iterator for BoundedLines<R> {
+        type Item = serde_json::Result<Value>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            loop {
+                match self.skip_whitespace() {
+                    std::result::Result::Ok(true) => {}
+                    std::result::Result::Ok(false) => return None,
+                    Err(e) => {
+                        eprintln!("Failed reading input : {}", e);
+                        return None;
+                    }
+                }
+                let mut line = LineReader {
+                    input: &mut self.input,
+                    remaining: self.max_line_length,
+                    ended: false,
+                    too_long: false,
+                };
+                let mut deserializer = serde_json::Deserializer::from_reader(&mut line);
+                let value = serde::Deserialize::deserialize(&mut deserializer)
+                    .and_then(|value| deserializer.end().map(|()| value));
+                let (ended, too_long) = (line.ended, line.too_long);
+                if too_long {
+                    eprintln!("Skipped line longer than {} bytes", self.max_line_length);
+                }
+                if !ended {
+                    if let Err(e) = self.skip_rest_of_line() {
+                        eprintln!("Failed reading input : {}", e);
+                        return None;
+                    }
+                }
+                if !too_long {
+                    return Some(value);
+                }
+            }
+        }
+    }
+
+    // node identity as given by an init
+    fn init_view(msg: &Message<Value>) -> Option<ClusterView> {
+        if msg.body.body.get("type")? != "init" {
+            return None;
+        }
+        let node_id = msg.body.body.get("node_id")?.as_str()?.to_string();
+        let node_ids: Vec<String> = msg
+            .body
+            .body
+            .get("node_ids")?
+            .as_array()?
+            .iter()
+            .filter_map(|node_id| node_id.as_str().map(str::to_string))
+            .collect();
+        Some(ClusterView {
+            node_id,
+            cluster_size: node_ids.len(),
+            node_ids,
+        })
+    }
+
+    fn typed<MessageType>(msg: Message<Value>) -> serde_json::Result<Message<MessageType>>
+    where
+        MessageType: for<'de> serde::Deserialize<'de>,
+    {
+        Result::Ok(Message {
+            src: msg.src,
+            dest: msg.dest,
+            body: Body {
+                msg_id: msg.body.msg_id,
+                in_reply_to: msg.body.in_reply_to,
+                body: serde_json::from_value(msg.body.body)?,
+            },
+        })
+    }
 
-    fn serialize<MessageType>(
+    // replies are serialized aside first so a failing one can't leave half a line on the output,
+    // non-finite floats don't fail though, serde_json writes them as null; when lenient, what
+    // fails in a reply is nulled, the rest still going out
+    fn serialize<MessageType, W>(
         maybe_reply: Option<Vec<Message<MessageType>>>,
-        out: &mut StdoutLock,
+        out: &mut W,
+        options: &RunnerOptions,
     ) -> Result<()>
     where
         MessageType: serde::Serialize,
+        W: Write,
     {
         if let Some(replies) = maybe_reply {
             for reply in replies {
-                serde_json::to_writer(&mut *out, &reply).context("Serialize reply message")?;
+                let mut line = match to_line(&reply, options) {
+                    std::result::Result::Ok(line) => line,
+                    Err(e) if options.lenient_serialization => {
+                        eprintln!("Nulled what failed to serialize in a reply : {}", e);
+                        value_to_line(crate::lenient::to_value(&reply), options)
+                            .context("Serialize reply message")?
+                    }
+                    Err(e) => return Err(e).context("Serialize reply message"),
+                };
                 // writing a new line to flush the line writer
-                out.write_all(b"\n")?;
+                line.push(b'\n');
+                out.write_all(&line)?;
             }
         }
         Ok(())
     }
 
-    pub fn run<MessageType, P>(processor: &mut P) -> anyhow::Result<()>
+    // what the node sends goes out in the configured order, numbered by the runner when the
+    // node left the msg_id out
+    fn outgoing<MessageType>(
+        maybe_msg: Option<Vec<Message<MessageType>>>,
+        allocator: &MessageIdAllocator,
+        options: &RunnerOptions,
+    ) -> Option<Vec<Message<MessageType>>>
     where
-        MessageType: for<'de> serde::Deserialize<'de>,
-        P: Processor<MessageType>,
         MessageType: serde::Serialize,
     {
-        let stdin = std::io::stdin().lock();
-        let deserializer = serde_json::Deserializer::from_reader(stdin);
-        let mut stdout = std::io::stdout().lock();
-        deserializer
-            .into_iter::<Message<MessageType>>()
-            .for_each(|msg| match msg {
-                std::result::Result::Ok(msg) => {
-                    let maybe_msg_result =
-                        processor.process(msg).context("Error processing message");
-                    if let Result::Ok(maybe_msg) = maybe_msg_result {
-                        serialize(maybe_msg, &mut stdout).unwrap();
-                    };
+        maybe_msg.map(|mut msgs| {
+            if options.reply_order == ReplyOrder::AcksFirst {
+                msgs.sort_by_key(|msg| !is_ack(msg));
+            }
+            for msg in &mut msgs {
+                if msg.body.msg_id.is_none() {
+                    msg.body.msg_id = Some(allocator.next());
                 }
-                Err(e) => {
-                    println!("Unknown message : {}", e);
+            }
+            msgs
+        })
+    }
+
+    // node-to-node messages carry the shared token when there is one
+    fn to_line<MessageType>(
+        msg: &Message<MessageType>,
+        options: &RunnerOptions,
+    ) -> serde_json::Result<Vec<u8>>
+    where
+        MessageType: serde::Serialize,
+    {
+        let to_node = msg.dest.as_ref().is_some_and(|dest| node_id::is_node(dest));
+        if !(options.large_ints_as_strings || to_node && options.auth_token.is_some()) {
+            return serde_json::to_vec(msg);
+        }
+        value_to_line(serde_json::to_value(msg)?, options)
+    }
+
+    fn value_to_line(mut msg: Value, options: &RunnerOptions) -> serde_json::Result<Vec<u8>> {
+        let to_node = msg["dest"].as_str().is_some_and(node_id::is_node);
+        let token = options.auth_token.as_ref().filter(|_| to_node);
+        if let Some(token) = token {
+            msg["body"]["token"] = token.clone().into();
+        }
+        if options.large_ints_as_strings {
+            stringify_large_ints(&mut msg);
+        }
+        serde_json::to_vec(&msg)
+    }
+
+    // Sources are known when they are a node of the cluster, any node until the init tells which
+    // ones, a client (`c` followed by a number) or one of Maelstrom's kv services.
+    fn unknown_source(msg: &Message<Value>, cluster_view: &Option<ClusterView>) -> bool {
+        let Some(src) = msg.src.as_deref() else {
+            return true;
+        };
+        let is_node = match cluster_view {
+            Some(view) => view.node_ids.iter().any(|node_id| node_id == src),
+            None => node_id::is_node(src),
+        };
+        let is_client = src
+            .strip_prefix('c')
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+        let is_service = [kv::SEQ_KV, kv::LIN_KV, kv::LWW_KV].contains(&src);
+        !(is_node || is_client || is_service)
+    }
+
+    // node-to-node messages without the shared token, when there is one, are rejected
+    fn unauthenticated(msg: &Message<Value>, options: &RunnerOptions) -> Option<Error> {
+        let token = options.auth_token.as_ref()?;
+        msg.src.as_ref()?.parse::<NodeId>().ok()?;
+        if msg.body.body.get("token").and_then(Value::as_str) == Some(token.as_str()) {
+            None
+        } else {
+            Some(Error {
+                code: error_codes::TEMPORARILY_UNAVAILABLE,
+                text: "Missing or wrong token".into(),
+            })
+        }
+    }
+
+    // acks are told apart by their type, `*_ok`
+    fn is_ack<MessageType>(msg: &Message<MessageType>) -> bool
+    where
+        MessageType: serde::Serialize,
+    {
+        serde_json::to_value(&msg.body).is_ok_and(|body| {
+            body.get("type")
+                .and_then(Value::as_str)
+                .is_some_and(|t| t.ends_with("_ok"))
+        })
+    }
+
+    /// NDJSON file where messages that couldn't be read or processed are appended, each along
+    /// with why.
+    struct DeadLetters {
+        file: Mutex<std::fs::File>,
+    }
+
+    impl DeadLetters {
+        fn open(path: &std::path::Path) -> Result<Self> {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Open dead letter file {}", path.display()))?;
+            Ok(Self {
+                file: Mutex::new(file),
+            })
+        }
+
+        // unparseable input has no message to go with the error
+        fn record(&self, error: String, msg: Option<Value>) {
+            let mut dead_letter = serde_json::json!({ "error": error });
+            if let Some(msg) = msg {
+                dead_letter["message"] = msg;
+            }
+            let mut file = self.file.lock().unwrap();
+            if let Err(e) = writeln!(file, "{}", dead_letter) {
+                eprintln!("Failed writing dead letter : {}", e);
+            }
+        }
+    }
+
+    // failures are dead letters too, the message being kept aside in case processing fails
+    // a restart is only honoured with somewhere to flag it, being a failure like any other
+    // otherwise
+    fn process_recorded<MessageType, P>(
+        processor: &mut P,
+        msg: Message<MessageType>,
+        rpcs: &RpcRegistry<MessageType>,
+        dead_letters: Option<&DeadLetters>,
+        restarting: Option<&mut bool>,
+    ) -> Processed<MessageType>
+    where
+        P: Processor<MessageType>,
+        MessageType: serde::Serialize,
+    {
+        // replies to the node's own requests go to whoever awaits them
+        let Some(msg) = rpcs.route(msg) else {
+            return std::result::Result::Ok(None);
+        };
+        let recorded = dead_letters.and_then(|_| serde_json::to_value(&msg).ok());
+        let request = request_envelope(&msg);
+        match process_collecting(processor, msg) {
+            std::result::Result::Ok(answers) => std::result::Result::Ok(answers),
+            Err(e) if e.is::<Restart>() && restarting.is_some() => {
+                eprintln!(
+                    "[{}] Restarting, awaiting a new init",
+                    request.as_ref().map_or("-".into(), Message::correlation_id)
+                );
+                processor.on_restart();
+                if let Some(restarting) = restarting {
+                    *restarting = true;
                 }
-            });
-        Ok(())
+                std::result::Result::Ok(None)
+            }
+            Err(e) => Err(failure_reply(request, e, dead_letters, recorded)),
+        }
+    }
+
+    // what a restarting node is sent until its new init, requests being told to try again
+    fn awaiting_init<MessageType>(
+        msg: &Message<MessageType>,
+        allocator: &MessageIdAllocator,
+        msg_ids: &mut MsgIdTracker,
+        report: &mut RunReport,
+        options: &RunnerOptions,
+    ) -> Result<Vec<u8>> {
+        report.rejected += 1;
+        let mut out = vec![];
+        let error_reply = request_envelope(msg)
+            .map(|request| request.error_reply(error_codes::TEMPORARILY_UNAVAILABLE, "Restarting"));
+        let error_reply = error_reply.map(|reply| vec![reply]);
+        send_out(error_reply, allocator, msg_ids, report, &mut out, options)?;
+        Ok(out)
+    }
+
+    // replies aren't answered, errors could otherwise bounce between two nodes forever
+    fn request_envelope<MessageType>(msg: &Message<MessageType>) -> Option<Message<()>> {
+        (msg.body.msg_id.is_some() && msg.body.in_reply_to.is_none()).then(|| Message {
+            src: msg.src.clone(),
+            dest: msg.dest.clone(),
+            body: Body {
+                msg_id: msg.body.msg_id,
+                in_reply_to: None,
+                body: (),
+            },
+        })
+    }
+
+    // logs a failure and records it as a dead letter, returning the error reply to send if
+    // the failed message was a request
+    fn failure_reply(
+        request: Option<Message<()>>,
+        e: anyhow::Error,
+        dead_letters: Option<&DeadLetters>,
+        recorded: Option<Value>,
+    ) -> Option<Message<Error>> {
+        // failures carrying an `Error` keep their code, any other one is reported as a crash
+        let (code, text) = match e.downcast_ref::<Error>() {
+            Some(error) => (error.code, error.text.clone()),
+            None => (error_codes::CRASH, e.to_string()),
+        };
+        let correlation_id = request.as_ref().map(Message::correlation_id);
+        let e = e.context("Error processing message");
+        eprintln!("[{}] {:#}", correlation_id.as_deref().unwrap_or("-"), e);
+        if let Some(dead_letters) = dead_letters {
+            dead_letters.record(format!("{:#}", e), recorded);
+        }
+        request.map(|request| request.error_reply(code, text))
+    }
+
+    // what processing a message came to: the node's answers, or on failure the error reply
+    // to send if the message was a request
+    type Processed<MessageType> =
+        std::result::Result<Option<Vec<Message<MessageType>>>, Option<Message<Error>>>;
+
+    // what the runner makes of an input message before the node gets to see it
+    enum Admitted<MessageType> {
+        // dealt with by the runner alone, with the lines answering it if any
+        Handled(Vec<u8>),
+        // an init, answered once the node took it in, with whether it did
+        Initialized {
+            out: Vec<u8>,
+            ok: bool,
+        },
+        // for the node to process
+        Process {
+            msg: Message<MessageType>,
+            received_at: Instant,
+        },
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn admit<MessageType, P>(
+        msg: serde_json::Result<Value>,
+        processor: &mut P,
+        cluster_view: &mut Option<ClusterView>,
+        allocator: &MessageIdAllocator,
+        msg_ids: &mut MsgIdTracker,
+        report: &mut RunReport,
+        dead_letters: Option<&DeadLetters>,
+        options: &RunnerOptions,
+    ) -> Result<Admitted<MessageType>>
+    where
+        MessageType: for<'de> serde::Deserialize<'de>,
+        P: Processor<MessageType>,
+        MessageType: serde::Serialize,
+    {
+        let mut out = vec![];
+        report.received += 1;
+        let value = match msg {
+            std::result::Result::Ok(value) => value,
+            Err(e) => {
+                report.failed += 1;
+                eprintln!("Unknown message : {}", e);
+                if let Some(dead_letters) = dead_letters {
+                    dead_letters.record(e.to_string(), None);
+                }
+                return Ok(Admitted::Handled(out));
+            }
+        };
+        // kept as read only when there's somewhere to record it
+        let raw = dead_letters.map(|_| value.clone());
+        let mut msg: Message<Value> = match serde_json::from_value(value) {
+            std::result::Result::Ok(msg) => msg,
+            Err(e) => {
+                report.failed += 1;
+                eprintln!("Unknown message : {}", e);
+                if let Some(dead_letters) = dead_letters {
+                    dead_letters.record(e.to_string(), raw);
+                }
+                return Ok(Admitted::Handled(out));
+            }
+        };
+        // nothing could route a reply back without a src
+        if msg.src.is_none() {
+            report.rejected += 1;
+            eprintln!("[{}] Rejected message without src", msg.correlation_id());
+            return Ok(Admitted::Handled(out));
+        }
+        if options.validate_sources && unknown_source(&msg, cluster_view) {
+            report.rejected += 1;
+            eprintln!(
+                "[{}] Dropped message from unknown source",
+                msg.correlation_id()
+            );
+            return Ok(Admitted::Handled(out));
+        }
+        if let Some(error) = unauthenticated(&msg, options) {
+            report.rejected += 1;
+            let error_reply = msg.error_reply(error.code, error.text);
+            send_out(
+                Some(vec![error_reply]),
+                allocator,
+                msg_ids,
+                report,
+                &mut out,
+                options,
+            )?;
+            return Ok(Admitted::Handled(out));
+        }
+        // the token is the runner's business only
+        if let (Some(_), Value::Object(fields)) = (&options.auth_token, &mut msg.body.body) {
+            fields.remove("token");
+        }
+        // a negative or unknown in_reply_to can't be correlated to any request
+        if msg
+            .body
+            .in_reply_to
+            .is_some_and(|in_reply_to| !msg_ids.was_sent(in_reply_to))
+        {
+            report.rejected += 1;
+            eprintln!(
+                "[{}] Ignored reply to unknown msg_id : in_reply_to {:?}",
+                msg.correlation_id(),
+                msg.body.in_reply_to
+            );
+            return Ok(Admitted::Handled(out));
+        }
+        let received_at = Instant::now();
+        if let Some(error) = missing_type(&msg).or_else(|| unsupported_version(&msg, options)) {
+            report.rejected += 1;
+            let error_reply = msg.error_reply(error.code, error.text);
+            send_out(
+                Some(vec![error_reply]),
+                allocator,
+                msg_ids,
+                report,
+                &mut out,
+                options,
+            )?;
+            return Ok(Admitted::Handled(out));
+        }
+        // answered centrally, nodes only get to refine the view
+        if msg
+            .body
+            .body
+            .get("type")
+            .is_some_and(|t| t == "cluster_view")
+        {
+            match cluster_view {
+                Some(view) => {
+                    let reply = msg.reply_with(None, processor.cluster_view(view.clone()));
+                    send_out(
+                        Some(vec![reply]),
+                        allocator,
+                        msg_ids,
+                        report,
+                        &mut out,
+                        options,
+                    )?;
+                }
+                None => {
+                    let error_reply = msg
+                        .error_reply(error_codes::TEMPORARILY_UNAVAILABLE, "Not initialized yet");
+                    send_out(
+                        Some(vec![error_reply]),
+                        allocator,
+                        msg_ids,
+                        report,
+                        &mut out,
+                        options,
+                    )?;
+                }
+            }
+            return Ok(Admitted::Handled(out));
+        }
+        if msg
+            .body
+            .body
+            .get("type")
+            .is_some_and(|t| t == "capabilities")
+        {
+            let capabilities = processor.capabilities();
+            if cluster_view.is_none() {
+                let error_reply =
+                    msg.error_reply(error_codes::TEMPORARILY_UNAVAILABLE, "Not initialized yet");
+                send_out(
+                    Some(vec![error_reply]),
+                    allocator,
+                    msg_ids,
+                    report,
+                    &mut out,
+                    options,
+                )?;
+            } else if capabilities.is_empty() {
+                let error_reply =
+                    msg.error_reply(error_codes::NOT_SUPPORTED, "No capabilities advertised");
+                send_out(
+                    Some(vec![error_reply]),
+                    allocator,
+                    msg_ids,
+                    report,
+                    &mut out,
+                    options,
+                )?;
+            } else {
+                let reply = msg.reply_with(
+                    None,
+                    serde_json::json!({
+                        "type": "capabilities_ok",
+                        "capabilities": capabilities,
+                    }),
+                );
+                send_out(
+                    Some(vec![reply]),
+                    allocator,
+                    msg_ids,
+                    report,
+                    &mut out,
+                    options,
+                )?;
+            }
+            return Ok(Admitted::Handled(out));
+        }
+        if let Some(view) = init_view(&msg) {
+            let initialized = processor.on_init(view.node_id.clone(), view.node_ids.clone());
+            let ok = initialized.is_ok();
+            match initialized {
+                std::result::Result::Ok(()) => {
+                    report.processed += 1;
+                    *cluster_view = Some(view);
+                    let init_ok = msg.reply_with(None, serde_json::json!({ "type": "init_ok" }));
+                    send_out(
+                        Some(vec![init_ok]),
+                        allocator,
+                        msg_ids,
+                        report,
+                        &mut out,
+                        options,
+                    )?;
+                }
+                Err(e) => {
+                    report.failed += 1;
+                    let error_reply = failure_reply(request_envelope(&msg), e, dead_letters, raw);
+                    send_out(
+                        error_reply.map(|reply| vec![reply]),
+                        allocator,
+                        msg_ids,
+                        report,
+                        &mut out,
+                        options,
+                    )?;
+                }
+            }
+            return Ok(Admitted::Initialized { out, ok });
+        }
+        let raw_body = options.strict.then(|| msg.body.body.clone());
+        let msg = match typed::<MessageType>(msg) {
+            std::result::Result::Ok(msg) => msg,
+            Err(e) => {
+                report.failed += 1;
+                eprintln!("Unknown message : {}", e);
+                if let Some(dead_letters) = dead_letters {
+                    dead_letters.record(e.to_string(), raw);
+                }
+                return Ok(Admitted::Handled(out));
+            }
+        };
+        if let Some(raw_body) = raw_body {
+            let unknown_fields = unknown_fields(&raw_body, &msg);
+            if !unknown_fields.is_empty() {
+                report.rejected += 1;
+                let error_reply = msg.error_reply(
+                    error_codes::MALFORMED_REQUEST,
+                    format!("Unknown fields {:?}", unknown_fields),
+                );
+                send_out(
+                    Some(vec![error_reply]),
+                    allocator,
+                    msg_ids,
+                    report,
+                    &mut out,
+                    options,
+                )?;
+                return Ok(Admitted::Handled(out));
+            }
+        }
+        Ok(Admitted::Process { msg, received_at })
+    }
+
+    // sends what the node answered once the reply latency floor is reached
+    fn complete<MessageType, W>(
+        maybe_msg_result: Processed<MessageType>,
+        received_at: Instant,
+        allocator: &MessageIdAllocator,
+        msg_ids: &mut MsgIdTracker,
+        report: &mut RunReport,
+        output: &mut W,
+        options: &RunnerOptions,
+    ) -> Result<()>
+    where
+        MessageType: serde::Serialize,
+        W: Write,
+    {
+        let maybe_msg = match maybe_msg_result {
+            std::result::Result::Ok(maybe_msg) => maybe_msg,
+            Err(error_reply) => {
+                report.failed += 1;
+                let error_reply = error_reply.map(|reply| vec![reply]);
+                return send_out(error_reply, allocator, msg_ids, report, output, options);
+            }
+        };
+        report.processed += 1;
+        if let Some(min_latency) = options.min_reply_latency {
+            std::thread::sleep(min_latency.saturating_sub(received_at.elapsed()));
+        }
+        send_out(maybe_msg, allocator, msg_ids, report, output, options)
+    }
+
+    // numbers what goes out, whether the node or the runner sends it, tracking its msg_ids
+    fn send_out<MessageType, W>(
+        maybe_msg: Option<Vec<Message<MessageType>>>,
+        allocator: &MessageIdAllocator,
+        msg_ids: &mut MsgIdTracker,
+        report: &mut RunReport,
+        output: &mut W,
+        options: &RunnerOptions,
+    ) -> Result<()>
+    where
+        MessageType: serde::Serialize,
+        W: Write,
+    {
+        let maybe_msg = outgoing(maybe_msg, allocator, options);
+        let duplicates = msg_ids.track(&maybe_msg);
+        report.duplicate_msg_ids += duplicates.len();
+        if options.check_msg_ids {
+            for msg_id in duplicates {
+                eprintln!("Duplicate msg_id sent : {}", msg_id);
+            }
+        }
+        serialize(maybe_msg, output, options)
+    }
+
+    // messages are read untyped first so the runner can look at them before the node does
+    fn messages<'a, R>(
+        input: R,
+        options: &RunnerOptions,
+    ) -> Box<dyn Iterator<Item = serde_json::Result<Value>> + 'a>
+    where
+        R: Read + 'a,
+    {
+        let input = match options.read_buffer_size {
+            Some(capacity) => BufReader::with_capacity(capacity, input),
+            None => BufReader::new(input),
+        };
+        match options.max_line_length {
+            Some(max_line_length) => Box::new(BoundedLines {
+                input,
+                max_line_length,
+            }),
+            None => Box::new(serde_json::Deserializer::from_reader(input).into_iter()),
+        }
+    }
+
+    // beyond this integers can't be told apart once read as doubles, e.g. in JavaScript
+    const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+    fn is_large(n: &serde_json::Number) -> bool {
+        match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => i.unsigned_abs() > MAX_SAFE_INTEGER,
+            (None, Some(_)) => true,
+            (None, None) => false,
+        }
+    }
+
+    // large integers written as strings, anywhere in the message
+    fn stringify_large_ints(value: &mut Value) {
+        match value {
+            Value::Number(n) if is_large(n) => *value = Value::String(n.to_string()),
+            Value::Array(values) => values.iter_mut().for_each(stringify_large_ints),
+            Value::Object(fields) => fields.values_mut().for_each(stringify_large_ints),
+            _ => {}
+        }
+    }
+
+    // what is left to do once the input is exhausted, whichever way messages were processed
+    fn shut_down<MessageType, P, W>(
+        processor: &mut P,
+        mut report: RunReport,
+        allocator: &MessageIdAllocator,
+        output: &mut W,
+        options: &RunnerOptions,
+    ) -> Result<RunReport>
+    where
+        P: Processor<MessageType>,
+        MessageType: serde::Serialize,
+        W: Write,
+    {
+        match processor.on_shutdown() {
+            Result::Ok(maybe_msg) => {
+                serialize(outgoing(maybe_msg, allocator, options), output, options)?
+            }
+            Err(e) => eprintln!("Error shutting down : {:?}", e),
+        }
+        if let Some(dump) = processor.dump() {
+            eprintln!("Node dump : {}", dump);
+        }
+        report.state_bytes = processor.save_state().map(|state| state.to_string().len());
+        eprintln!("Run report : {}", serde_json::json!(report));
+        if let Some(path) = &options.metrics_file {
+            std::fs::write(path, crate::metrics::prometheus(&report))
+                .with_context(|| format!("Write metrics to {}", path.display()))?;
+        }
+        Ok(report)
+    }
+
+    pub fn run<MessageType, P>(processor: &mut P) -> anyhow::Result<()>
+    where
+        MessageType: for<'de> serde::Deserialize<'de>,
+        P: Processor<MessageType>,
+        MessageType: serde::Serialize,
+    {
+        run_with_options(processor, &RunnerOptions::from_env())
+    }
+
+    pub fn run_with_options<MessageType, P>(
+        processor: &mut P,
+        options: &RunnerOptions,
+    ) -> anyhow::Result<()>
+    where
+        MessageType: for<'de> serde::Deserialize<'de>,
+        P: Processor<MessageType>,
+        MessageType: serde::Serialize,
+    {
+        let stdin = std::io::stdin().lock();
+        let mut stdout = std::io::stdout().lock();
+        run_with(processor, stdin, &mut stdout, options)
+    }
+
+    pub fn run_with<MessageType, P, R, W>(
+        processor: &mut P,
+        input: R,
+        output: &mut W,
+        options: &RunnerOptions,
+    ) -> anyhow::Result<()>
+    where
+        MessageType: for<'de> serde::Deserialize<'de>,
+        P: Processor<MessageType>,
+        MessageType: serde::Serialize,
+        R: Read,
+        W: Write,
+    {
+        run_with_report(processor, input, output, options).map(|_| ())
+    }
+
+    /// Runs like `run_with`, also returning the report logged once the input is exhausted.
+    pub fn run_with_report<MessageType, P, R, W>(
+        processor: &mut P,
+        input: R,
+        output: &mut W,
+        options: &RunnerOptions,
+    ) -> anyhow::Result<RunReport>
+    where
+        MessageType: for<'de> serde::Deserialize<'de>,
+        P: Processor<MessageType>,
+        MessageType: serde::Serialize,
+        R: Read,
+        W: Write,
+    {
+        run_with_clock(processor, input, output, options, &SystemClock)
+    }
+
+    /// Runs like `run_with_report`, phases being timed with `clock` when profiling.
+    pub fn run_with_clock<MessageType, P, R, W>(
+        processor: &mut P,
+        input: R,
+        output: &mut W,
+        options: &RunnerOptions,
+        clock: &dyn Clock,
+    ) -> anyhow::Result<RunReport>
+    where
+        MessageType: for<'de> serde::Deserialize<'de>,
+        P: Processor<MessageType>,
+        MessageType: serde::Serialize,
+        R: Read,
+        W: Write,
+    {
+        let mut timer = PhaseTimer {
+            clock,
+            times: options.profile_phases.then(PhaseTimes::default),
+        };
+        let mut report = RunReport::default();
+        let mut ready = false;
+        let mut restarting = false;
+        let allocator = MessageIdAllocator::default();
+        processor.use_msg_ids(allocator.clone());
+        let rpcs = RpcRegistry::new(allocator.clone());
+        processor.use_rpcs(rpcs.clone());
+        let mut msg_ids = MsgIdTracker::new(allocator.clone(), options.check_msg_ids);
+        let mut cluster_view: Option<ClusterView> = None;
+        let dead_letters = options
+            .dead_letter_file
+            .as_deref()
+            .map(DeadLetters::open)
+            .transpose()?;
+        let mut messages = messages(input, options);
+        while let Some(msg) = timer.time(Phase::Read, || messages.next()) {
+            let admitted = timer.time(Phase::Read, || {
+                admit(
+                    msg,
+                    processor,
+                    &mut cluster_view,
+                    &allocator,
+                    &mut msg_ids,
+                    &mut report,
+                    dead_letters.as_ref(),
+                    options,
+                )
+            })?;
+            match admitted {
+                Admitted::Handled(out) => timer.time(Phase::Write, || output.write_all(&out))?,
+                Admitted::Initialized { out, ok } => {
+                    timer.time(Phase::Write, || output.write_all(&out))?;
+                    if options.init_first {
+                        output.flush()?;
+                    }
+                    if ok && (!ready || restarting) {
+                        ready = true;
+                        restarting = false;
+                        processor.on_ready();
+                    }
+                }
+                Admitted::Process { msg, .. } if restarting => {
+                    let out = awaiting_init(&msg, &allocator, &mut msg_ids, &mut report, options)?;
+                    timer.time(Phase::Write, || output.write_all(&out))?;
+                }
+                Admitted::Process { msg, received_at } => {
+                    let maybe_msg_result = timer.time(Phase::Process, || {
+                        process_recorded(
+                            processor,
+                            msg,
+                            &rpcs,
+                            dead_letters.as_ref(),
+                            Some(&mut restarting),
+                        )
+                    });
+                    timer.time(Phase::Write, || {
+                        complete(
+                            maybe_msg_result,
+                            received_at,
+                            &allocator,
+                            &mut msg_ids,
+                            &mut report,
+                            output,
+                            options,
+                        )
+                    })?;
+                }
+            }
+        }
+        report.phases = timer.times;
+        shut_down(processor, report, &allocator, output, options)
+    }
+
+    // a closure standing in for a node
+    struct FnProcessor<F>(F);
+
+    impl<MessageType, F> Processor<MessageType> for FnProcessor<F>
+    where
+        F: FnMut(Message<MessageType>) -> ProcessResult<MessageType>,
+    {
+        fn process(&mut self, msg: Message<MessageType>) -> ProcessResult<MessageType> {
+            (self.0)(msg)
+        }
+    }
+
+    /// Runs a closure processing every message, for experiments not worth a `Processor`,
+    /// with the options set through the environment.
+    pub fn run_fn<MessageType, F, R, W>(f: F, input: R, output: &mut W) -> anyhow::Result<()>
+    where
+        MessageType: for<'de> serde::Deserialize<'de>,
+        MessageType: serde::Serialize,
+        F: FnMut(Message<MessageType>) -> ProcessResult<MessageType>,
+        R: Read,
+        W: Write,
+    {
+        run_with(
+            &mut FnProcessor(f),
+            input,
+            output,
+            &RunnerOptions::from_env(),
+        )
+    }
+
+    pub fn run_parallel<MessageType, P>(processor: &mut P) -> anyhow::Result<()>
+    where
+        MessageType: for<'de> serde::Deserialize<'de>,
+        P: StatelessProcessor<MessageType>,
+        MessageType: serde::Serialize + Send,
+    {
+        let options = RunnerOptions::from_env();
+        let mut stdout = std::io::stdout().lock();
+        run_parallel_with(processor, std::io::stdin(), &mut stdout, &options).map(|_| ())
+    }
+
+    /// Runs like `run_with_report`, except that every message but init is processed by a
+    /// copy of the node, on one of `options.workers` threads, answers still being sent in
+    /// the order messages were read. Copies share the node's msg_ids and pending rpcs.
+    pub fn run_parallel_with<MessageType, P, R, W>(
+        processor: &mut P,
+        input: R,
+        output: &mut W,
+        options: &RunnerOptions,
+    ) -> anyhow::Result<RunReport>
+    where
+        MessageType: for<'de> serde::Deserialize<'de>,
+        P: StatelessProcessor<MessageType>,
+        MessageType: serde::Serialize + Send,
+        R: Read + Send,
+        W: Write,
+    {
+        let workers = options.workers.unwrap_or_else(|| {
+            std::thread::available_parallelism().map_or(1, |workers| workers.get())
+        });
+        let allocator = MessageIdAllocator::default();
+        processor.use_msg_ids(allocator.clone());
+        let rpcs = RpcRegistry::new(allocator.clone());
+        processor.use_rpcs(rpcs.clone());
+        let msg_ids = Mutex::new(MsgIdTracker::new(allocator.clone(), options.check_msg_ids));
+        let dead_letters = options
+            .dead_letter_file
+            .as_deref()
+            .map(DeadLetters::open)
+            .transpose()?;
+        let dead_letters = dead_letters.as_ref();
+        let rpcs = &rpcs;
+        let node = &mut *processor;
+        let (jobs, queue) = mpsc::channel::<(usize, P, Message<MessageType>, Instant)>();
+        let queue = Mutex::new(queue);
+        let (done, outcomes) = mpsc::channel::<(usize, Outcome<MessageType>)>();
+        let (read_report, written_report) = std::thread::scope(|scope| {
+            for _ in 0..workers {
+                let done = done.clone();
+                let queue = &queue;
+                scope.spawn(move || loop {
+                    let job = queue.lock().unwrap().recv();
+                    let Result::Ok((seq, mut copy, msg, received_at)) = job else {
+                        return;
+                    };
+                    let result = process_recorded(&mut copy, msg, rpcs, dead_letters, None);
+                    if done
+                        .send((seq, Outcome::Processed(result, received_at)))
+                        .is_err()
+                    {
+                        return;
+                    }
+                });
+            }
+            let msg_ids = &msg_ids;
+            let allocator = &allocator;
+            let reader = scope.spawn(move || {
+                read_admitted(
+                    input,
+                    node,
+                    allocator,
+                    msg_ids,
+                    dead_letters,
+                    options,
+                    &done,
+                    |node, seq, msg, received_at| {
+                        jobs.send((seq, node.clone(), msg, received_at)).is_ok()
+                    },
+                )
+            });
+            let written = write_in_order(outcomes, allocator, msg_ids, output, options);
+            let read = reader.join().expect("Reader thread panicked");
+            read.and_then(|read| written.map(|written| (read, written)))
+        })?;
+        let report = merge_reports(read_report, written_report);
+        shut_down(processor, report, &allocator, output, options)
+    }
+
+    pub fn run_sharded<MessageType, P>(processor: &mut P) -> anyhow::Result<()>
+    where
+        MessageType: for<'de> serde::Deserialize<'de>,
+        P: ShardedProcessor<MessageType>,
+        MessageType: serde::Serialize + Send,
+    {
+        let options = RunnerOptions::from_env();
+        let mut stdout = std::io::stdout().lock();
+        run_sharded_with(processor, std::io::stdin(), &mut stdout, &options).map(|_| ())
+    }
+
+    /// Runs like `run_with_report`, except that every message but init is processed by one of
+    /// `options.workers` copies of the node (shards), each on a thread of its own, answers
+    /// still being sent in the order messages were read. Keys are spread over the shards in
+    /// the order they are first seen, every message for a key going to the same shard; the
+    /// shards are made once the first message past init is read, later inits only reaching
+    /// the node itself.
+    pub fn run_sharded_with<MessageType, P, R, W>(
+        processor: &mut P,
+        input: R,
+        output: &mut W,
+        options: &RunnerOptions,
+    ) -> anyhow::Result<RunReport>
+    where
+        MessageType: for<'de> serde::Deserialize<'de>,
+        P: ShardedProcessor<MessageType>,
+        MessageType: serde::Serialize + Send,
+        R: Read + Send,
+        W: Write,
+    {
+        let shard_count = options.workers.unwrap_or_else(|| {
+            std::thread::available_parallelism().map_or(1, |workers| workers.get())
+        });
+        let allocator = MessageIdAllocator::default();
+        processor.use_msg_ids(allocator.clone());
+        let rpcs = RpcRegistry::new(allocator.clone());
+        processor.use_rpcs(rpcs.clone());
+        let msg_ids = Mutex::new(MsgIdTracker::new(allocator.clone(), options.check_msg_ids));
+        let dead_letters = options
+            .dead_letter_file
+            .as_deref()
+            .map(DeadLetters::open)
+            .transpose()?;
+        let dead_letters = dead_letters.as_ref();
+        let rpcs = &rpcs;
+        let node = &mut *processor;
+        let (done, outcomes) = mpsc::channel::<(usize, Outcome<MessageType>)>();
+        let (read_report, written_report, shards) = std::thread::scope(|scope| {
+            let msg_ids = &msg_ids;
+            let allocator = &allocator;
+            let reader = scope.spawn(move || -> Result<(RunReport, Vec<P>)> {
+                let mut shards = Vec::new();
+                let mut assigned: HashMap<String, usize> = HashMap::new();
+                let report = read_admitted(
+                    input,
+                    node,
+                    allocator,
+                    msg_ids,
+                    dead_letters,
+                    options,
+                    &done,
+                    |node, seq, msg, received_at| {
+                        if shards.is_empty() {
+                            shards = (0..shard_count.max(1))
+                                .map(|_| {
+                                    let (jobs, queue) =
+                                        mpsc::channel::<(usize, Message<MessageType>, Instant)>();
+                                    let done = done.clone();
+                                    let mut shard = node.clone();
+                                    let handle = scope.spawn(move || {
+                                        for (seq, msg, received_at) in queue {
+                                            let result = process_recorded(
+                                                &mut shard,
+                                                msg,
+                                                rpcs,
+                                                dead_letters,
+                                                None,
+                                            );
+                                            let outcome = Outcome::Processed(result, received_at);
+                                            if done.send((seq, outcome)).is_err() {
+                                                break;
+                                            }
+                                        }
+                                        shard
+                                    });
+                                    (jobs, handle)
+                                })
+                                .collect();
+                        }
+                        let shard = match node.shard_key(&msg) {
+                            Some(key) => {
+                                let next = assigned.len() % shards.len();
+                                *assigned.entry(key).or_insert(next)
+                            }
+                            None => 0,
+                        };
+                        shards[shard].0.send((seq, msg, received_at)).is_ok()
+                    },
+                )?;
+                // shards are done once what they were given is processed
+                let shards = shards
+                    .into_iter()
+                    .map(|(jobs, handle)| {
+                        drop(jobs);
+                        handle.join().expect("Shard thread panicked")
+                    })
+                    .collect();
+                Ok((report, shards))
+            });
+            let written = write_in_order(outcomes, allocator, msg_ids, output, options);
+            let read = reader.join().expect("Reader thread panicked");
+            read.and_then(|(read, shards)| written.map(|written| (read, written, shards)))
+        })?;
+        for mut shard in shards {
+            match shard.on_shutdown() {
+                Result::Ok(maybe_msg) => {
+                    serialize(outgoing(maybe_msg, &allocator, options), output, options)?
+                }
+                Err(e) => eprintln!("Error shutting down shard : {:?}", e),
+            }
+        }
+        let report = merge_reports(read_report, written_report);
+        shut_down(processor, report, &allocator, output, options)
+    }
+
+    pub fn run_with_timers<MessageType, P>(processor: &mut P) -> anyhow::Result<()>
+    where
+        MessageType: for<'de> serde::Deserialize<'de>,
+        P: Processor<MessageType>,
+        MessageType: serde::Serialize,
+    {
+        let options = RunnerOptions::from_env();
+        let mut stdout = std::io::stdout().lock();
+        run_with_timers_with(processor, std::io::stdin(), &mut stdout, &options).map(|_| ())
+    }
+
+    /// Runs like `run_with_report`, except that the input is read on a thread of its own, so
+    /// that the node's timers can fire while no message comes in. Timers start once the node
+    /// is ready; one falling due while a message is being processed fires right after it.
+    pub fn run_with_timers_with<MessageType, P, R, W>(
+        processor: &mut P,
+        input: R,
+        output: &mut W,
+        options: &RunnerOptions,
+    ) -> anyhow::Result<RunReport>
+    where
+        MessageType: for<'de> serde::Deserialize<'de>,
+        P: Processor<MessageType>,
+        MessageType: serde::Serialize,
+        R: Read + Send,
+        W: Write,
+    {
+        let mut report = RunReport::default();
+        let mut ready = false;
+        let mut restarting = false;
+        let allocator = MessageIdAllocator::default();
+        processor.use_msg_ids(allocator.clone());
+        let rpcs = RpcRegistry::new(allocator.clone());
+        processor.use_rpcs(rpcs.clone());
+        let mut msg_ids = MsgIdTracker::new(allocator.clone(), options.check_msg_ids);
+        let mut cluster_view: Option<ClusterView> = None;
+        let dead_letters = options
+            .dead_letter_file
+            .as_deref()
+            .map(DeadLetters::open)
+            .transpose()?;
+        let timers = processor.timers();
+        let mut due: Vec<Option<Instant>> = vec![None; timers.len()];
+        let (read, received) = mpsc::channel();
+        std::thread::scope(|scope| -> Result<()> {
+            scope.spawn(move || {
+                for msg in messages(input, options) {
+                    if read.send(msg).is_err() {
+                        break;
+                    }
+                }
+            });
+            loop {
+                let now = Instant::now();
+                for ((name, every), due) in timers.iter().zip(due.iter_mut()) {
+                    if !restarting && due.is_some_and(|at| at <= now) {
+                        *due = Some(now + *every);
+                        match processor.on_timer(name) {
+                            Result::Ok(maybe_msg) => send_out(
+                                maybe_msg,
+                                &allocator,
+                                &mut msg_ids,
+                                &mut report,
+                                output,
+                                options,
+                            )?,
+                            Err(e) => eprintln!("Error on timer {} : {:?}", name, e),
+                        }
+                    }
+                }
+                let next_due = due.iter().flatten().min().filter(|_| !restarting);
+                let msg = match next_due {
+                    Some(at) => match received.recv_timeout(at.saturating_duration_since(now)) {
+                        std::result::Result::Ok(msg) => msg,
+                        Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    },
+                    None => match received.recv() {
+                        std::result::Result::Ok(msg) => msg,
+                        Err(_) => break,
+                    },
+                };
+                let admitted = admit(
+                    msg,
+                    processor,
+                    &mut cluster_view,
+                    &allocator,
+                    &mut msg_ids,
+                    &mut report,
+                    dead_letters.as_ref(),
+                    options,
+                )?;
+                match admitted {
+                    Admitted::Handled(out) => output.write_all(&out)?,
+                    Admitted::Initialized { out, ok } => {
+                        output.write_all(&out)?;
+                        if options.init_first {
+                            output.flush()?;
+                        }
+                        if ok && (!ready || restarting) {
+                            ready = true;
+                            restarting = false;
+                            processor.on_ready();
+                            let now = Instant::now();
+                            due = timers.iter().map(|(_, every)| Some(now + *every)).collect();
+                        }
+                    }
+                    Admitted::Process { msg, .. } if restarting => {
+                        let out =
+                            awaiting_init(&msg, &allocator, &mut msg_ids, &mut report, options)?;
+                        output.write_all(&out)?;
+                    }
+                    Admitted::Process { msg, received_at } => {
+                        let maybe_msg_result = process_recorded(
+                            processor,
+                            msg,
+                            &rpcs,
+                            dead_letters.as_ref(),
+                            Some(&mut restarting),
+                        );
+                        complete(
+                            maybe_msg_result,
+                            received_at,
+                            &allocator,
+                            &mut msg_ids,
+                            &mut report,
+                            output,
+                            options,
+                        )?;
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        shut_down(processor, report, &allocator, output, options)
+    }
+
+    // Admits every message read, those left for the node to process being handed to
+    // `dispatch` with their position in the input, until the input ends or the writer gives
+    // up, which `dispatch` tells by returning false.
+    #[allow(clippy::too_many_arguments)]
+    fn read_admitted<MessageType, P, R>(
+        input: R,
+        node: &mut P,
+        allocator: &MessageIdAllocator,
+        msg_ids: &Mutex<MsgIdTracker>,
+        dead_letters: Option<&DeadLetters>,
+        options: &RunnerOptions,
+        done: &mpsc::Sender<(usize, Outcome<MessageType>)>,
+        mut dispatch: impl FnMut(&mut P, usize, Message<MessageType>, Instant) -> bool,
+    ) -> Result<RunReport>
+    where
+        MessageType: for<'de> serde::Deserialize<'de>,
+        MessageType: serde::Serialize,
+        P: Processor<MessageType>,
+        R: Read,
+    {
+        let mut report = RunReport::default();
+        let mut ready = false;
+        let mut cluster_view: Option<ClusterView> = None;
+        for (seq, msg) in messages(input, options).enumerate() {
+            let admitted = admit(
+                msg,
+                node,
+                &mut cluster_view,
+                allocator,
+                &mut msg_ids.lock().unwrap(),
+                &mut report,
+                dead_letters,
+                options,
+            )?;
+            let sent = match admitted {
+                Admitted::Handled(out) => done.send((seq, Outcome::Handled(out, None))).is_ok(),
+                // the node itself is initialized, for every copy made afterwards
+                Admitted::Initialized { out, ok } => {
+                    if ok && !ready {
+                        ready = true;
+                        node.on_ready();
+                    }
+                    let (flushed, on_flushed) = mpsc::channel();
+                    let flushed = options.init_first.then_some(flushed);
+                    // with init_first, nothing more is read until init is answered
+                    done.send((seq, Outcome::Handled(out, flushed))).is_ok()
+                        && (!options.init_first || on_flushed.recv().is_ok())
+                }
+                Admitted::Process { msg, received_at } => dispatch(node, seq, msg, received_at),
+            };
+            // the writer gave up, nothing read from now on would be answered
+            if !sent {
+                break;
+            }
+        }
+        Ok(report)
+    }
+
+    // Writes what became of every message in the order messages were read, whatever the
+    // order they were processed in.
+    fn write_in_order<MessageType, W>(
+        outcomes: mpsc::Receiver<(usize, Outcome<MessageType>)>,
+        allocator: &MessageIdAllocator,
+        msg_ids: &Mutex<MsgIdTracker>,
+        output: &mut W,
+        options: &RunnerOptions,
+    ) -> Result<RunReport>
+    where
+        MessageType: serde::Serialize,
+        W: Write,
+    {
+        let mut report = RunReport::default();
+        let mut next_seq = 0;
+        let mut early = BTreeMap::new();
+        for (seq, outcome) in outcomes {
+            early.insert(seq, outcome);
+            while let Some(outcome) = early.remove(&next_seq) {
+                next_seq += 1;
+                match outcome {
+                    Outcome::Handled(out, flushed) => {
+                        output.write_all(&out)?;
+                        if let Some(flushed) = flushed {
+                            output.flush()?;
+                            // the reader may have given up already
+                            let _ = flushed.send(());
+                        }
+                    }
+                    Outcome::Processed(result, received_at) => {
+                        complete(
+                            result,
+                            received_at,
+                            allocator,
+                            &mut msg_ids.lock().unwrap(),
+                            &mut report,
+                            output,
+                            options,
+                        )?;
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    // what was counted while reading and while writing, for nodes run on several threads
+    fn merge_reports(read_report: RunReport, written_report: RunReport) -> RunReport {
+        RunReport {
+            received: read_report.received,
+            rejected: read_report.rejected,
+            failed: read_report.failed + written_report.failed,
+            // inits are processed as they are read
+            processed: read_report.processed + written_report.processed,
+            duplicate_msg_ids: written_report.duplicate_msg_ids,
+            state_bytes: None,
+            phases: None,
+        }
+    }
+
+    // what became of a message run on several threads, waiting for its turn to be written
+    enum Outcome<MessageType> {
+        // with whom to tell once the answers are written and flushed, if anyone is waiting
+        Handled(Vec<u8>, Option<mpsc::Sender<()>>),
+        Processed(Processed<MessageType>, Instant),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::msg_protocol::*;
+    use crate::runner::*;
+    use serde::{Deserialize, Serialize};
+
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::collections::HashSet;
+    use std::time::{Duration, Instant};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum EchoMessage {
+        Echo { echo: String },
+        EchoOk { echo: String },
+    }
+
+    struct EchoNode;
+
+    impl Processor<EchoMessage> for EchoNode {
+        fn process(
+            &mut self,
+            msg: Message<EchoMessage>,
+        ) -> anyhow::Result<Option<Vec<Message<EchoMessage>>>> {
+            match msg.body.body {
+                EchoMessage::Echo { echo } => Ok(Some(vec![Message {
+                    src: msg.dest,
+                    dest: msg.src,
+                    body: Body {
+                        msg_id: msg.body.msg_id,
+                        in_reply_to: msg.body.msg_id,
+                        body: EchoMessage::EchoOk { echo },
+                    },
+                }])),
+                _ => Err(anyhow::anyhow!("Received unknown message: {:?}", msg)),
+            }
+        }
+    }
+
+    fn run_lines<T>(input: &str, options: &RunnerOptions) -> Vec<Message<T>>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let mut output = vec![];
+        run_with(&mut EchoNode, input.as_bytes(), &mut output, options).unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    const ECHO_LINE: &str =
+        r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hello"}}"#;
+
+    fn echo_ok(msg_id: i64) -> Message<EchoMessage> {
+        Message {
+            src: Some("n1".into()),
+            dest: Some("c1".into()),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: Some(msg_id),
+                body: EchoMessage::EchoOk {
+                    echo: "hello".into(),
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn test_process_result_helpers() {
+        assert_eq!(reply(vec![echo_ok(1)]).unwrap(), Some(vec![echo_ok(1)]));
+        assert_eq!(no_reply::<EchoMessage>().unwrap(), None);
+        assert_eq!(
+            fail::<EchoMessage>(anyhow::anyhow!("boom"))
+                .unwrap_err()
+                .to_string(),
+            "boom"
+        );
+    }
+
+    #[test]
+    fn test_serde_kv_cas() {
+        use crate::msg_protocol::kv::KvMessage;
+
+        let cas: Message<KvMessage> = serde_json::from_str(
+            r#"{"src":"n1","dest":"lin-kv","body":{"type":"cas","msg_id":1,"key":"k","from":1,"to":2,"create_if_not_exists":true}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            cas.body.body,
+            KvMessage::Cas {
+                key: "k".into(),
+                from: 1.into(),
+                to: 2.into(),
+                create_if_not_exists: true,
+            }
+        );
+        let round_trip = serde_json::from_str(&serde_json::to_string(&cas).unwrap()).unwrap();
+        assert_eq!(cas, round_trip);
+    }
+
+    #[test]
+    fn test_kv_read_result() {
+        use crate::msg_protocol::kv::{read_result, KvMessage};
+        use crate::replying::OrError;
+
+        let reply = |body: &str| serde_json::from_str::<OrError<KvMessage>>(body).unwrap();
+
+        assert_eq!(
+            read_result(reply(r#"{"type":"read_ok","value":3}"#)).unwrap(),
+            Some(3.into())
+        );
+        assert_eq!(
+            read_result(reply(r#"{"type":"error","code":20,"text":"not found"}"#)).unwrap(),
+            None
+        );
+        let timeout = read_result(reply(r#"{"type":"error","code":0,"text":"timed out"}"#));
+        assert_eq!(
+            timeout.unwrap_err().downcast::<Error>().unwrap().code,
+            error_codes::TIMEOUT
+        );
+        assert!(read_result(reply(r#"{"type":"write_ok"}"#)).is_err());
+    }
+
+    #[test]
+    fn test_serde_body_omits_absent_ids() {
+        let mut msg = echo_ok(1);
+        msg.body.msg_id = None;
+        msg.body.in_reply_to = None;
+
+        let serialized = serde_json::to_string(&msg).unwrap();
+
+        assert_eq!(
+            serialized,
+            r#"{"src":"n1","dest":"c1","body":{"type":"echo_ok","echo":"hello"}}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Message<EchoMessage>>(&serialized).unwrap(),
+            msg
+        );
+    }
+
+    #[test]
+    fn test_error_reply() {
+        let request: Message<EchoMessage> = serde_json::from_str(ECHO_LINE).unwrap();
+
+        assert_eq!(
+            request.error_reply(error_codes::NOT_SUPPORTED, "nope"),
+            Message {
+                src: Some("n1".into()),
+                dest: Some("c1".into()),
+                body: Body {
+                    msg_id: None,
+                    in_reply_to: Some(1),
+                    body: Error {
+                        code: error_codes::NOT_SUPPORTED,
+                        text: "nope".into(),
+                    },
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_reply_with() {
+        let request: Message<EchoMessage> = serde_json::from_str(ECHO_LINE).unwrap();
+
+        assert_eq!(
+            request.reply_with(
+                Some(7),
+                EchoMessage::EchoOk {
+                    echo: "hello".into()
+                }
+            ),
+            Message {
+                src: Some("n1".into()),
+                dest: Some("c1".into()),
+                body: Body {
+                    msg_id: Some(7),
+                    in_reply_to: Some(1),
+                    body: EchoMessage::EchoOk {
+                        echo: "hello".into()
+                    },
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_run_with_numbers_what_nodes_send_uniquely_and_monotonically() {
+        // gossips every echo to n2 with an id it takes itself, then leaves the answer and a
+        // second gossip for the runner to number
+        #[derive(Default)]
+        struct GossipingNode {
+            msg_ids: MessageIdAllocator,
+        }
+
+        impl Processor<EchoMessage> for GossipingNode {
+            fn process(&mut self, msg: Message<EchoMessage>) -> ProcessResult<EchoMessage> {
+                let EchoMessage::Echo { ref echo } = msg.body.body else {
+                    return no_reply();
+                };
+                let gossip = Message {
+                    src: msg.dest.clone(),
+                    dest: Some("n2".into()),
+                    body: Body {
+                        msg_id: Some(self.msg_ids.next()),
+                        in_reply_to: None,
+                        body: EchoMessage::Echo { echo: echo.clone() },
+                    },
+                };
+                let echo_ok = msg.reply_with(None, EchoMessage::EchoOk { echo: echo.clone() });
+                let unnumbered = Message {
+                    src: msg.dest.clone(),
+                    dest: Some("n2".into()),
+                    body: Body {
+                        msg_id: None,
+                        in_reply_to: None,
+                        body: EchoMessage::Echo { echo: echo.clone() },
+                    },
+                };
+                reply(vec![gossip, echo_ok, unnumbered])
+            }
+
+            fn use_msg_ids(&mut self, msg_ids: MessageIdAllocator) {
+                self.msg_ids = msg_ids;
+            }
+        }
+
+        let input = [
+            crate::testkit::fixtures::init_line("n1", &["n1", "n2"]),
+            ECHO_LINE.to_string(),
+            r#"{"src":"c1","dest":"n1","body":{"type":"cluster_view","msg_id":2}}"#.into(),
+            ECHO_LINE.replace(r#""msg_id":1"#, r#""msg_id":3"#),
+            ECHO_LINE.replace(r#""msg_id":1"#, r#""msg_id":4"#),
+        ]
+        .join("\n");
+        let mut output = vec![];
+        run_with(
+            &mut GossipingNode::default(),
+            input.as_bytes(),
+            &mut output,
+            &RunnerOptions::default(),
+        )
+        .unwrap();
+
+        let msg_ids: Vec<i64> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Message<serde_json::Value>>(line).unwrap())
+            .filter_map(|msg| msg.body.msg_id)
+            .collect();
+        // init_ok and cluster_view_ok, answered by the runner, are numbered along
+        assert_eq!(msg_ids, (1..=11).collect::<Vec<i64>>());
+    }
+
+    #[test]
+    fn test_run_with_answers_failures_with_error_replies() {
+        struct FailingNode;
+
+        impl Processor<EchoMessage> for FailingNode {
+            fn process(&mut self, msg: Message<EchoMessage>) -> ProcessResult<EchoMessage> {
+                match msg.body.msg_id {
+                    Some(1) => fail(Error {
+                        code: error_codes::KEY_DOES_NOT_EXIST,
+                        text: "no such key".into(),
+                    }),
+                    _ => fail(anyhow::anyhow!("boom")),
+                }
+            }
+        }
+
+        let lines = [
+            ECHO_LINE,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"hello"}}"#,
+            // nobody waits for an answer to a message without msg_id
+            r#"{"src":"n2","dest":"n1","body":{"type":"echo","echo":"hello"}}"#,
+        ];
+        let input = lines.join("\n");
+        let mut output = vec![];
+        let report = run_with_report(
+            &mut FailingNode,
+            input.as_bytes(),
+            &mut output,
+            &RunnerOptions::default(),
+        )
+        .unwrap();
+
+        let replies: Vec<Message<Error>> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let request = |line| serde_json::from_str::<Message<EchoMessage>>(line).unwrap();
+        let numbered = |mut reply: Message<Error>, msg_id| {
+            reply.body.msg_id = Some(msg_id);
+            reply
+        };
+        assert_eq!(
+            replies,
+            vec![
+                numbered(
+                    request(lines[0]).error_reply(error_codes::KEY_DOES_NOT_EXIST, "no such key"),
+                    1
+                ),
+                numbered(request(lines[1]).error_reply(error_codes::CRASH, "boom"), 2),
+            ]
+        );
+        assert_eq!(report.failed, 3);
+    }
+
+    #[test]
+    fn test_run_with_clock_profiles_phases() {
+        use crate::clock::ManualClock;
+
+        // every phase makes the clock advance by its own step, telling them apart
+        struct SlowInput<'a> {
+            input: &'a [u8],
+            clock: ManualClock,
+        }
+
+        impl std::io::Read for SlowInput<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.clock.advance(Duration::from_millis(1));
+                self.input.read(buf)
+            }
+        }
+
+        struct SlowNode(ManualClock);
+
+        impl Processor<EchoMessage> for SlowNode {
+            fn process(&mut self, msg: Message<EchoMessage>) -> ProcessResult<EchoMessage> {
+                self.0.advance(Duration::from_millis(10));
+                EchoNode.process(msg)
+            }
+        }
+
+        struct SlowOutput(ManualClock);
+
+        impl std::io::Write for SlowOutput {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.advance(Duration::from_millis(100));
+                std::io::Result::Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                std::io::Result::Ok(())
+            }
+        }
+
+        let clock = ManualClock::default();
+        let input = [ECHO_LINE, ECHO_LINE].join("\n");
+        let options = RunnerOptions {
+            profile_phases: true,
+            ..Default::default()
+        };
+
+        let report = run_with_clock(
+            &mut SlowNode(clock.clone()),
+            SlowInput {
+                input: input.as_bytes(),
+                clock: clock.clone(),
+            },
+            &mut SlowOutput(clock.clone()),
+            &options,
+            &clock,
+        )
+        .unwrap();
+
+        let phases = report.phases.unwrap();
+        // the input is read in as many chunks as the buffer takes, then up to its end
+        assert!(phases.read_us >= 1_000 && phases.read_us < 10_000);
+        assert_eq!(phases.process_us, 20_000);
+        assert_eq!(phases.write_us, 200_000);
+    }
+
+    #[test]
+    fn test_run_with_min_reply_latency() {
+        let options = RunnerOptions {
+            min_reply_latency: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+
+        let started_at = Instant::now();
+        let replies = run_lines::<EchoMessage>(ECHO_LINE, &options);
+
+        assert!(started_at.elapsed() >= Duration::from_millis(20));
+        assert_eq!(replies, vec![echo_ok(1)]);
+    }
+
+    #[test]
+    fn test_run_with_rejects_message_without_src() {
+        let input = [
+            r#"{"src":null,"dest":"n1","body":{"type":"echo","msg_id":2,"echo":"hello"}}"#,
+            ECHO_LINE,
+        ]
+        .join("\n");
+
+        let replies = run_lines::<EchoMessage>(&input, &RunnerOptions::default());
+
+        assert_eq!(replies, vec![echo_ok(1)]);
+    }
+
+    #[test]
+    fn test_run_with_rejects_unsupported_version() {
+        let options = RunnerOptions {
+            supported_versions: Some(HashSet::from_iter(vec!["1".to_string()])),
+            ..Default::default()
+        };
+        let init = |version: &str| {
+            format!(
+                r#"{{"src":"c0","dest":"n1","body":{{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"],"version":{}}}}}"#,
+                version
+            )
+        };
+        let input = [init(r#""2""#), init(r#""1""#)].join("\n");
+
+        let replies = run_lines::<serde_json::Value>(&input, &options);
+
+        assert_eq!(
+            serde_json::from_value::<Error>(replies[0].body.body.clone()).unwrap(),
+            Error {
+                code: error_codes::NOT_SUPPORTED,
+                text: "Unsupported workload version 2".into(),
+            }
+        );
+        // the supported one goes through
+        assert_eq!(replies[1].body.body, json!({"type": "init_ok"}));
+        assert_eq!(replies.len(), 2);
+    }
+
+    #[test]
+    fn test_run_with_rejects_message_without_type() {
+        let input = [
+            r#"{"src":"c1","dest":"n1","body":{"msg_id":1,"echo":"hello"}}"#,
+            r#"{"dest":"n1","body":{"msg_id":2,"echo":"hello"}}"#,
+        ]
+        .join("\n");
+
+        // the message without a src can't be answered and is only logged
+        assert_eq!(
+            run_lines::<Error>(&input, &RunnerOptions::default()),
+            vec![Message {
+                src: Some("n1".into()),
+                dest: Some("c1".into()),
+                body: Body {
+                    msg_id: Some(1),
+                    in_reply_to: Some(1),
+                    body: Error {
+                        code: error_codes::MALFORMED_REQUEST,
+                        text: "Missing message type".into(),
+                    },
+                },
+            }]
+        );
+    }
+
+    #[test]
+    fn test_run_with_unknown_fields_in_strict_and_lenient_modes() {
+        let input = r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hello","extra":1}}"#;
+
+        let strict = RunnerOptions {
+            strict: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            run_lines::<Error>(input, &strict),
+            vec![Message {
+                src: Some("n1".into()),
+                dest: Some("c1".into()),
+                body: Body {
+                    msg_id: Some(1),
+                    in_reply_to: Some(1),
+                    body: Error {
+                        code: error_codes::MALFORMED_REQUEST,
+                        text: r#"Unknown fields ["extra"]"#.into(),
+                    },
+                },
+            }]
+        );
+        assert_eq!(
+            run_lines::<EchoMessage>(ECHO_LINE, &strict),
+            vec![echo_ok(1)]
+        );
+
+        let lenient = RunnerOptions::default();
+        assert_eq!(run_lines::<EchoMessage>(input, &lenient), vec![echo_ok(1)]);
+    }
+
+    #[test]
+    fn test_run_with_skips_lines_over_max_length() {
+        let options = RunnerOptions {
+            max_line_length: Some(ECHO_LINE.len()),
+            ..Default::default()
+        };
+        let too_long = ECHO_LINE.replace("hello", &"x".repeat(1000));
+        // lines are parsed as they are read, one failing to parse midway skipped as a whole
+        let input = [
+            too_long.as_str(),
+            r#"{"src": oops, "dest": "n1"}"#,
+            &ECHO_LINE.replace(r#""msg_id":1"#, r#""msg_id":2"#),
+            &too_long,
+        ]
+        .join("\n");
+
+        assert_eq!(run_lines::<EchoMessage>(&input, &options), vec![echo_ok(2)]);
+    }
+
+    #[test]
+    fn test_run_with_ignores_replies_to_unknown_msg_ids() {
+        let reply_to = |msg_id: i64, in_reply_to: i64| {
+            format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"echo","msg_id":{},"in_reply_to":{},"echo":"hello"}}}}"#,
+                msg_id, in_reply_to
+            )
+        };
+        let input = [
+            reply_to(1, -1),
+            reply_to(2, 42),
+            ECHO_LINE.replace(r#""msg_id":1"#, r#""msg_id":3"#),
+            reply_to(4, 3),
+        ]
+        .join("\n");
+
+        let answered = |options: &RunnerOptions| -> Vec<Option<i64>> {
+            run_lines::<serde_json::Value>(&input, options)
+                .iter()
+                .map(|r| r.body.in_reply_to)
+                .collect()
+        };
+
+        let checking = RunnerOptions {
+            check_msg_ids: true,
+            ..Default::default()
+        };
+        assert_eq!(answered(&checking), vec![Some(3), Some(4)]);
+        // only bounded by the allocator otherwise, which the echo node picking its own
+        // msg_ids never used
+        assert_eq!(answered(&RunnerOptions::default()), vec![Some(3)]);
+    }
+
+    #[test]
+    fn test_run_with_tracks_what_the_runner_answers() {
+        let checking = RunnerOptions {
+            check_msg_ids: true,
+            ..Default::default()
+        };
+        let input = [
+            crate::testkit::fixtures::init_line("n1", &["n1"]),
+            // a reply to init_ok, which took msg_id 1
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"in_reply_to":1,"echo":"hello"}}"#.into(),
+        ]
+        .join("\n");
+
+        let replies = run_lines::<serde_json::Value>(&input, &checking);
+
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].body.msg_id, Some(1));
+        assert_eq!(replies[1].body.in_reply_to, Some(2));
+    }
+
+    #[test]
+    fn test_run_with_lenient_serialization() {
+        #[derive(Debug, Serialize, Deserialize)]
+        #[serde(tag = "type", rename_all = "snake_case")]
+        enum SqrtMessage {
+            Sqrt {
+                value: f64,
+            },
+            SqrtOk {
+                value: f64,
+                // maps keyed by anything but strings or numbers can't be JSON
+                #[serde(default)]
+                roots: HashMap<Vec<i64>, f64>,
+            },
+        }
+        struct SqrtNode;
+        impl Processor<SqrtMessage> for SqrtNode {
+            fn process(&mut self, msg: Message<SqrtMessage>) -> ProcessResult<SqrtMessage> {
+                let SqrtMessage::Sqrt { value } = msg.body.body else {
+                    return no_reply();
+                };
+                let roots = match value {
+                    0.0 => HashMap::from([(vec![0], 0.0)]),
+                    _ => HashMap::new(),
+                };
+                reply(vec![Message {
+                    src: msg.dest,
+                    dest: msg.src,
+                    body: Body {
+                        msg_id: msg.body.msg_id,
+                        in_reply_to: msg.body.msg_id,
+                        body: SqrtMessage::SqrtOk {
+                            value: value.sqrt(),
+                            roots,
+                        },
+                    },
+                }])
+            }
+        }
+        let sqrt = |msg_id: i64, value: f64| {
+            format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"sqrt","msg_id":{},"value":{}}}}}"#,
+                msg_id, value
+            )
+        };
+        let input = [sqrt(1, 4.0), sqrt(2, -1.0), sqrt(3, 0.0), sqrt(4, 9.0)].join("\n");
+        let options = RunnerOptions {
+            lenient_serialization: true,
+            ..Default::default()
+        };
+
+        let mut output = vec![];
+        run_with(&mut SqrtNode, input.as_bytes(), &mut output, &options).unwrap();
+
+        let bodies: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["body"].clone())
+            .collect();
+        let values: Vec<&serde_json::Value> = bodies.iter().map(|body| &body["value"]).collect();
+        assert_eq!(
+            values,
+            [&json!(2.0), &json!(null), &json!(0.0), &json!(3.0)]
+        );
+        // the reply with roots JSON can't have still goes out, without them
+        assert_eq!(
+            bodies[2],
+            json!({"msg_id": 3, "in_reply_to": 3, "type": "sqrt_ok", "value": 0.0, "roots": null})
+        );
+        assert_eq!(bodies[3]["roots"], json!({}));
+    }
+
+    #[test]
+    fn test_run_with_answers_init_once_the_node_took_it_in() {
+        #[derive(Default)]
+        struct InitRecorder {
+            inits: Vec<(String, Vec<String>)>,
+        }
+
+        impl Processor<EchoMessage> for InitRecorder {
+            fn process(&mut self, msg: Message<EchoMessage>) -> ProcessResult<EchoMessage> {
+                EchoNode.process(msg)
+            }
+
+            fn on_init(&mut self, node_id: String, node_ids: Vec<String>) -> anyhow::Result<()> {
+                anyhow::ensure!(self.inits.is_empty(), "Already initialized");
+                self.inits.push((node_id, node_ids));
+                Ok(())
+            }
+        }
+
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}"#;
+        let input = [init, init].join("\n");
+        let mut processor = InitRecorder::default();
+        let mut output = vec![];
+
+        run_with(
+            &mut processor,
+            input.as_bytes(),
+            &mut output,
+            &RunnerOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            processor.inits,
+            vec![("n1".to_string(), vec!["n1".to_string(), "n2".to_string()])]
+        );
+        let replies: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            replies,
+            vec![
+                json!({"src": "n1", "dest": "c0", "body": {
+                    "msg_id": 1, "in_reply_to": 1, "type": "init_ok"
+                }}),
+                json!({"src": "n1", "dest": "c0", "body": {
+                    "msg_id": 2, "in_reply_to": 1, "type": "error", "code": 13,
+                    "text": "Already initialized"
+                }}),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_run_with_answers_cluster_view_from_init() {
+        let cluster_view = r#"{"src":"c1","dest":"n1","body":{"type":"cluster_view","msg_id":2}}"#;
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2","n3"]}}"#;
+        let input = [cluster_view, init, cluster_view].join("\n");
+
+        let replies = run_lines::<serde_json::Value>(&input, &RunnerOptions::default());
+
+        assert_eq!(replies.len(), 3);
+        assert_eq!(
+            replies[0].body.body["code"],
+            json!(error_codes::TEMPORARILY_UNAVAILABLE)
+        );
+        assert_eq!(replies[1].body.body, json!({"type": "init_ok"}));
+        assert_eq!(
+            serde_json::from_value::<ClusterView>(replies[2].body.body.clone()).unwrap(),
+            ClusterView {
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into(), "n2".into(), "n3".into()],
+                cluster_size: 3,
+            }
+        );
+        assert_eq!(replies[2].body.in_reply_to, Some(2));
+    }
+
+    #[test]
+    fn test_run_fn_with_echoing_closure() {
+        let echo = |msg: Message<EchoMessage>| match msg.body.body {
+            EchoMessage::Echo { echo } => reply(vec![Message {
+                src: msg.dest,
+                dest: msg.src,
+                body: Body {
+                    msg_id: msg.body.msg_id,
+                    in_reply_to: msg.body.msg_id,
+                    body: EchoMessage::EchoOk { echo },
+                },
+            }]),
+            _ => no_reply(),
+        };
+        let mut output = vec![];
+
+        run_fn(echo, ECHO_LINE.as_bytes(), &mut output).unwrap();
+
+        let replies: Vec<Message<EchoMessage>> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(replies, vec![echo_ok(1)]);
+    }
+
+    #[test]
+    fn test_run_with_acks_first() {
+        // gossips every echo to n2 before answering it
+        struct GossipingEchoNode;
+
+        impl Processor<EchoMessage> for GossipingEchoNode {
+            fn process(&mut self, msg: Message<EchoMessage>) -> ProcessResult<EchoMessage> {
+                let gossip = Message {
+                    src: msg.dest.clone(),
+                    dest: Some("n2".into()),
+                    body: Body {
+                        msg_id: Some(2),
+                        in_reply_to: None,
+                        body: msg.body.body.clone(),
+                    },
+                };
+                let mut replies = EchoNode.process(msg)?.unwrap_or_default();
+                replies.insert(0, gossip);
+                reply(replies)
+            }
+        }
+        let types = |options: &RunnerOptions| {
+            let mut output = vec![];
+            run_with(
+                &mut GossipingEchoNode,
+                ECHO_LINE.as_bytes(),
+                &mut output,
+                options,
+            )
+            .unwrap();
+            String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .map(|line| {
+                    serde_json::from_str::<serde_json::Value>(line).unwrap()["body"]["type"].clone()
+                })
+                .collect::<Vec<serde_json::Value>>()
+        };
+
+        assert_eq!(
+            types(&RunnerOptions::default()),
+            vec![json!("echo"), json!("echo_ok")]
+        );
+        assert_eq!(
+            types(&RunnerOptions {
+                reply_order: ReplyOrder::AcksFirst,
+                ..RunnerOptions::default()
+            }),
+            vec![json!("echo_ok"), json!("echo")]
+        );
+    }
+
+    #[test]
+    fn test_run_with_auth_token() {
+        let from_node = |token: &str| {
+            format!(
+                r#"{{"src":"n2","dest":"n1","body":{{"type":"echo","msg_id":1,"echo":"hello"{}}}}}"#,
+                token
+            )
+        };
+        let input = [
+            from_node(""),
+            from_node(r#","token":"wrong""#),
+            from_node(r#","token":"secret""#),
+            ECHO_LINE.to_string(),
+        ]
+        .join("\n");
+        let options = RunnerOptions {
+            auth_token: Some("secret".into()),
+            strict: true,
+            ..RunnerOptions::default()
+        };
+        let mut output = vec![];
+
+        run_with(&mut EchoNode, input.as_bytes(), &mut output, &options).unwrap();
+
+        let replies: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(replies.len(), 4);
+        for rejected in &replies[..2] {
+            assert_eq!(rejected["body"]["type"], "error");
+            assert_eq!(
+                rejected["body"]["code"],
+                error_codes::TEMPORARILY_UNAVAILABLE
+            );
+        }
+        assert_eq!(replies[2]["body"]["type"], "echo_ok");
+        assert_eq!(replies[2]["body"]["token"], "secret");
+        // clients aren't nodes, they need no token and get none
+        assert_eq!(replies[3]["body"]["type"], "echo_ok");
+        assert_eq!(replies[3]["body"].get("token"), None);
+    }
+
+    #[test]
+    fn test_run_with_large_ints_as_strings() {
+        // 2^53 + 1, which would be read back as 2^53 through a double
+        let input = [
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":"9007199254740993","echo":"x"}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"2"}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":3,"echo":"12345678901234567890"}}"#,
+        ]
+        .join("\n");
+        let options = RunnerOptions {
+            large_ints_as_strings: true,
+            ..RunnerOptions::default()
+        };
+        let mut output = vec![];
+
+        run_with(&mut EchoNode, input.as_bytes(), &mut output, &options).unwrap();
+
+        let replies: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(replies[0]["body"]["msg_id"], json!("9007199254740993"));
+        assert_eq!(replies[0]["body"]["in_reply_to"], json!("9007199254740993"));
+        assert_eq!(replies[1]["body"]["in_reply_to"], json!(2));
+        // small integers are left alone either way, strings or not
+        assert_eq!(replies[1]["body"]["echo"], json!("2"));
+        // strings only read as integers in integer fields, payloads stay strings
+        assert_eq!(replies[2]["body"]["echo"], json!("12345678901234567890"));
+    }
+
+    #[test]
+    fn test_run_with_dead_letter_file() {
+        let path = std::env::temp_dir().join(format!("dead-letters-{}.ndjson", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let input = [
+            // no echo field
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1}}"#,
+            // the node fails processing it
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo_ok","msg_id":2,"echo":"hello"}}"#,
+            ECHO_LINE,
+            "not json",
+        ]
+        .join("\n");
+        let options = RunnerOptions {
+            dead_letter_file: Some(path.clone()),
+            ..RunnerOptions::default()
+        };
+        let mut output = vec![];
+
+        run_with(&mut EchoNode, input.as_bytes(), &mut output, &options).unwrap();
+
+        let dead_letters: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(dead_letters.len(), 3);
+        assert_eq!(dead_letters[0]["message"]["body"]["msg_id"], json!(1));
+        assert!(dead_letters[0]["error"].as_str().unwrap().contains("echo"));
+        assert_eq!(dead_letters[1]["message"]["body"]["type"], json!("echo_ok"));
+        assert!(dead_letters[1]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Error processing message"));
+        assert_eq!(dead_letters[2].get("message"), None);
+    }
+
+    #[test]
+    fn test_run_with_sends_what_nodes_send_after_their_replies() {
+        // answers echoes, passing every one on to n2 as it goes
+        struct RelayingNode;
+
+        impl Processor<EchoMessage> for RelayingNode {
+            fn process(&mut self, msg: Message<EchoMessage>) -> ProcessResult<EchoMessage> {
+                process_collecting(self, msg)
+            }
+
+            fn process_with_sender(
+                &mut self,
+                msg: Message<EchoMessage>,
+                sender: &mut dyn Sender<EchoMessage>,
+            ) -> ProcessResult<EchoMessage> {
+                let EchoMessage::Echo { ref echo } = msg.body.body else {
+                    return no_reply();
+                };
+                sender.send(Message {
+                    src: msg.dest.clone(),
+                    dest: Some("n2".into()),
+                    body: Body {
+                        msg_id: None,
+                        in_reply_to: None,
+                        body: EchoMessage::Echo { echo: echo.clone() },
+                    },
+                });
+                reply(vec![
+                    msg.reply_with(None, EchoMessage::EchoOk { echo: echo.clone() })
+                ])
+            }
+        }
+
+        let mut output = vec![];
+        run_with(
+            &mut RelayingNode,
+            ECHO_LINE.as_bytes(),
+            &mut output,
+            &RunnerOptions::default(),
+        )
+        .unwrap();
+
+        let sent: Vec<Message<serde_json::Value>> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].body.body["type"], "echo_ok");
+        assert_eq!(sent[1].dest.as_deref(), Some("n2"));
+        assert_eq!(sent[1].body.body["type"], "echo");
+        assert_eq!(sent[1].body.msg_id, Some(2));
+    }
+
+    #[test]
+    fn test_run_with_routes_replies_to_pending_rpcs() {
+        use crate::rpc::{PendingRpc, RpcRegistry};
+
+        // asks n2 for the first echo, answering the next ones with what n2 answered
+        #[derive(Default)]
+        struct AskingNode {
+            rpcs: RpcRegistry<EchoMessage>,
+            asked: Option<PendingRpc<EchoMessage>>,
+        }
+
+        impl Processor<EchoMessage> for AskingNode {
+            fn process(&mut self, msg: Message<EchoMessage>) -> ProcessResult<EchoMessage> {
+                process_collecting(self, msg)
+            }
+
+            fn process_with_sender(
+                &mut self,
+                msg: Message<EchoMessage>,
+                sender: &mut dyn Sender<EchoMessage>,
+            ) -> ProcessResult<EchoMessage> {
+                let EchoMessage::Echo { ref echo } = msg.body.body else {
+                    return fail(anyhow::anyhow!("Replies are for the asker"));
+                };
+                let Some(asked) = &self.asked else {
+                    let body = EchoMessage::Echo { echo: echo.clone() };
+                    let timeout = Duration::from_secs(60);
+                    self.asked = Some(self.rpcs.rpc(sender, "n1", "n2", body, timeout));
+                    return no_reply();
+                };
+                let answer = match asked.try_get() {
+                    Some(answered) => match answered?.body.body {
+                        EchoMessage::EchoOk { echo } => echo,
+                        EchoMessage::Echo { .. } => unreachable!(),
+                    },
+                    None => "pending".into(),
+                };
+                reply(vec![
+                    msg.reply_with(None, EchoMessage::EchoOk { echo: answer })
+                ])
+            }
+
+            fn use_rpcs(&mut self, rpcs: RpcRegistry<EchoMessage>) {
+                self.rpcs = rpcs;
+            }
+        }
+
+        let echo = |msg_id, echo: &str| {
+            format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"echo","msg_id":{},"echo":"{}"}}}}"#,
+                msg_id, echo
+            )
+        };
+        let input = [
+            echo(1, "ask"),
+            echo(2, "too early"),
+            r#"{"src":"n2","dest":"n1","body":{"type":"echo_ok","in_reply_to":1,"echo":"from n2"}}"#.into(),
+            echo(3, "answered"),
+        ]
+        .join("\n");
+        let mut output = vec![];
+        let report = run_with_report(
+            &mut AskingNode::default(),
+            input.as_bytes(),
+            &mut output,
+            &RunnerOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(report.failed, 0);
+
+        let sent: Vec<Message<serde_json::Value>> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let sent: Vec<(Option<String>, Option<i64>, serde_json::Value)> = sent
+            .into_iter()
+            .map(|msg| {
+                (
+                    msg.dest,
+                    msg.body.in_reply_to,
+                    msg.body.body["echo"].clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            sent,
+            vec![
+                (Some("n2".into()), None, json!("ask")),
+                (Some("c1".into()), Some(2), json!("pending")),
+                (Some("c1".into()), Some(3), json!("from n2")),
+            ]
+        );
+
+        // wrappers hand the processor a registry of its own, routing the replies on to it
+        let mut output = vec![];
+        let report = run_with_report(
+            &mut crate::epochs::EpochTagging::new(crate::replying::ErrorReplying::new(
+                AskingNode::default(),
+            )),
+            input.as_bytes(),
+            &mut output,
+            &RunnerOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(report.failed, 0);
+        let answers: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<serde_json::Value>(line).unwrap()["body"]["echo"].clone()
+            })
+            .collect();
+        assert_eq!(
+            answers,
+            vec![json!("ask"), json!("pending"), json!("from n2")]
+        );
+
+        // requests not answered in time fail with a timeout
+        let rpcs = RpcRegistry::default();
+        let asked = rpcs.rpc(
+            &mut vec![],
+            "n1",
+            "n2",
+            EchoMessage::Echo { echo: "".into() },
+            Duration::ZERO,
+        );
+        let timed_out = asked.try_get().unwrap().unwrap_err();
+        assert_eq!(
+            timed_out.downcast_ref::<Error>().map(|e| e.code),
+            Some(error_codes::TIMEOUT)
+        );
+        // and are forgotten then, as when dropped, a late reply going to the node
+        let late_reply = |in_reply_to| Message {
+            src: Some("n2".into()),
+            dest: Some("n1".into()),
+            body: Body {
+                msg_id: None,
+                in_reply_to: Some(in_reply_to),
+                body: EchoMessage::EchoOk { echo: "".into() },
+            },
+        };
+        assert!(rpcs.route(late_reply(asked.msg_id)).is_some());
+        let dropped = rpcs.rpc(
+            &mut vec![],
+            "n1",
+            "n2",
+            EchoMessage::Echo { echo: "".into() },
+            Duration::from_secs(60),
+        );
+        let msg_id = dropped.msg_id;
+        drop(dropped);
+        assert!(rpcs.route(late_reply(msg_id)).is_some());
+    }
+
+    #[test]
+    fn test_run_with_restarts_nodes_asking_for_it() {
+        // keeps every echo, asking for a restart on `restart`
+        #[derive(Default)]
+        struct RestartingNode {
+            echoes: Vec<String>,
+        }
+
+        impl Processor<EchoMessage> for RestartingNode {
+            fn process(&mut self, msg: Message<EchoMessage>) -> ProcessResult<EchoMessage> {
+                let EchoMessage::Echo { ref echo } = msg.body.body else {
+                    return no_reply();
+                };
+                if echo == "restart" {
+                    return restart();
+                }
+                self.echoes.push(echo.clone());
+                reply(vec![
+                    msg.reply_with(None, EchoMessage::EchoOk { echo: echo.clone() })
+                ])
+            }
+
+            fn on_restart(&mut self) {
+                self.reset();
+            }
+        }
+
+        let echo = |msg_id, echo: &str| {
+            format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"echo","msg_id":{},"echo":"{}"}}}}"#,
+                msg_id, echo
+            )
+        };
+        let init = crate::testkit::fixtures::init_line("n1", &["n1"]);
+        let input = [
+            init.clone(),
+            echo(1, "before"),
+            echo(2, "restart"),
+            echo(3, "too early"),
+            init,
+            echo(4, "after"),
+        ]
+        .join("\n");
+        let mut node = RestartingNode::default();
+        let mut output = vec![];
+        let report = run_with_report(
+            &mut node,
+            input.as_bytes(),
+            &mut output,
+            &RunnerOptions::default(),
+        )
+        .unwrap();
+
+        // what came before the restart is forgotten
+        assert_eq!(node.echoes, vec!["after"]);
+        assert_eq!(report.rejected, 1);
+        let sent: Vec<(Option<i64>, serde_json::Value)> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Message<serde_json::Value>>(line).unwrap())
+            .map(|msg| (msg.body.in_reply_to, msg.body.body))
+            .collect();
+        assert_eq!(sent.len(), 5);
+        assert_eq!(
+            sent[1],
+            (Some(1), json!({"type": "echo_ok", "echo": "before"}))
+        );
+        assert_eq!(sent[2].0, Some(3));
+        assert_eq!(
+            sent[2].1["code"],
+            json!(error_codes::TEMPORARILY_UNAVAILABLE)
+        );
+        assert_eq!(sent[3].1["type"], "init_ok");
+        assert_eq!(
+            sent[4],
+            (Some(4), json!({"type": "echo_ok", "echo": "after"}))
+        );
+    }
+
+    #[test]
+    fn test_run_with_metrics_file() {
+        let path = std::env::temp_dir().join(format!("metrics-{}.prom", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let input = [ECHO_LINE, "not json"].join("\n");
+        let options = RunnerOptions {
+            metrics_file: Some(path.clone()),
+            ..RunnerOptions::default()
+        };
+        let mut output = vec![];
+
+        run_with(&mut EchoNode, input.as_bytes(), &mut output, &options).unwrap();
+
+        let metrics = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let samples: Vec<&str> = metrics.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            samples,
+            vec![
+                "maelstrom_messages_received_total 2",
+                "maelstrom_messages_processed_total 1",
+                "maelstrom_messages_rejected_total 0",
+                "maelstrom_messages_failed_total 1",
+                "maelstrom_duplicate_msg_ids_total 0",
+            ]
+        );
+        assert!(metrics.contains("# TYPE maelstrom_messages_processed_total counter\n"));
+    }
+
+    #[test]
+    fn test_run_with_validate_sources() {
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}"#;
+        let echo_from = |src: &str| {
+            format!(
+                r#"{{"src":"{}","dest":"n1","body":{{"type":"echo","msg_id":2,"echo":"hello"}}}}"#,
+                src
+            )
+        };
+        let input = [
+            init.to_string(),
+            echo_from("n2"),
+            echo_from("c12"),
+            echo_from("n3"),
+            echo_from("mallory"),
+        ]
+        .join("\n");
+        let answered = |options: &RunnerOptions| {
+            let mut output = vec![];
+            run_with(&mut EchoNode, input.as_bytes(), &mut output, options).unwrap();
+            String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+                .filter(|reply| reply["body"]["type"] == "echo_ok")
+                .map(|reply| reply["dest"].as_str().unwrap().to_string())
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(
+            answered(&RunnerOptions::default()),
+            vec!["n2", "c12", "n3", "mallory"]
+        );
+        let validating = RunnerOptions {
+            validate_sources: true,
+            ..RunnerOptions::default()
+        };
+        assert_eq!(answered(&validating), vec!["n2", "c12"]);
+    }
+
+    #[test]
+    fn test_run_with_report() {
+        let input = [
+            ECHO_LINE,
+            r#"{"dest":"n1","body":{"type":"echo","msg_id":2,"echo":"hello"}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo_ok","in_reply_to":3,"echo":"hello"}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo_ok","echo":"hello"}}"#,
+            ECHO_LINE,
+            // the stream ends at the first unparseable input
+            "not json",
+        ]
+        .join("\n");
+        let mut output = vec![];
+
+        let report = run_with_report(
+            &mut EchoNode,
+            input.as_bytes(),
+            &mut output,
+            &RunnerOptions {
+                check_msg_ids: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            report,
+            RunReport {
+                received: 6,
+                processed: 2,
+                rejected: 2,
+                failed: 2,
+                duplicate_msg_ids: 1,
+                state_bytes: None,
+                phases: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_run_with_custom_read_buffer_size() {
+        let options = RunnerOptions {
+            read_buffer_size: Some(16),
+            ..Default::default()
+        };
+        let input = [
+            ECHO_LINE,
+            &ECHO_LINE.replace(r#""msg_id":1"#, r#""msg_id":2"#),
+        ]
+        .join("\n");
+
+        assert_eq!(
+            run_lines::<EchoMessage>(&input, &options),
+            vec![echo_ok(1), echo_ok(2)]
+        );
+    }
+
+    #[test]
+    fn test_run_with_signals_readiness_once_after_init() {
+        #[derive(Default)]
+        struct ReadyCounter {
+            processed: usize,
+            ready_after: Vec<usize>,
+        }
+
+        impl Processor<serde_json::Value> for ReadyCounter {
+            fn process(
+                &mut self,
+                _msg: Message<serde_json::Value>,
+            ) -> ProcessResult<serde_json::Value> {
+                self.processed += 1;
+                Ok(None)
+            }
+
+            fn on_ready(&mut self) {
+                self.ready_after.push(self.processed);
+            }
+        }
+
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#;
+        let input = [ECHO_LINE, init, ECHO_LINE, init].join("\n");
+        let mut processor = ReadyCounter::default();
+
+        run_with(
+            &mut processor,
+            input.as_bytes(),
+            &mut vec![],
+            &RunnerOptions::default(),
+        )
+        .unwrap();
+
+        // inits never get to the node as messages
+        assert_eq!(processor.processed, 2);
+        assert_eq!(processor.ready_after, vec![1]);
+    }
+
+    #[test]
+    fn test_run_sharded_with_keeps_keys_in_order_across_shards() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::{Arc, Mutex};
+
+        // keyed by the first letter of the echo, echoes of key `a` being processed in order
+        // and `a1` only once `b1` was, which it can't be when they share a thread
+        #[derive(Clone, Default)]
+        struct KeyedNode {
+            b_processed: Arc<AtomicBool>,
+            processed: Arc<Mutex<Vec<String>>>,
+        }
+
+        impl Processor<EchoMessage> for KeyedNode {
+            fn process(&mut self, msg: Message<EchoMessage>) -> ProcessResult<EchoMessage> {
+                let EchoMessage::Echo { ref echo } = msg.body.body else {
+                    return no_reply();
+                };
+                if echo == "a1" {
+                    let deadline = Instant::now() + Duration::from_secs(5);
+                    while !self.b_processed.load(Ordering::SeqCst) && Instant::now() < deadline {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                }
+                self.processed.lock().unwrap().push(echo.clone());
+                if echo.starts_with('b') {
+                    self.b_processed.store(true, Ordering::SeqCst);
+                }
+                reply(vec![
+                    msg.reply_with(None, EchoMessage::EchoOk { echo: echo.clone() })
+                ])
+            }
+        }
+
+        impl ShardedProcessor<EchoMessage> for KeyedNode {
+            fn shard_key(&self, msg: &Message<EchoMessage>) -> Option<String> {
+                match &msg.body.body {
+                    EchoMessage::Echo { echo } => echo.get(..1).map(String::from),
+                    EchoMessage::EchoOk { .. } => None,
+                }
+            }
+        }
+
+        let echo = |msg_id, echo: &str| {
+            format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"echo","msg_id":{},"echo":"{}"}}}}"#,
+                msg_id, echo
+            )
+        };
+        let input = [
+            crate::testkit::fixtures::init_line("n1", &["n1"]),
+            echo(1, "a1"),
+            echo(2, "b1"),
+            echo(3, "a2"),
+            echo(4, "a3"),
+        ]
+        .join("\n");
+        let mut node = KeyedNode::default();
+        let mut output = vec![];
+        let options = RunnerOptions {
+            workers: Some(2),
+            ..Default::default()
+        };
+        let report = run_sharded_with(&mut node, input.as_bytes(), &mut output, &options).unwrap();
+        assert_eq!(report.processed, 5);
+
+        // b1 went through while a1 was still being processed
+        assert_eq!(
+            *node.processed.lock().unwrap(),
+            vec!["b1", "a1", "a2", "a3"]
+        );
+        let answered: Vec<Option<i64>> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Message<serde_json::Value>>(line).unwrap())
+            .map(|msg| msg.body.in_reply_to)
+            .collect();
+        assert_eq!(answered, vec![Some(1), Some(1), Some(2), Some(3), Some(4)]);
+    }
+
+    #[test]
+    fn test_run_with_timers_fires_while_no_message_comes_in() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        // flushes a tick to c1 every few milliseconds
+        struct TickingNode(Arc<AtomicUsize>);
+
+        impl Processor<EchoMessage> for TickingNode {
+            fn process(&mut self, msg: Message<EchoMessage>) -> ProcessResult<EchoMessage> {
+                EchoNode.process(msg)
+            }
+
+            fn timers(&self) -> Vec<(&'static str, Duration)> {
+                vec![("flush", Duration::from_millis(5))]
+            }
+
+            fn on_timer(&mut self, timer: &str) -> ProcessResult<EchoMessage> {
+                assert_eq!(timer, "flush");
+                self.0.fetch_add(1, Ordering::SeqCst);
+                reply(vec![Message {
+                    src: Some("n1".into()),
+                    dest: Some("c1".into()),
+                    body: Body {
+                        msg_id: None,
+                        in_reply_to: None,
+                        body: EchoMessage::EchoOk {
+                            echo: "tick".into(),
+                        },
+                    },
+                }])
+            }
+        }
+
+        // the init, then nothing until the node ticked twice
+        struct QuietInput {
+            init: std::io::Cursor<String>,
+            ticks: Arc<AtomicUsize>,
+        }
+
+        impl std::io::Read for QuietInput {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let read = self.init.read(buf)?;
+                if read > 0 {
+                    return std::io::Result::Ok(read);
+                }
+                let deadline = Instant::now() + Duration::from_secs(5);
+                while self.ticks.load(Ordering::SeqCst) < 2 && Instant::now() < deadline {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                std::io::Result::Ok(0)
+            }
+        }
+
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let input = QuietInput {
+            init: std::io::Cursor::new(crate::testkit::fixtures::init_line("n1", &["n1"]) + "\n"),
+            ticks: ticks.clone(),
+        };
+        let mut output = vec![];
+        run_with_timers_with(
+            &mut TickingNode(ticks.clone()),
+            input,
+            &mut output,
+            &RunnerOptions::default(),
+        )
+        .unwrap();
+
+        let sent: Vec<Message<serde_json::Value>> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(sent[0].body.body["type"], "init_ok");
+        let flushed = &sent[1..];
+        assert!(flushed.len() >= 2);
+        assert!(flushed.iter().all(|msg| msg.body.body["echo"] == "tick"));
+        let msg_ids: HashSet<Option<i64>> = flushed.iter().map(|msg| msg.body.msg_id).collect();
+        assert_eq!(msg_ids.len(), flushed.len());
+    }
+
+    #[test]
+    fn test_run_parallel_with_init_first() {
+        use std::sync::{Arc, Mutex};
+
+        // what the node and its output went through, in order
+        type Events = Arc<Mutex<Vec<String>>>;
+
+        #[derive(Clone)]
+        struct IdentityNode {
+            node_id: Option<String>,
+            events: Events,
+        }
+
+        impl Processor<serde_json::Value> for IdentityNode {
+            fn process(
+                &mut self,
+                msg: Message<serde_json::Value>,
+            ) -> ProcessResult<serde_json::Value> {
+                let processed = format!("broadcast processed by {:?}", self.node_id);
+                self.events.lock().unwrap().push(processed);
+                Ok(Some(vec![Message {
+                    src: self.node_id.clone(),
+                    dest: msg.src,
+                    body: Body {
+                        msg_id: None,
+                        in_reply_to: msg.body.msg_id,
+                        body: json!({"type": "broadcast_ok"}),
+                    },
+                }]))
+            }
+
+            fn on_init(&mut self, node_id: String, _node_ids: Vec<String>) -> anyhow::Result<()> {
+                self.node_id = Some(node_id);
+                Ok(())
+            }
+        }
+
+        impl StatelessProcessor<serde_json::Value> for IdentityNode {}
+
+        struct EventWriter {
+            written: Vec<u8>,
+            events: Events,
+        }
+
+        impl std::io::Write for EventWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.written.write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                let lines = self.written.iter().filter(|b| **b == b'\n').count();
+                let flushed = format!("{} lines flushed", lines);
+                self.events.lock().unwrap().push(flushed);
+                std::io::Result::Ok(())
+            }
+        }
+
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#;
+        let broadcast =
+            r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":1,"message":7}}"#;
+        let input = [init, broadcast].join("\n");
+        let events = Events::default();
+        let mut output = EventWriter {
+            written: vec![],
+            events: events.clone(),
+        };
+        let options = RunnerOptions {
+            workers: Some(4),
+            init_first: true,
+            ..RunnerOptions::default()
+        };
+
+        run_parallel_with(
+            &mut IdentityNode {
+                node_id: None,
+                events: events.clone(),
+            },
+            input.as_bytes(),
+            &mut output,
+            &options,
+        )
+        .unwrap();
+
+        assert_eq!(
+            events.lock().unwrap()[..2],
+            ["1 lines flushed", "broadcast processed by Some(\"n1\")"]
+        );
+        let replies: Vec<serde_json::Value> = String::from_utf8(output.written)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(replies[1]["src"], "n1");
+        assert_eq!(replies[1]["body"]["type"], "broadcast_ok");
+    }
+
+    #[test]
+    fn test_run_parallel_with_routes_replies_to_pending_rpcs() {
+        use crate::rpc::{PendingRpc, RpcRegistry};
+        use std::sync::{Arc, Mutex};
+
+        // asks n2 for the first echo, answering the next ones with what n2 answered; the
+        // request is kept where every copy sees it
+        #[derive(Clone, Default)]
+        struct AskingNode {
+            rpcs: RpcRegistry<EchoMessage>,
+            asked: Arc<Mutex<Option<PendingRpc<EchoMessage>>>>,
+        }
+
+        impl Processor<EchoMessage> for AskingNode {
+            fn process(&mut self, msg: Message<EchoMessage>) -> ProcessResult<EchoMessage> {
+                process_collecting(self, msg)
+            }
+
+            fn process_with_sender(
+                &mut self,
+                msg: Message<EchoMessage>,
+                sender: &mut dyn Sender<EchoMessage>,
+            ) -> ProcessResult<EchoMessage> {
+                let EchoMessage::Echo { ref echo } = msg.body.body else {
+                    return fail(anyhow::anyhow!("Replies are for the asker"));
+                };
+                let mut asked = self.asked.lock().unwrap();
+                let Some(pending) = &*asked else {
+                    let body = EchoMessage::Echo { echo: echo.clone() };
+                    let timeout = Duration::from_secs(60);
+                    *asked = Some(self.rpcs.rpc(sender, "n1", "n2", body, timeout));
+                    return no_reply();
+                };
+                let answer = match pending.try_get() {
+                    Some(answered) => match answered?.body.body {
+                        EchoMessage::EchoOk { echo } => echo,
+                        EchoMessage::Echo { .. } => unreachable!(),
+                    },
+                    None => "pending".into(),
+                };
+                reply(vec![
+                    msg.reply_with(None, EchoMessage::EchoOk { echo: answer })
+                ])
+            }
+
+            fn use_rpcs(&mut self, rpcs: RpcRegistry<EchoMessage>) {
+                self.rpcs = rpcs;
+            }
+        }
+
+        impl StatelessProcessor<EchoMessage> for AskingNode {}
+
+        // holds the reply back until the request is sent, as n2 would
+        struct AnsweringInput {
+            asking: std::io::Cursor<String>,
+            answering: std::io::Cursor<String>,
+            asked: Arc<Mutex<Option<PendingRpc<EchoMessage>>>>,
+        }
+
+        impl std::io::Read for AnsweringInput {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let read = self.asking.read(buf)?;
+                if read > 0 {
+                    return std::io::Result::Ok(read);
+                }
+                let deadline = Instant::now() + Duration::from_secs(5);
+                while self.asked.lock().unwrap().is_none() && Instant::now() < deadline {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                self.answering.read(buf)
+            }
+        }
+
+        let echo = |msg_id, echo: &str| {
+            format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"echo","msg_id":{},"echo":"{}"}}}}"#,
+                msg_id, echo
+            )
+        };
+        let asking = [
+            crate::testkit::fixtures::init_line("n1", &["n1", "n2"]),
+            echo(1, "ask"),
+        ];
+        let answering = [
+            // init_ok went out as msg_id 1
+            r#"{"src":"n2","dest":"n1","body":{"type":"echo_ok","in_reply_to":2,"echo":"from n2"}}"#.into(),
+            echo(2, "answered"),
+        ];
+        let node = AskingNode::default();
+        let input = AnsweringInput {
+            asking: std::io::Cursor::new(asking.join("\n") + "\n"),
+            answering: std::io::Cursor::new(answering.join("\n")),
+            asked: node.asked.clone(),
+        };
+        let mut output = vec![];
+        // one worker, for the reply to be routed before the echo answered with it
+        let options = RunnerOptions {
+            workers: Some(1),
+            ..RunnerOptions::default()
+        };
+        let report = run_parallel_with(&mut node.clone(), input, &mut output, &options).unwrap();
+        assert_eq!(report.failed, 0);
+
+        let sent: Vec<Message<serde_json::Value>> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(sent[1].dest.as_deref(), Some("n2"));
+        assert_eq!(sent[1].body.msg_id, Some(2));
+        assert_eq!(sent[2].dest.as_deref(), Some("c1"));
+        assert_eq!(sent[2].body.body["echo"], "from n2");
     }
 }
@@ -0,0 +1,75 @@
+use std::fmt::Write;
+
+use crate::runner::RunReport;
+
+/// Run report in the Prometheus text exposition format, counters covering the whole run.
+pub fn prometheus(report: &RunReport) -> String {
+    let mut text = String::new();
+    let counters = [
+        (
+            "maelstrom_messages_received_total",
+            "Input messages, whatever became of them.",
+            report.received,
+        ),
+        (
+            "maelstrom_messages_processed_total",
+            "Messages the node processed successfully.",
+            report.processed,
+        ),
+        (
+            "maelstrom_messages_rejected_total",
+            "Messages the runner refused to hand over to the node.",
+            report.rejected,
+        ),
+        (
+            "maelstrom_messages_failed_total",
+            "Messages that couldn't be parsed, or whose processing failed.",
+            report.failed,
+        ),
+        (
+            "maelstrom_duplicate_msg_ids_total",
+            "Msg_ids the node sent more than once.",
+            report.duplicate_msg_ids,
+        ),
+    ];
+    for (name, help, value) in counters {
+        metric(&mut text, name, help, "counter", &[("", value as f64)]);
+    }
+    if let Some(state_bytes) = report.state_bytes {
+        metric(
+            &mut text,
+            "maelstrom_state_bytes",
+            "Size of the final node state once serialized.",
+            "gauge",
+            &[("", state_bytes as f64)],
+        );
+    }
+    if let Some(phases) = &report.phases {
+        let seconds = |us: u64| us as f64 / 1e6;
+        metric(
+            &mut text,
+            "maelstrom_phase_seconds_total",
+            "Time spent in each phase of the run.",
+            "counter",
+            &[
+                (r#"phase="read""#, seconds(phases.read_us)),
+                (r#"phase="process""#, seconds(phases.process_us)),
+                (r#"phase="write""#, seconds(phases.write_us)),
+            ],
+        );
+    }
+    text
+}
+
+// one sample per set of labels, labels being written as they are
+fn metric(text: &mut String, name: &str, help: &str, kind: &str, samples: &[(&str, f64)]) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(text, "{} {}", name, value);
+        } else {
+            let _ = writeln!(text, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
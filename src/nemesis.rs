@@ -0,0 +1,57 @@
+use anyhow::Result;
+
+use crate::msg_protocol::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Deterministic replica of Maelstrom's loss nemesis: wraps a processor and drops every
+/// nth message going in or out of it. `n = 0` never drops anything.
+pub struct DropEveryNth<P> {
+    processor: P,
+    n: usize,
+    direction: Direction,
+    count: usize,
+}
+
+impl<P> DropEveryNth<P> {
+    pub fn new(processor: P, n: usize, direction: Direction) -> Self {
+        Self {
+            processor,
+            n,
+            direction,
+            count: 0,
+        }
+    }
+
+    fn drops_next(&mut self) -> bool {
+        self.count += 1;
+        self.n > 0 && self.count.is_multiple_of(self.n)
+    }
+}
+
+impl<MessageType, P> Processor<MessageType> for DropEveryNth<P>
+where
+    P: Processor<MessageType>,
+{
+    fn process(&mut self, msg: Message<MessageType>) -> Result<Option<Vec<Message<MessageType>>>> {
+        match self.direction {
+            Direction::Inbound => {
+                if self.drops_next() {
+                    return Ok(None);
+                }
+                process_collecting(&mut self.processor, msg)
+            }
+            Direction::Outbound => {
+                let replies = process_collecting(&mut self.processor, msg)?;
+                Ok(replies
+                    .map(|replies| replies.into_iter().filter(|_| !self.drops_next()).collect()))
+            }
+        }
+    }
+
+    delegate_hooks!(processor => all);
+}
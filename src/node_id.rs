@@ -0,0 +1,95 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// Id of a Maelstrom node, `n` followed by its index. Ids are ordered by index, so that
+/// `n2` comes before `n10`, and normalized when displayed (`n007` is `n7`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId {
+    index: u64,
+}
+
+impl NodeId {
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+}
+
+impl FromStr for NodeId {
+    type Err = ParseNodeIdError;
+
+    fn from_str(node_id: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseNodeIdError(node_id.to_string());
+        let digits = node_id.strip_prefix('n').ok_or_else(invalid)?;
+        // u64 parsing alone would accept a sign
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let index = digits.parse().map_err(|_| invalid())?;
+        Ok(NodeId { index })
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "n{}", self.index)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseNodeIdError(String);
+
+impl fmt::Display for ParseNodeIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid node id {:?}, expected n<number>", self.0)
+    }
+}
+
+impl std::error::Error for ParseNodeIdError {}
+
+/// Whether `id` is the id of a node, rather than of a client or a service.
+pub fn is_node(id: &str) -> bool {
+    id.parse::<NodeId>().is_ok()
+}
+
+/// Order in which nodes are considered wherever it matters (root election, partitions...):
+/// node ids by index first, then anything else, e.g. clients or services, as plain strings.
+pub fn compare(a: &str, b: &str) -> Ordering {
+    match (a.parse::<NodeId>(), b.parse::<NodeId>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_id_parsing() {
+        let n10: NodeId = "n10".parse().unwrap();
+        assert_eq!(n10.index(), 10);
+        assert_eq!("n007".parse::<NodeId>().unwrap().to_string(), "n7");
+        for invalid in [
+            "",
+            "n",
+            "c1",
+            "N1",
+            "n-1",
+            "n+1",
+            "n1a",
+            " n1",
+            "n99999999999999999999",
+        ] {
+            assert!(invalid.parse::<NodeId>().is_err(), "{:?} parsed", invalid);
+        }
+
+        assert!("n2".parse::<NodeId>().unwrap() < n10);
+        let mut ids = vec!["seq-kv", "n10", "c1", "n2"];
+        ids.sort_by(|a, b| compare(a, b));
+        assert_eq!(ids, vec!["n2", "n10", "c1", "seq-kv"]);
+        assert!(is_node("n1") && !is_node("c1") && !is_node("node1"));
+    }
+}
@@ -0,0 +1,53 @@
+use anyhow::Result;
+
+use crate::msg_protocol::*;
+
+/// One processed message along with the replies it produced and the node state right after.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transition<MessageType> {
+    pub state: Option<serde_json::Value>,
+    pub msg: Message<MessageType>,
+    pub replies: Option<Vec<Message<MessageType>>>,
+}
+
+/// Wraps a processor and records every successful transition, so a run can be checked
+/// against an invariant afterwards. Messages whose processing fails are not recorded.
+pub struct Recording<MessageType, P> {
+    processor: P,
+    transitions: Vec<Transition<MessageType>>,
+}
+
+impl<MessageType, P> Recording<MessageType, P> {
+    pub fn new(processor: P) -> Self {
+        Self {
+            processor,
+            transitions: vec![],
+        }
+    }
+
+    pub fn transitions(&self) -> &[Transition<MessageType>] {
+        &self.transitions
+    }
+
+    pub fn into_inner(self) -> P {
+        self.processor
+    }
+}
+
+impl<MessageType, P> Processor<MessageType> for Recording<MessageType, P>
+where
+    MessageType: Clone,
+    P: Processor<MessageType>,
+{
+    fn process(&mut self, msg: Message<MessageType>) -> Result<Option<Vec<Message<MessageType>>>> {
+        let replies = process_collecting(&mut self.processor, msg.clone())?;
+        self.transitions.push(Transition {
+            state: self.processor.save_state(),
+            msg,
+            replies: replies.clone(),
+        });
+        Ok(replies)
+    }
+
+    delegate_hooks!(processor => all);
+}
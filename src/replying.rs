@@ -0,0 +1,124 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::msg_protocol::*;
+use crate::rpc::RpcRegistry;
+
+/// Body of either a workload message or a Maelstrom error.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum OrError<MessageType> {
+    Message(MessageType),
+    Error(Error),
+}
+
+/// Wraps a processor and turns every message it fails to process into an error reply to the
+/// sender among its answers, as the runner does with failures, for processors driven outside
+/// of it. Failures carrying an `Error` keep their code, any other one is reported as a crash.
+/// Errors received are dropped, answering them could bounce errors between two nodes forever;
+/// a request the processor sent through its registry and failed is then left to time out.
+pub struct ErrorReplying<MessageType, P> {
+    processor: P,
+    // the processor's own, the runner only routing replies to requests of the wrapper's type
+    rpcs: RpcRegistry<MessageType>,
+}
+
+impl<MessageType, P> ErrorReplying<MessageType, P> {
+    pub fn new(processor: P) -> Self {
+        Self {
+            processor,
+            rpcs: RpcRegistry::default(),
+        }
+    }
+
+    pub fn into_inner(self) -> P {
+        self.processor
+    }
+}
+
+impl<MessageType, P> Processor<OrError<MessageType>> for ErrorReplying<MessageType, P>
+where
+    P: Processor<MessageType>,
+{
+    fn process(
+        &mut self,
+        msg: Message<OrError<MessageType>>,
+    ) -> Result<Option<Vec<Message<OrError<MessageType>>>>> {
+        let request = Message {
+            src: msg.src,
+            dest: msg.dest,
+            body: Body {
+                msg_id: msg.body.msg_id,
+                in_reply_to: msg.body.in_reply_to,
+                body: (),
+            },
+        };
+        let body = match msg.body.body {
+            OrError::Message(body) => body,
+            OrError::Error(_) => return Ok(None),
+        };
+        let msg = Message {
+            src: request.src.clone(),
+            dest: request.dest.clone(),
+            body: Body {
+                msg_id: request.body.msg_id,
+                in_reply_to: request.body.in_reply_to,
+                body,
+            },
+        };
+        let Some(msg) = self.rpcs.route(msg) else {
+            return Ok(None);
+        };
+        match process_collecting(&mut self.processor, msg) {
+            Ok(replies) => Ok(replies.map(wrap)),
+            // a restart isn't a failure to answer, it is for the runner to carry out
+            Err(err) if err.is::<Restart>() => Err(err),
+            Err(err) => {
+                let (code, text) = match err.downcast_ref::<Error>() {
+                    Some(error) => (error.code, error.text.clone()),
+                    None => (error_codes::CRASH, err.to_string()),
+                };
+                let error_reply = request.error_reply(code, text);
+                Ok(Some(vec![Message {
+                    src: error_reply.src,
+                    dest: error_reply.dest,
+                    body: Body {
+                        msg_id: error_reply.body.msg_id,
+                        in_reply_to: error_reply.body.in_reply_to,
+                        body: OrError::Error(error_reply.body.body),
+                    },
+                }]))
+            }
+        }
+    }
+
+    delegate_hooks!(processor => untyped, init);
+
+    fn use_rpcs(&mut self, rpcs: RpcRegistry<OrError<MessageType>>) {
+        self.rpcs = rpcs.fork();
+        self.processor.use_rpcs(self.rpcs.clone())
+    }
+
+    fn on_shutdown(&mut self) -> Result<Option<Vec<Message<OrError<MessageType>>>>> {
+        Ok(self.processor.on_shutdown()?.map(wrap))
+    }
+
+    fn on_timer(&mut self, timer: &str) -> Result<Option<Vec<Message<OrError<MessageType>>>>> {
+        Ok(self.processor.on_timer(timer)?.map(wrap))
+    }
+}
+
+fn wrap<MessageType>(replies: Vec<Message<MessageType>>) -> Vec<Message<OrError<MessageType>>> {
+    replies
+        .into_iter()
+        .map(|reply| Message {
+            src: reply.src,
+            dest: reply.dest,
+            body: Body {
+                msg_id: reply.body.msg_id,
+                in_reply_to: reply.body.in_reply_to,
+                body: OrError::Message(reply.body.body),
+            },
+        })
+        .collect()
+}
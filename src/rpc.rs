@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::msg_protocol::*;
+
+/// Requests a node sent and awaits the reply to, by msg_id. The runner hands the node its
+/// registry before the first message, and routes every reply to one of them to the waiting
+/// `PendingRpc` instead of the node. Clones share the pending requests, a request leaving
+/// them once answered, timed out or dropped.
+pub struct RpcRegistry<MessageType> {
+    msg_ids: MessageIdAllocator,
+    pending: Pending<MessageType>,
+}
+
+// where the reply to each request still awaited goes, by msg_id
+type Pending<MessageType> = Arc<Mutex<HashMap<i64, mpsc::Sender<Message<MessageType>>>>>;
+
+impl<MessageType> Clone for RpcRegistry<MessageType> {
+    fn clone(&self) -> Self {
+        Self {
+            msg_ids: self.msg_ids.clone(),
+            pending: self.pending.clone(),
+        }
+    }
+}
+
+impl<MessageType> Default for RpcRegistry<MessageType> {
+    fn default() -> Self {
+        Self::new(MessageIdAllocator::default())
+    }
+}
+
+impl<MessageType> RpcRegistry<MessageType> {
+    pub fn new(msg_ids: MessageIdAllocator) -> Self {
+        Self {
+            msg_ids,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Registry numbering its requests like this one, but with pending requests of its own and
+    /// another message type, e.g. for a processor wrapped in one changing the message type:
+    /// the runner doesn't route replies to it, whoever holds it does.
+    pub fn fork<OtherType>(&self) -> RpcRegistry<OtherType> {
+        RpcRegistry::new(self.msg_ids.clone())
+    }
+
+    /// Sends `body` from `src` to `dest` through `sender`, the reply being awaited for at most
+    /// `timeout`.
+    pub fn rpc(
+        &self,
+        sender: &mut dyn Sender<MessageType>,
+        src: &str,
+        dest: &str,
+        body: MessageType,
+        timeout: Duration,
+    ) -> PendingRpc<MessageType> {
+        let msg_id = self.msg_ids.next();
+        let (reply, waiter) = mpsc::channel();
+        self.pending.lock().unwrap().insert(msg_id, reply);
+        sender.send(Message {
+            src: Some(src.into()),
+            dest: Some(dest.into()),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                body,
+            },
+        });
+        PendingRpc {
+            msg_id,
+            deadline: Instant::now() + timeout,
+            waiter,
+            pending: self.pending.clone(),
+        }
+    }
+
+    /// Hands `msg` over to the request it answers, if one is pending, giving it back otherwise,
+    /// as with replies coming after their request timed out or was dropped.
+    pub fn route(&self, msg: Message<MessageType>) -> Option<Message<MessageType>> {
+        let Some(in_reply_to) = msg.body.in_reply_to else {
+            return Some(msg);
+        };
+        let Some(reply) = self.pending.lock().unwrap().remove(&in_reply_to) else {
+            return Some(msg);
+        };
+        // the waiter may be on its way out, the reply going with it
+        let _ = reply.send(msg);
+        None
+    }
+}
+
+/// Reply to a request sent with `RpcRegistry::rpc`, once it comes back.
+pub struct PendingRpc<MessageType> {
+    pub msg_id: i64,
+    deadline: Instant,
+    waiter: mpsc::Receiver<Message<MessageType>>,
+    pending: Pending<MessageType>,
+}
+
+impl<MessageType> PendingRpc<MessageType> {
+    /// The reply if it came back, a timeout error if it didn't in time, none while it may
+    /// still come.
+    pub fn try_get(&self) -> Option<Result<Message<MessageType>>> {
+        match self.waiter.try_recv() {
+            Ok(reply) => Some(Ok(reply)),
+            Err(_) if Instant::now() >= self.deadline => {
+                self.give_up();
+                Some(Err(self.timed_out()))
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Blocks until the reply comes back or the timeout, for nodes waiting on a thread other
+    /// than the one the runner routes replies on. Called from `process`, it blocks the runner
+    /// until the timeout: the reply can't be routed while the runner waits on the node.
+    pub fn wait(self) -> Result<Message<MessageType>> {
+        let timeout = self.deadline.saturating_duration_since(Instant::now());
+        self.waiter
+            .recv_timeout(timeout)
+            .map_err(|_| self.timed_out())
+    }
+
+    // also on drop, where panicking over a poisoned lock would abort
+    fn give_up(&self) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.remove(&self.msg_id);
+        }
+    }
+
+    fn timed_out(&self) -> anyhow::Error {
+        Error {
+            code: error_codes::TIMEOUT,
+            text: format!("No reply to msg_id {} in time", self.msg_id),
+        }
+        .into()
+    }
+}
+
+impl<MessageType> Drop for PendingRpc<MessageType> {
+    fn drop(&mut self) {
+        self.give_up();
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+use crate::clock::*;
+use crate::msg_protocol::*;
+
+/// In-memory network of processors for testing a workload without Maelstrom.
+/// Every tick delivers all the messages in flight, messages for unknown nodes (clients)
+/// are kept aside and processing errors are dropped, like the runner does.
+pub struct Cluster<MessageType, P> {
+    nodes: BTreeMap<String, P>,
+    in_flight: VecDeque<Message<MessageType>>,
+    client_messages: Vec<Message<MessageType>>,
+    // time of the cluster, which node clocks are skewed from
+    clock: ManualClock,
+}
+
+impl<MessageType, P> Cluster<MessageType, P>
+where
+    P: Processor<MessageType>,
+{
+    pub fn new(nodes: impl IntoIterator<Item = (String, P)>) -> Self {
+        Self {
+            nodes: nodes.into_iter().collect(),
+            in_flight: VecDeque::new(),
+            client_messages: vec![],
+            clock: ManualClock::default(),
+        }
+    }
+
+    /// Cluster whose nodes each get a clock `offset_ms` milliseconds ahead of the cluster's
+    /// time (behind when negative), built along with the node by `build`.
+    pub fn with_clock_offsets(
+        offsets: impl IntoIterator<Item = (String, i64)>,
+        mut build: impl FnMut(&str, Box<dyn Clock>) -> P,
+    ) -> Self {
+        let clock = ManualClock::default();
+        let nodes = offsets.into_iter().map(|(node_id, offset_ms)| {
+            let node = build(
+                &node_id,
+                Box::new(SkewedClock::new(clock.clone(), offset_ms)),
+            );
+            (node_id, node)
+        });
+        Self {
+            clock: clock.clone(),
+            ..Self::new(nodes)
+        }
+    }
+
+    // moves the time of the cluster, every node clock following
+    pub fn set_time(&self, now: Duration) {
+        self.clock.set(now);
+    }
+
+    pub fn send(&mut self, msg: Message<MessageType>) {
+        self.in_flight.push_back(msg);
+    }
+
+    pub fn node(&self, node_id: &str) -> Option<&P> {
+        self.nodes.get(node_id)
+    }
+
+    // what a node reports through its save_state and dump hooks, to check invariants between
+    // ticks rather than only once converged
+    pub fn node_state(&self, node_id: &str) -> Option<serde_json::Value> {
+        let node = self.nodes.get(node_id)?;
+        Some(serde_json::json!({
+            "state": node.save_state(),
+            "dump": node.dump(),
+        }))
+    }
+
+    // messages to deliver on the next tick
+    pub fn in_flight(&self) -> impl Iterator<Item = &Message<MessageType>> {
+        self.in_flight.iter()
+    }
+
+    // messages sent by nodes to anything outside of the cluster
+    pub fn client_messages(&self) -> &[Message<MessageType>] {
+        &self.client_messages
+    }
+
+    pub fn tick(&mut self) {
+        let delivered: Vec<Message<MessageType>> = self.in_flight.drain(..).collect();
+        for msg in delivered {
+            let node = msg.dest.as_ref().and_then(|dest| self.nodes.get_mut(dest));
+            match node {
+                Some(node) => {
+                    if let Ok(Some(replies)) = node.process(msg) {
+                        self.in_flight.extend(replies);
+                    }
+                }
+                None => self.client_messages.push(msg),
+            }
+        }
+    }
+
+    // all the nodes report the same state through their save_state hook
+    pub fn converged(&self) -> bool {
+        let mut states = self.nodes.values().map(|node| node.save_state());
+        match states.next() {
+            Some(first) => states.all(|state| state == first),
+            None => true,
+        }
+    }
+
+    // ticks until the nodes converge, at least once so the messages already sent are delivered
+    pub fn converges_within(&mut self, max_ticks: usize) -> bool {
+        for _ in 0..max_ticks {
+            self.tick();
+            if self.converged() {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn assert_converges_within(&mut self, max_ticks: usize) {
+        assert!(
+            self.converges_within(max_ticks),
+            "cluster did not converge within {} ticks",
+            max_ticks
+        );
+    }
+}
+
+/// Builders for the messages every workload test needs, whatever its message type.
+pub mod fixtures {
+    use crate::msg_protocol::*;
+
+    // a request sent from "src" to "dest"
+    pub fn request<T>(body: T) -> Message<T> {
+        Message {
+            src: Some("src".into()),
+            dest: Some("dest".into()),
+            body: Body {
+                msg_id: Some(1),
+                in_reply_to: None,
+                body,
+            },
+        }
+    }
+
+    // the reply a node gives to `request`, left for the runner to number
+    pub fn reply<T>(body: T) -> Message<T> {
+        Message {
+            src: Some("dest".into()),
+            dest: Some("src".into()),
+            body: Body {
+                msg_id: None,
+                in_reply_to: Some(1),
+                body,
+            },
+        }
+    }
+
+    // the init Maelstrom starts with, as a line for the runner to read since nodes never get
+    // to see it as a message
+    pub fn init_line(node_id: &str, node_ids: &[&str]) -> String {
+        serde_json::json!({
+            "src": "c0",
+            "dest": node_id,
+            "body": {"type": "init", "msg_id": 1, "node_id": node_id, "node_ids": node_ids},
+        })
+        .to_string()
+    }
+}
@@ -0,0 +1,38 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use maelstrom_rust::testkit::fixtures::init_line;
+
+// what a node writes out for the given input lines, parsed
+fn run_node(bin: &str, input: &[String]) -> Vec<serde_json::Value> {
+    let mut node = Command::new(bin)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    node.stdin
+        .take()
+        .unwrap()
+        .write_all(input.join("\n").as_bytes())
+        .unwrap();
+    let output = node.wait_with_output().unwrap();
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+// the same init fixture starts any kind of node
+#[test]
+fn test_init_fixture_initializes_every_node_type() {
+    for bin in [env!("CARGO_BIN_EXE_echo"), env!("CARGO_BIN_EXE_unique-id")] {
+        let output = run_node(bin, &[init_line("n2", &["n1", "n2"])]);
+        assert_eq!(output.len(), 1, "{}", bin);
+        assert_eq!(output[0]["src"], "n2", "{}", bin);
+        assert_eq!(output[0]["dest"], "c0", "{}", bin);
+        assert_eq!(output[0]["body"]["type"], "init_ok", "{}", bin);
+        assert_eq!(output[0]["body"]["in_reply_to"], 1, "{}", bin);
+    }
+}
@@ -0,0 +1,41 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// Maelstrom reads replies from stdout, anything else written there breaks the run
+#[test]
+fn test_stdout_only_carries_messages() {
+    let input = [
+        r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+        r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"hello"}}"#,
+        r#"{"src":"c1","dest":"n1","body":{"type":"unknown","msg_id":3}}"#,
+        "garbage",
+    ]
+    .join("\n");
+    let mut node = Command::new(env!("CARGO_BIN_EXE_echo"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    node.stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = node.wait_with_output().unwrap();
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let types: Vec<String> = stdout
+        .lines()
+        .map(|line| {
+            let msg: serde_json::Value = serde_json::from_str(line)
+                .unwrap_or_else(|e| panic!("Not a message on stdout : {:?} ({})", line, e));
+            assert!(msg["src"].is_string() && msg["dest"].is_string());
+            msg["body"]["type"].as_str().unwrap().to_string()
+        })
+        .collect();
+    assert_eq!(types, ["init_ok", "echo_ok"]);
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Unknown message"));
+}